@@ -0,0 +1,908 @@
+// src/archive.rs
+
+use crate::env::Env;
+use anyhow::{Context, Result, bail};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, warn};
+
+/// Inode number of the archive's top directory.
+pub const ROOT_INO: u64 = 1;
+
+const TAR_BLOCK: u64 = 512;
+
+// Deflated zip members are inflated whole to serve a READ; bigger ones
+// are refused rather than held in memory.
+const MAX_INFLATE: u64 = 256 << 20;
+
+// zip records
+const ZIP_LOCAL: u32 = 0x0403_4b50;
+const ZIP_CENTRAL: u32 = 0x0201_4b50;
+const ZIP_END: u32 = 0x0605_4b50;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Kind {
+    File,
+    Dir,
+    Symlink(String),
+}
+
+/// Where a member's contents live in the archive file.
+#[derive(Clone, Copy, Debug)]
+enum Data {
+    Empty,
+    Stored { offset: u64 },
+    Deflated { offset: u64, csize: u64, crc: u32 },
+}
+
+/// One file, directory or symlink of the archive. Its inode number is
+/// its position in the index plus one; the root is `ROOT_INO`.
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub name: String,
+    pub kind: Kind,
+    /// permission bits
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u32,
+    pub size: u64,
+    /// inode numbers, in archive order
+    pub children: Vec<u64>,
+    data: Data,
+}
+
+/// A member as the tar or zip reader found it.
+struct Member {
+    path: String,
+    kind: Kind,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: u32,
+    size: u64,
+    data: Data,
+    /// tar hard link: shares the contents of this earlier member
+    hardlink: Option<String>,
+}
+
+/// Read-only tree served out of a `.tar` or `.zip` file (`archive`
+/// export option). Opening the archive builds an index of every member;
+/// READs then go to the member's offset in the file. Inode numbers
+/// follow archive order, so they are the same every time the same
+/// archive is opened.
+pub struct ArchiveFs {
+    path: PathBuf,
+    file: fs::File,
+    nodes: Vec<Node>,
+    stamp: u32,
+    // the last deflated member READ from
+    inflated: Mutex<Option<(u64, Arc<Vec<u8>>)>>,
+}
+
+impl fmt::Debug for ArchiveFs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ArchiveFs")
+            .field("path", &self.path)
+            .field("nodes", &self.nodes.len())
+            .finish()
+    }
+}
+
+impl ArchiveFs {
+    /// Index the archive at `path`; tar or zip, told apart by content.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = fs::File::open(path)?;
+        let meta = file.metadata()?;
+
+        let mut magic = [0u8; 4];
+        let n = read_upto(&file, &mut magic, 0)?;
+        let members = match &magic[..n] {
+            [b'P', b'K', 3, 4] | [b'P', b'K', 5, 6] => zip_members(&file, meta.len())?,
+            [0x1f, 0x8b, ..] => bail!("compressed tar archives are not supported"),
+            _ => tar_members(&file, meta.len())?,
+        };
+
+        let nodes = build(members, meta.mtime() as u32);
+        debug!(path = %path.display(), nodes = nodes.len(), "archive indexed");
+
+        // A different archive at the same path makes old handles stale.
+        let mut stamp = crc32fast::Hasher::new();
+        stamp.update(&meta.len().to_be_bytes());
+        stamp.update(&meta.mtime().to_be_bytes());
+        stamp.update(&meta.mtime_nsec().to_be_bytes());
+        stamp.update(&meta.ino().to_be_bytes());
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            nodes,
+            stamp: stamp.finalize(),
+            inflated: Mutex::new(None),
+        })
+    }
+
+    /// Identifies this version of the archive; carried in handles.
+    pub fn stamp(&self) -> u32 {
+        self.stamp
+    }
+
+    pub fn node(&self, ino: u64) -> Option<&Node> {
+        self.nodes.get(usize::try_from(ino).ok()?.checked_sub(1)?)
+    }
+
+    /// Inode of `name` in directory `dir`.
+    pub fn lookup(&self, dir: u64, name: &str) -> Option<u64> {
+        self.node(dir)?
+            .children
+            .iter()
+            .copied()
+            .find(|&c| self.nodes[c as usize - 1].name == name)
+    }
+
+    /// Up to `count` bytes of file `ino` from `offset`.
+    pub fn read(&self, env: &Env, ino: u64, offset: u64, count: usize) -> io::Result<Vec<u8>> {
+        let node = self.node(ino).ok_or(io::ErrorKind::NotFound)?;
+        let end = offset.saturating_add(count as u64).min(node.size);
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+
+        match node.data {
+            Data::Empty => Ok(Vec::new()),
+            Data::Stored { offset: base } => {
+                let mut buf = vec![0u8; (end - offset) as usize];
+                let n = crate::nfs2::read_full(env, &self.file, &mut buf, base + offset)?;
+                buf.truncate(n);
+                Ok(buf)
+            }
+            Data::Deflated { .. } => {
+                let data = self.inflated(ino, node)?;
+                Ok(data[offset as usize..end as usize].to_vec())
+            }
+        }
+    }
+
+    fn inflated(&self, ino: u64, node: &Node) -> io::Result<Arc<Vec<u8>>> {
+        if let Some((i, data)) = &*self.inflated.lock().unwrap()
+            && *i == ino
+        {
+            return Ok(data.clone());
+        }
+        if node.size > MAX_INFLATE {
+            return Err(io::ErrorKind::FileTooLarge.into());
+        }
+
+        let data = Arc::new(member_bytes(&self.file, node.data, node.size)?);
+        *self.inflated.lock().unwrap() = Some((ino, data.clone()));
+        Ok(data)
+    }
+}
+
+/// Whole contents of a member, inflated and checked if deflated.
+fn member_bytes(file: &fs::File, data: Data, size: u64) -> io::Result<Vec<u8>> {
+    let bad =
+        |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("zip member: {what}"));
+    match data {
+        Data::Empty => Ok(Vec::new()),
+        Data::Stored { offset } => {
+            let mut buf = vec![0u8; size as usize];
+            file.read_exact_at(&mut buf, offset)?;
+            Ok(buf)
+        }
+        Data::Deflated { offset, csize, crc } => {
+            let mut raw = vec![0u8; csize as usize];
+            file.read_exact_at(&mut raw, offset)?;
+            let out = inflate(&raw, size as usize)?;
+            if out.len() as u64 != size {
+                return Err(bad("size mismatch"));
+            }
+            if crc32fast::hash(&out) != crc {
+                return Err(bad("CRC mismatch"));
+            }
+            Ok(out)
+        }
+    }
+}
+
+fn read_upto(file: &fs::File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    crate::nfs2::read_full(&Env::system(), file, buf, offset)
+}
+
+/// Path components of a member, or `None` if it tries to climb out
+/// with "..". Leading "/" and "./" are dropped.
+fn components(path: &str) -> Option<Vec<&str>> {
+    let parts: Vec<&str> = path
+        .split('/')
+        .filter(|c| !c.is_empty() && *c != ".")
+        .collect();
+    (!parts.contains(&"..")).then_some(parts)
+}
+
+/// Arrange members into a tree. Directories missing from the archive
+/// are made up; a later member replaces an earlier one of the same
+/// path, as when extracting.
+fn build(members: Vec<Member>, mtime: u32) -> Vec<Node> {
+    let dir = |name: &str, mtime| Node {
+        name: name.to_string(),
+        kind: Kind::Dir,
+        mode: 0o755,
+        uid: 0,
+        gid: 0,
+        mtime,
+        size: 0,
+        children: Vec::new(),
+        data: Data::Empty,
+    };
+
+    let mut nodes = vec![dir("", mtime)];
+    let mut by_path: HashMap<String, u64> = HashMap::from([(String::new(), ROOT_INO)]);
+
+    'members: for m in members {
+        let Some(parts) = components(&m.path) else {
+            warn!(path = m.path, "archive: skipping member outside the tree");
+            continue;
+        };
+
+        let mut node = Node {
+            name: parts.last().copied().unwrap_or_default().to_string(),
+            kind: m.kind,
+            mode: m.mode & 0o7777,
+            uid: m.uid,
+            gid: m.gid,
+            mtime: m.mtime,
+            size: m.size,
+            children: Vec::new(),
+            data: m.data,
+        };
+        if let Some(target) = &m.hardlink {
+            let target = components(target).map(|p| p.join("/"));
+            match target.and_then(|t| by_path.get(&t)) {
+                Some(&i) if nodes[i as usize - 1].kind == Kind::File => {
+                    let t = &nodes[i as usize - 1];
+                    (node.size, node.data) = (t.size, t.data);
+                }
+                _ => {
+                    warn!(
+                        path = m.path,
+                        "archive: skipping hard link to an unknown file"
+                    );
+                    continue;
+                }
+            }
+        }
+
+        // parent directories
+        let mut parent = ROOT_INO;
+        let mut prefix = String::new();
+        for part in parts.iter().take(parts.len().saturating_sub(1)) {
+            if !prefix.is_empty() {
+                prefix.push('/');
+            }
+            prefix.push_str(part);
+            parent = match by_path.get(&prefix) {
+                Some(&i) if nodes[i as usize - 1].kind == Kind::Dir => i,
+                Some(_) => {
+                    warn!(
+                        path = m.path,
+                        "archive: skipping member below a non-directory"
+                    );
+                    continue 'members;
+                }
+                None => {
+                    nodes.push(dir(part, m.mtime));
+                    let i = nodes.len() as u64;
+                    nodes[parent as usize - 1].children.push(i);
+                    by_path.insert(prefix.clone(), i);
+                    i
+                }
+            };
+        }
+
+        let path = parts.join("/");
+        match by_path.get(&path) {
+            Some(&i) => {
+                let old = &mut nodes[i as usize - 1];
+                if old.kind == Kind::Dir && node.kind == Kind::Dir {
+                    node.children = std::mem::take(&mut old.children);
+                } else if !old.children.is_empty() {
+                    warn!(
+                        path = m.path,
+                        "archive: skipping file over a non-empty directory"
+                    );
+                    continue;
+                }
+                if path.is_empty() {
+                    node.name = String::new();
+                }
+                *old = node;
+            }
+            None => {
+                nodes.push(node);
+                let i = nodes.len() as u64;
+                nodes[parent as usize - 1].children.push(i);
+                by_path.insert(path, i);
+            }
+        }
+    }
+    nodes
+}
+
+// ------------------------------------------------------------
+// tar
+// ------------------------------------------------------------
+
+/// NUL terminated string field.
+fn cstr(b: &[u8]) -> String {
+    let end = b.iter().position(|&c| c == 0).unwrap_or(b.len());
+    String::from_utf8_lossy(&b[..end]).into_owned()
+}
+
+/// Numeric header field: octal text, or GNU base-256 when the high bit
+/// of the first byte is set.
+fn tar_num(b: &[u8]) -> Option<u64> {
+    if b.first().is_some_and(|&c| c & 0x80 != 0) {
+        return b[1..]
+            .iter()
+            .try_fold(0u64, |n, &c| n.checked_mul(256).map(|n| n | c as u64));
+    }
+    let s = std::str::from_utf8(b).ok()?;
+    let s = s.trim_matches(|c| c == '\0' || c == ' ');
+    if s.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(s, 8).ok()
+}
+
+fn tar_checksum_ok(h: &[u8; 512]) -> bool {
+    let want = tar_num(&h[148..156]);
+    let field = |i: usize| if (148..156).contains(&i) { b' ' } else { h[i] };
+    let unsigned: u64 = (0..512).map(|i| field(i) as u64).sum();
+    let signed: i64 = (0..512).map(|i| field(i) as i8 as i64).sum();
+    want == Some(unsigned) || want.map(|w| w as i64) == Some(signed)
+}
+
+/// pax extended header records: "<len> <key>=<value>\n".
+fn pax_records(data: &[u8]) -> HashMap<String, String> {
+    let mut out = HashMap::new();
+    let mut rest = data;
+    while let Some(sp) = rest.iter().position(|&c| c == b' ') {
+        let Some(len) = std::str::from_utf8(&rest[..sp])
+            .ok()
+            .and_then(|l| l.parse::<usize>().ok())
+            .filter(|&l| l > sp && l <= rest.len())
+        else {
+            break;
+        };
+        let record = String::from_utf8_lossy(&rest[sp + 1..len]);
+        if let Some((k, v)) = record.trim_end_matches('\n').split_once('=') {
+            out.insert(k.to_string(), v.to_string());
+        }
+        rest = &rest[len..];
+    }
+    out
+}
+
+fn tar_members(file: &fs::File, len: u64) -> Result<Vec<Member>> {
+    let mut out = Vec::new();
+    let mut pos = 0u64;
+    let mut pax: HashMap<String, String> = HashMap::new();
+    let mut long_name = None;
+    let mut long_link = None;
+
+    let read = |offset: u64, size: u64| -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; usize::try_from(size)?];
+        file.read_exact_at(&mut buf, offset)?;
+        Ok(buf)
+    };
+
+    while pos + TAR_BLOCK <= len {
+        let mut h = [0u8; 512];
+        file.read_exact_at(&mut h, pos)?;
+        if h.iter().all(|&c| c == 0) {
+            break;
+        }
+        if !tar_checksum_ok(&h) {
+            bail!("not a tar or zip archive (bad tar header at offset {pos})");
+        }
+
+        let typeflag = h[156];
+        let mut size = tar_num(&h[124..136]).context("tar header: bad size")?;
+        if let Some(s) = pax.get("size") {
+            size = s.parse().context("pax header: bad size")?;
+        }
+        let data = pos + TAR_BLOCK;
+        if data.checked_add(size).is_none_or(|end| end > len) {
+            bail!("tar archive truncated at offset {pos}");
+        }
+        pos = data + size.div_ceil(TAR_BLOCK) * TAR_BLOCK;
+
+        match typeflag {
+            // attributes of the next member
+            b'x' => {
+                pax = pax_records(&read(data, size)?);
+                continue;
+            }
+            b'L' => {
+                long_name = Some(cstr(&read(data, size)?));
+                continue;
+            }
+            b'K' => {
+                long_link = Some(cstr(&read(data, size)?));
+                continue;
+            }
+            _ => {}
+        }
+
+        let name = pax.remove("path").or(long_name.take()).unwrap_or_else(|| {
+            let name = cstr(&h[0..100]);
+            let prefix = cstr(&h[345..500]);
+            if &h[257..262] == b"ustar" && !prefix.is_empty() {
+                format!("{prefix}/{name}")
+            } else {
+                name
+            }
+        });
+        let link = pax
+            .remove("linkpath")
+            .or(long_link.take())
+            .unwrap_or_else(|| cstr(&h[157..257]));
+        let num = |key: &str, field: &[u8]| {
+            pax.get(key)
+                .and_then(|v| v.split('.').next()?.parse().ok())
+                .or_else(|| tar_num(field))
+                .unwrap_or(0)
+        };
+        let (uid, gid, mtime) = (
+            num("uid", &h[108..116]) as u32,
+            num("gid", &h[116..124]) as u32,
+            num("mtime", &h[136..148]) as u32,
+        );
+        let mode = tar_num(&h[100..108]).unwrap_or(0o644) as u32;
+        pax.clear();
+
+        let (kind, hardlink) = match typeflag {
+            b'0' | 0 | b'7' if name.ends_with('/') => (Kind::Dir, None),
+            b'0' | 0 | b'7' => (Kind::File, None),
+            b'1' => (Kind::File, Some(link)),
+            b'2' => (Kind::Symlink(link), None),
+            b'5' => (Kind::Dir, None),
+            t => {
+                debug!(
+                    name,
+                    typeflag = t,
+                    "archive: skipping tar member of unsupported type"
+                );
+                continue;
+            }
+        };
+        let (size, data) = match kind {
+            Kind::File if size > 0 => (size, Data::Stored { offset: data }),
+            Kind::Symlink(ref l) => (l.len() as u64, Data::Empty),
+            _ => (0, Data::Empty),
+        };
+        out.push(Member {
+            path: name,
+            kind,
+            mode,
+            uid,
+            gid,
+            mtime,
+            size,
+            data,
+            hardlink,
+        });
+    }
+    Ok(out)
+}
+
+// ------------------------------------------------------------
+// zip
+// ------------------------------------------------------------
+
+fn le16(b: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([b[i], b[i + 1]])
+}
+
+fn le32(b: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes(b[i..i + 4].try_into().unwrap())
+}
+
+/// MS-DOS date and time fields (local time, taken as UTC) -> unix time.
+fn dos_time(date: u16, time: u16) -> u32 {
+    let (y, m, d) = (
+        1980 + (date >> 9) as i64,
+        ((date >> 5) & 0xf) as i64,
+        (date & 0x1f) as i64,
+    );
+    // days from civil, proleptic Gregorian
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (m + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    let secs =
+        (time >> 11) as i64 * 3600 + ((time >> 5) & 0x3f) as i64 * 60 + (time & 0x1f) as i64 * 2;
+    (days * 86400 + secs).max(0) as u32
+}
+
+/// Unix mtime from an "extended timestamp" extra field (0x5455), which
+/// Info-ZIP writes next to the DOS time.
+fn zip_unix_mtime(mut extra: &[u8]) -> Option<u32> {
+    while extra.len() >= 4 {
+        let (id, len) = (le16(extra, 0), le16(extra, 2) as usize);
+        let body = extra.get(4..4 + len)?;
+        if id == 0x5455 && body.len() >= 5 && body[0] & 1 != 0 {
+            return Some(le32(body, 1));
+        }
+        extra = &extra[4 + len..];
+    }
+    None
+}
+
+fn zip_members(file: &fs::File, len: u64) -> Result<Vec<Member>> {
+    // end of central directory: 22 bytes plus a comment of up to 64 KiB
+    let tail_len = len.min(22 + 0xffff) as usize;
+    let mut tail = vec![0u8; tail_len];
+    file.read_exact_at(&mut tail, len - tail_len as u64)?;
+    let end = (0..=tail_len.saturating_sub(22))
+        .rev()
+        .find(|&i| le32(&tail, i) == ZIP_END)
+        .context("zip: no end of central directory")?;
+    let e = &tail[end..];
+    let (count, cd_size, cd_offset) = (le16(e, 10), le32(e, 12), le32(e, 16));
+    if count == 0xffff || cd_offset == u32::MAX || cd_size == u32::MAX {
+        bail!("zip64 archives are not supported");
+    }
+
+    let mut cd = vec![0u8; cd_size as usize];
+    file.read_exact_at(&mut cd, cd_offset as u64)
+        .context("zip: central directory")?;
+
+    let mut out = Vec::new();
+    let mut i = 0usize;
+    for _ in 0..count {
+        if cd.len() < i + 46 || le32(&cd, i) != ZIP_CENTRAL {
+            bail!("zip: corrupt central directory");
+        }
+        let c = &cd[i..];
+        let (made_by, flags, method) = (le16(c, 4), le16(c, 8), le16(c, 10));
+        let (time, date, crc) = (le16(c, 12), le16(c, 14), le32(c, 16));
+        let (csize, usize_) = (le32(c, 20) as u64, le32(c, 24) as u64);
+        let (nlen, xlen, clen) = (
+            le16(c, 28) as usize,
+            le16(c, 30) as usize,
+            le16(c, 32) as usize,
+        );
+        let (attrs, local) = (le32(c, 38), le32(c, 42) as u64);
+        let Some(name) = c.get(46..46 + nlen) else {
+            bail!("zip: corrupt central directory");
+        };
+        let name = String::from_utf8_lossy(name).into_owned();
+        let extra = c.get(46 + nlen..46 + nlen + xlen).unwrap_or_default();
+        i += 46 + nlen + xlen + clen;
+
+        if flags & 1 != 0 {
+            warn!(name, "archive: skipping encrypted zip member");
+            continue;
+        }
+
+        // Unix attributes sit in the high half when made on Unix
+        let unix = if made_by >> 8 == 3 { attrs >> 16 } else { 0 };
+        let is_dir = name.ends_with('/') || unix & S_IFMT == S_IFDIR;
+        let mode = match unix & 0o7777 {
+            0 if is_dir => 0o755,
+            0 => 0o644,
+            m => m,
+        };
+
+        let mut h = [0u8; 30];
+        file.read_exact_at(&mut h, local)?;
+        if le32(&h, 0) != ZIP_LOCAL {
+            bail!("zip: bad local header for {name}");
+        }
+        let offset = local + 30 + le16(&h, 26) as u64 + le16(&h, 28) as u64;
+        if offset + csize > len {
+            bail!("zip: member {name} runs past the end of the archive");
+        }
+        let data = match method {
+            _ if is_dir || usize_ == 0 => Data::Empty,
+            0 if csize == usize_ => Data::Stored { offset },
+            8 => Data::Deflated { offset, csize, crc },
+            m => {
+                warn!(
+                    name,
+                    method = m,
+                    "archive: skipping zip member with unsupported compression"
+                );
+                continue;
+            }
+        };
+
+        let kind = if is_dir {
+            Kind::Dir
+        } else if unix & S_IFMT == S_IFLNK {
+            let target =
+                member_bytes(file, data, usize_).with_context(|| format!("zip: symlink {name}"))?;
+            Kind::Symlink(String::from_utf8_lossy(&target).into_owned())
+        } else {
+            Kind::File
+        };
+        let mtime = zip_unix_mtime(extra).unwrap_or_else(|| dos_time(date, time));
+
+        out.push(Member {
+            path: name,
+            size: if is_dir { 0 } else { usize_ },
+            data: if matches!(kind, Kind::File) {
+                data
+            } else {
+                Data::Empty
+            },
+            kind,
+            mode,
+            uid: 0,
+            gid: 0,
+            mtime,
+            hardlink: None,
+        });
+    }
+    Ok(out)
+}
+
+/// Raw deflate stream `src`, which must not inflate to more than `limit`
+/// bytes.
+fn inflate(src: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+    miniz_oxide::inflate::decompress_to_vec_with_limit(src, limit).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("corrupt deflate stream: {e}"),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{TempDir, tar};
+
+    const HELLO: &[u8] = b"hello hello hello hello\n";
+
+    fn lines() -> Vec<u8> {
+        (0..40)
+            .flat_map(|i| {
+                format!("line {i} of the archive test file, with some repetition\n").into_bytes()
+            })
+            .collect()
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // raw deflate streams from zlib (wbits -15)
+    const FIXED: &str = "cb48cdc9c957c84027b900";
+    const STORED: &str = "011800e7ff68656c6c6f2068656c6c6f2068656c6c6f2068656c6c6f0a";
+    const DYNAMIC: &str = "9dd5c10dc2400c05d13b55b8000ed88600e520e4682d6db228b1a07d44094c0173f2d377cf35e42463966a218fedd9f21d52b197ccd9e3289fac26fb5842b6784565e5580ffd5729aa0c558eaa33aa2ea89a507545d50d557776658883e950c643990f6540940951464499116548942931a6c4e0863025c6941853624c893125c6941853624c893325ce94387c354c893325ce943853e24c893325feb7922f";
+
+    fn open(bytes: &[u8]) -> (TempDir, ArchiveFs) {
+        let dir = TempDir::new();
+        let p = dir.path().join("bundle");
+        fs::write(&p, bytes).unwrap();
+        let a = ArchiveFs::open(&p).unwrap();
+        (dir, a)
+    }
+
+    /// Inode of a '/' separated path from the root.
+    fn walk(a: &ArchiveFs, path: &str) -> Option<u64> {
+        path.split('/')
+            .try_fold(ROOT_INO, |dir, name| a.lookup(dir, name))
+    }
+
+    fn names(a: &ArchiveFs, dir: u64) -> Vec<&str> {
+        let node = a.node(dir).unwrap();
+        node.children
+            .iter()
+            .map(|&c| a.node(c).unwrap().name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn inflates_every_block_type() {
+        assert_eq!(inflate(&hex(FIXED), HELLO.len()).unwrap(), HELLO);
+        assert_eq!(inflate(&hex(STORED), HELLO.len()).unwrap(), HELLO);
+        assert_eq!(inflate(&hex(DYNAMIC), lines().len()).unwrap(), lines());
+
+        // more output than the member claims is corrupt, not a bigger buffer
+        assert!(inflate(&hex(DYNAMIC), 100).is_err());
+        assert!(inflate(&hex(DYNAMIC)[..40], lines().len()).is_err());
+    }
+
+    #[test]
+    fn tar_members_form_a_tree() {
+        let (_dir, a) = open(&tar(&[
+            ("./docs/", b'5', b""),
+            ("./docs/readme.txt", b'0', b"read me\n"),
+            ("src/lib/deep.rs", b'0', b"fn deep() {}\n"),
+            ("docs/link", b'2', b"readme.txt"),
+            ("docs/copy", b'1', b"docs/readme.txt"),
+            ("../escape.txt", b'0', b"nope"),
+            ("docs/readme.txt", b'0', b"newer\n"),
+        ]));
+
+        assert_eq!(names(&a, ROOT_INO), ["docs", "src"]);
+        assert_eq!(
+            names(&a, walk(&a, "docs").unwrap()),
+            ["readme.txt", "link", "copy"]
+        );
+
+        // a later member of the same path replaces the earlier one
+        let readme = walk(&a, "docs/readme.txt").unwrap();
+        assert_eq!(a.read(&Env::default(), readme, 0, 100).unwrap(), b"newer\n");
+        // the hard link was made when readme held its first contents
+        let copy = walk(&a, "docs/copy").unwrap();
+        assert_eq!(a.read(&Env::default(), copy, 0, 100).unwrap(), b"read me\n");
+        assert_eq!(a.read(&Env::default(), copy, 5, 2).unwrap(), b"me");
+
+        // "src/lib" never appeared itself
+        let deep = walk(&a, "src/lib/deep.rs").unwrap();
+        assert_eq!(
+            a.node(walk(&a, "src/lib").unwrap()).unwrap().kind,
+            Kind::Dir
+        );
+        assert_eq!(a.node(deep).unwrap().mode, 0o644);
+        assert_eq!(a.node(deep).unwrap().uid, 1000);
+
+        let link = a.node(walk(&a, "docs/link").unwrap()).unwrap();
+        assert_eq!(link.kind, Kind::Symlink("readme.txt".into()));
+        assert!(a.lookup(ROOT_INO, "escape.txt").is_none());
+        assert!(a.lookup(ROOT_INO, "..").is_none());
+    }
+
+    #[test]
+    fn same_archive_gives_the_same_inodes() {
+        let bytes = tar(&[("a/b", b'0', b"1"), ("c", b'0', b"2"), ("a/d", b'0', b"3")]);
+        let (_d1, a) = open(&bytes);
+        let (_d2, b) = open(&bytes);
+        for p in ["a", "a/b", "c", "a/d"] {
+            assert_eq!(walk(&a, p), walk(&b, p), "{p}");
+        }
+    }
+
+    #[test]
+    fn gzip_and_garbage_are_refused() {
+        let dir = TempDir::new();
+        let p = dir.path().join("x");
+        fs::write(&p, [0x1f, 0x8b, 8, 0, 0, 0, 0, 0]).unwrap();
+        assert!(ArchiveFs::open(&p).is_err());
+        fs::write(&p, vec![b'x'; 1024]).unwrap();
+        assert!(ArchiveFs::open(&p).is_err());
+    }
+
+    #[test]
+    fn pax_size_past_u64_is_truncation() {
+        let dir = TempDir::new();
+        let p = dir.path().join("x");
+        let size = format!("29 size={}\n", u64::MAX);
+        fs::write(
+            &p,
+            tar(&[("pax", b'x', size.as_bytes()), ("f", b'0', b"data")]),
+        )
+        .unwrap();
+        let err = ArchiveFs::open(&p).unwrap_err();
+        assert!(err.to_string().contains("truncated"), "{err:#}");
+    }
+
+    /// name, method, unix mode, contents, stored bytes
+    type ZipMember<'a> = (&'a str, u16, u32, &'a [u8], &'a [u8]);
+
+    fn zip(members: &[ZipMember]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut cd = Vec::new();
+        for &(name, method, mode, data, raw) in members {
+            let crc = crc32fast::hash(data);
+            let offset = out.len() as u32;
+            let fixed = |sig: u32, v: &mut Vec<u8>| {
+                v.extend_from_slice(&sig.to_le_bytes());
+            };
+
+            fixed(ZIP_LOCAL, &mut out);
+            out.extend_from_slice(&[20, 0, 0, 0]); // version, flags
+            out.extend_from_slice(&method.to_le_bytes());
+            out.extend_from_slice(&[0, 0, 0x21, 0x58]); // time, date (2024-01-01)
+            out.extend_from_slice(&crc.to_le_bytes());
+            out.extend_from_slice(&(raw.len() as u32).to_le_bytes());
+            out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            out.extend_from_slice(&[0, 0]);
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(raw);
+
+            fixed(ZIP_CENTRAL, &mut cd);
+            cd.extend_from_slice(&[20, 3, 20, 0, 0, 0]); // made by unix, version, flags
+            cd.extend_from_slice(&method.to_le_bytes());
+            cd.extend_from_slice(&[0, 0, 0x21, 0x58]);
+            cd.extend_from_slice(&crc.to_le_bytes());
+            cd.extend_from_slice(&(raw.len() as u32).to_le_bytes());
+            cd.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            cd.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            cd.extend_from_slice(&[0; 8]); // extra, comment, disk, internal attrs
+            cd.extend_from_slice(&(mode << 16).to_le_bytes());
+            cd.extend_from_slice(&offset.to_le_bytes());
+            cd.extend_from_slice(name.as_bytes());
+        }
+        let cd_offset = out.len() as u32;
+        out.extend_from_slice(&cd);
+        out.extend_from_slice(&ZIP_END.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&(members.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(members.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(cd.len() as u32).to_le_bytes());
+        out.extend_from_slice(&cd_offset.to_le_bytes());
+        out.extend_from_slice(&[0, 0]);
+        out
+    }
+
+    #[test]
+    fn zip_members_stored_and_deflated() {
+        let lines = lines();
+        let (_dir, a) = open(&zip(&[
+            ("dir/", 0, 0o040755, b"", b""),
+            ("dir/hello.txt", 0, 0o100600, HELLO, HELLO),
+            ("dir/lines.txt", 8, 0o100644, &lines, &hex(DYNAMIC)),
+            (
+                "hello",
+                8,
+                0o120777,
+                b"dir/hello.txt",
+                &hex("4bc92cd2cf48cdc9c9d72ba9280100"),
+            ),
+        ]));
+
+        let hello = walk(&a, "dir/hello.txt").unwrap();
+        assert_eq!(a.read(&Env::default(), hello, 6, 5).unwrap(), b"hello");
+        assert_eq!(a.node(hello).unwrap().mode, 0o600);
+
+        let big = walk(&a, "dir/lines.txt").unwrap();
+        assert_eq!(a.node(big).unwrap().size, lines.len() as u64);
+        assert_eq!(a.read(&Env::default(), big, 0, 8192).unwrap(), lines);
+        assert_eq!(
+            a.read(&Env::default(), big, 2000, 8192).unwrap(),
+            &lines[2000..]
+        );
+
+        let link = a.node(walk(&a, "hello").unwrap()).unwrap();
+        assert_eq!(link.kind, Kind::Symlink("dir/hello.txt".into()));
+        // 2024-01-01 00:00:00
+        assert_eq!(link.mtime, 1_704_067_200);
+    }
+
+    #[test]
+    fn zip_member_with_a_bad_crc_is_not_served() {
+        let mut bytes = zip(&[("f", 8, 0o100644, HELLO, &hex(FIXED))]);
+        // flip the CRC in both headers
+        bytes[14] ^= 1;
+        let cd = bytes.len() - 22 - 47;
+        bytes[cd + 16] ^= 1;
+        let (_dir, a) = open(&bytes);
+        let f = a.lookup(ROOT_INO, "f").unwrap();
+        assert_eq!(
+            a.read(&Env::default(), f, 0, 10).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
@@ -0,0 +1,45 @@
+// src/changeid.rs
+
+use std::{
+    collections::HashMap,
+    fs,
+    os::unix::fs::MetadataExt,
+    sync::{Arc, Mutex},
+};
+
+// Dropped wholesale when full, like the handle cache.
+const MAX_ENTRIES: usize = 65536;
+
+// The counter is reported as microseconds, which stop short of this.
+const USECS: u32 = 1_000_000;
+
+/// Per-inode count of the mutations we made (`change_ids`). mtime only
+/// has whole seconds in NFSv2, so two writes within one second look the
+/// same to a caching client; reporting this count as the ctime
+/// microseconds makes every change visible. Memory only: a restart or
+/// reload starts all counts over.
+#[derive(Clone, Debug, Default)]
+pub struct ChangeIds {
+    inner: Arc<Mutex<HashMap<(u64, u64), u32>>>,
+}
+
+impl ChangeIds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a WRITE or SETATTR that succeeded on this inode.
+    pub fn bump(&self, meta: &fs::Metadata) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.len() >= MAX_ENTRIES {
+            inner.clear();
+        }
+        let n = inner.entry((meta.dev(), meta.ino())).or_default();
+        *n = (*n + 1) % USECS;
+    }
+
+    pub fn get(&self, meta: &fs::Metadata) -> u32 {
+        let inner = self.inner.lock().unwrap();
+        inner.get(&(meta.dev(), meta.ino())).copied().unwrap_or(0)
+    }
+}
@@ -0,0 +1,315 @@
+// src/combined.rs
+
+use crate::fsqueue::FsQueue;
+use crate::metrics::{Gauge, METRICS};
+use crate::mountd::Mountd;
+use crate::nfs2::Nfs2;
+use crate::rpc::{Reply, next_conn_id};
+use crate::shutdown::Shutdown;
+use crate::tcp;
+use crate::udp;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use tokio::net::{TcpListener, UdpSocket, UnixListener};
+use tracing::{Instrument, debug, info, info_span, warn};
+
+/// Unix socket clients are local processes; `clients` lists and logs
+/// see them as loopback.
+const UNIX_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// MOUNT and NFS on one socket (`single_port`, `unix_socket`). Some
+/// minimal clients send every call to the first port they talked to
+/// and expect the server to route by program number.
+#[derive(Clone)]
+pub struct Combined {
+    mountd: Mountd,
+    nfsd: Nfs2,
+}
+
+impl Combined {
+    pub fn new(mountd: Mountd, nfsd: Nfs2) -> Self {
+        Self { mountd, nfsd }
+    }
+
+    /// Route one call by the program number in its header. Anything
+    /// not NFS goes to mountd, which rejects unknown programs and
+    /// malformed headers. Each side times its own calls, so MOUNT calls
+    /// show up in mountd's latency and slow-request warnings, not nfsd's.
+    pub fn handle_call(&self, buf: &[u8], peer: SocketAddr) -> Reply {
+        // xid, msg type, rpc version, then the program
+        let prog = buf
+            .get(12..16)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()));
+        match prog {
+            Some(prog) if self.nfsd.serves(prog) => self.nfsd.handle_call(buf, peer),
+            _ => {
+                debug!(%peer, ?prog, "combined: routing to mountd");
+                self.mountd.handle_call(buf, peer).into()
+            }
+        }
+    }
+
+    pub async fn run_udp(self, sock: UdpSocket, stop: Shutdown, queue: FsQueue) {
+        let local = sock.local_addr().ok();
+        info!(?local, "nfsd+mountd listening (UDP)");
+
+        udp::serve(sock, stop, queue, move |buf, peer| {
+            self.handle_call(buf, peer)
+        })
+        .await;
+
+        info!(?local, "nfsd+mountd stopped (UDP)");
+    }
+
+    pub async fn run_tcp(
+        self,
+        listener: TcpListener,
+        stop: Shutdown,
+        queue: FsQueue,
+        max_inflight: usize,
+    ) {
+        let local = listener.local_addr().ok();
+        info!(?local, "nfsd+mountd listening (TCP)");
+
+        loop {
+            let (stream, peer) = tokio::select! {
+                _ = stop.stopped() => break,
+                res = listener.accept() => match res {
+                    Ok(v) => v,
+                    Err(e) => {
+                        warn!(?e, "combined: TCP accept failed");
+                        continue;
+                    }
+                },
+            };
+
+            let this = self.clone();
+            let stop = stop.clone();
+            let queue = queue.clone();
+            let conn_id = next_conn_id();
+
+            tokio::spawn(
+                async move {
+                    info!(%peer, "combined: TCP connected");
+                    // counted as NFS: mount calls are a handful per session
+                    let _conn = Gauge::inc(&METRICS.nfs_connections);
+
+                    tcp::serve(stream, stop, queue, max_inflight, move |buf| {
+                        this.handle_call(buf, peer)
+                    })
+                    .await;
+
+                    info!(%peer, "combined: TCP disconnected");
+                }
+                .instrument(info_span!("tcp", conn_id, %peer)),
+            );
+        }
+
+        info!(?local, "nfsd+mountd stopped (TCP)");
+    }
+
+    /// Record-marked RPC over a Unix domain socket, for local clients
+    /// that should not need rpcbind or a network port. The socket file
+    /// is removed again on shutdown.
+    pub async fn run_unix(
+        self,
+        listener: UnixListener,
+        path: PathBuf,
+        stop: Shutdown,
+        queue: FsQueue,
+        max_inflight: usize,
+    ) {
+        info!(path = %path.display(), "nfsd+mountd listening (Unix)");
+
+        loop {
+            let stream = tokio::select! {
+                _ = stop.stopped() => break,
+                res = listener.accept() => match res {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!(?e, "combined: Unix accept failed");
+                        continue;
+                    }
+                },
+            };
+
+            let this = self.clone();
+            let stop = stop.clone();
+            let queue = queue.clone();
+            let conn_id = next_conn_id();
+
+            tokio::spawn(
+                async move {
+                    info!("combined: Unix client connected");
+                    let _conn = Gauge::inc(&METRICS.nfs_connections);
+
+                    tcp::serve(stream, stop, queue, max_inflight, move |buf| {
+                        this.handle_call(buf, UNIX_PEER)
+                    })
+                    .await;
+
+                    info!("combined: Unix client disconnected");
+                }
+                .instrument(info_span!("unix", conn_id)),
+            );
+        }
+
+        if let Err(e) = std::fs::remove_file(&path) {
+            warn!(?e, path = %path.display(), "could not remove Unix socket");
+        }
+        info!(path = %path.display(), "nfsd+mountd stopped (Unix)");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::Env;
+    use crate::export::Exports;
+    use crate::mountd::{MOUNT_PROG, MountTable};
+    use crate::nfs2::{DisabledProcs, FhKey, NFS_PROG, NFS_VERS};
+    use crate::rpc::PROG_UNAVAIL;
+    use crate::testutil::{CLIENT, TempDir, accepted, call, export};
+    use crate::xdr::XdrW;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::net::{TcpStream, UnixStream};
+
+    /// Exported directory `share` under `dir`, served by one Combined.
+    fn combined(dir: &TempDir) -> (Combined, PathBuf) {
+        let share = dir.path().join("share");
+        std::fs::create_dir(&share).unwrap();
+        let exports = Exports::new(vec![export(&share)]);
+        let mounts = MountTable::default();
+        let svc = Combined::new(
+            Mountd::new(
+                exports.clone(),
+                mounts.clone(),
+                FhKey::default(),
+                MOUNT_PROG,
+            ),
+            Nfs2::new(
+                exports,
+                mounts,
+                FhKey::default(),
+                DisabledProcs::default(),
+                Env::default(),
+                NFS_PROG,
+                FsQueue::default(),
+            ),
+        );
+        (svc, share)
+    }
+
+    fn mnt_call(share: &std::path::Path) -> Vec<u8> {
+        let mut args = XdrW::new();
+        args.put_string(&share.to_string_lossy());
+        call(MOUNT_PROG, 1, 1, Some(0), &args.buf)
+    }
+
+    /// Check a MNT reply and return the handle it carries.
+    fn mounted(reply: &[u8]) -> Vec<u8> {
+        let (_, mut r) = accepted(reply);
+        assert_eq!(r.get_u32().unwrap(), 0, "MNT status");
+        r.get_fixed(32).unwrap()
+    }
+
+    /// Check that a GETATTR reply describes a directory.
+    fn getattr_is_dir(reply: &[u8]) {
+        let (_, mut r) = accepted(reply);
+        assert_eq!(r.get_u32().unwrap(), 0, "GETATTR status");
+        assert_eq!(r.get_u32().unwrap(), 2, "NFDIR");
+    }
+
+    /// Send one record-marked call and read the reply record.
+    async fn rpc<S: AsyncRead + AsyncWrite + Unpin>(s: &mut S, call: &[u8]) -> Vec<u8> {
+        s.write_all(&(0x8000_0000 | call.len() as u32).to_be_bytes())
+            .await
+            .unwrap();
+        s.write_all(call).await.unwrap();
+        let len = s.read_u32().await.unwrap() & 0x7fff_ffff;
+        let mut reply = vec![0u8; len as usize];
+        s.read_exact(&mut reply).await.unwrap();
+        reply
+    }
+
+    #[tokio::test]
+    async fn routes_each_call_by_program_number() {
+        let dir = TempDir::new();
+        let (svc, share) = combined(&dir);
+        let handle = async |call: &[u8]| svc.handle_call(call, CLIENT).finish().await.unwrap();
+
+        let fh = mounted(&handle(&mnt_call(&share)).await);
+        getattr_is_dir(&handle(&call(NFS_PROG, NFS_VERS, 1, Some(0), &fh)).await);
+
+        let reply = handle(&call(100_099, 1, 0, None, &[])).await;
+        assert_eq!(accepted(&reply).0, PROG_UNAVAIL);
+    }
+
+    #[tokio::test]
+    async fn mount_and_getattr_over_one_udp_and_tcp_port() {
+        let dir = TempDir::new();
+        let (svc, share) = combined(&dir);
+        let stop = Shutdown::new();
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (udp_addr, tcp_addr) = (udp.local_addr().unwrap(), tcp.local_addr().unwrap());
+        let servers = [
+            tokio::spawn(svc.clone().run_udp(udp, stop.clone(), FsQueue::default())),
+            tokio::spawn(svc.run_tcp(
+                tcp,
+                stop.clone(),
+                FsQueue::default(),
+                tcp::DEFAULT_MAX_INFLIGHT,
+            )),
+        ];
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(udp_addr).await.unwrap();
+        let mut buf = [0u8; 1024];
+        let mut udp_rpc = async |call: &[u8]| {
+            client.send(call).await.unwrap();
+            let n = client.recv(&mut buf).await.unwrap();
+            buf[..n].to_vec()
+        };
+        let fh = mounted(&udp_rpc(&mnt_call(&share)).await);
+        getattr_is_dir(&udp_rpc(&call(NFS_PROG, NFS_VERS, 1, Some(0), &fh)).await);
+        let reply = udp_rpc(&call(100_099, 1, 0, None, &[])).await;
+        assert_eq!(accepted(&reply).0, PROG_UNAVAIL);
+
+        let mut s = TcpStream::connect(tcp_addr).await.unwrap();
+        let fh = mounted(&rpc(&mut s, &mnt_call(&share)).await);
+        getattr_is_dir(&rpc(&mut s, &call(NFS_PROG, NFS_VERS, 1, Some(0), &fh)).await);
+        let reply = rpc(&mut s, &call(100_099, 1, 0, None, &[])).await;
+        assert_eq!(accepted(&reply).0, PROG_UNAVAIL);
+
+        stop.trigger();
+        for server in servers {
+            server.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn mount_and_getattr_over_a_unix_socket() {
+        let dir = TempDir::new();
+        let (svc, share) = combined(&dir);
+
+        let path = dir.path().join("nfs.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let stop = Shutdown::new();
+        let server = tokio::spawn(svc.run_unix(
+            listener,
+            path.clone(),
+            stop.clone(),
+            FsQueue::default(),
+            tcp::DEFAULT_MAX_INFLIGHT,
+        ));
+
+        let mut s = UnixStream::connect(&path).await.unwrap();
+        let fh = mounted(&rpc(&mut s, &mnt_call(&share)).await);
+        getattr_is_dir(&rpc(&mut s, &call(NFS_PROG, NFS_VERS, 1, Some(0), &fh)).await);
+
+        stop.trigger();
+        server.await.unwrap();
+        assert!(!path.exists(), "socket file removed on shutdown");
+    }
+}
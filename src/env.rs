@@ -0,0 +1,159 @@
+// src/env.rs
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+type FileTimes = dyn Fn(&fs::Metadata) -> [u32; 3] + Send + Sync;
+type ReadAt = dyn Fn(&fs::File, &mut [u8], u64) -> io::Result<usize> + Send + Sync;
+type SyncData = dyn Fn(&fs::File) -> io::Result<()> + Send + Sync;
+type ReadDir = dyn Fn(&Path) -> io::Result<Listing> + Send + Sync;
+
+/// Paths of a directory's entries, in the order the filesystem lists
+/// them; an `Err` is the listing itself failing.
+pub type Listing = Box<dyn Iterator<Item = io::Result<PathBuf>>>;
+
+/// Sources of nondeterminism: RPC xids for calls we originate, the
+/// file times we report, how much one read returns, what a directory
+/// listing yields and whether a flush to disk succeeds. Production uses random xids, each file's own
+/// times and the real syscalls; tests inject fixed values to get
+/// byte-identical packets, and behaviour no healthy disk shows on
+/// demand. This is the one seam for faking the filesystem: a new
+/// kind of fault goes here, not into a handler.
+/// (SETATTR's "now" is the kernel's, through UTIME_NOW.)
+#[derive(Clone)]
+pub struct Env {
+    xid: Arc<dyn Fn() -> u32 + Send + Sync>,
+    file_times: Arc<FileTimes>,
+    read_at: Arc<ReadAt>,
+    read_dir: Arc<ReadDir>,
+    sync_data: Arc<SyncData>,
+}
+
+impl Env {
+    pub fn system() -> Self {
+        Self {
+            xid: Arc::new(rand::random::<u32>),
+            file_times: Arc::new(|m| [m.atime() as u32, m.mtime() as u32, m.ctime() as u32]),
+            read_at: Arc::new(|f, buf, offset| f.read_at(buf, offset)),
+            read_dir: Arc::new(|dir| {
+                let rd = fs::read_dir(dir)?;
+                Ok(Box::new(rd.map(|e| e.map(|e| e.path()))) as Listing)
+            }),
+            sync_data: Arc::new(fs::File::sync_data),
+        }
+    }
+
+    /// Constant xid; every file reports `time` as its times.
+    #[cfg(test)]
+    pub fn fixed(xid: u32, time: std::time::SystemTime) -> Self {
+        let secs = time
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as u32);
+        Self {
+            xid: Arc::new(move || xid),
+            file_times: Arc::new(move |_| [secs; 3]),
+            ..Self::system()
+        }
+    }
+
+    /// Reads return at most `chunk` bytes, and every third one is
+    /// interrupted, as on a network filesystem taking signals.
+    #[cfg(test)]
+    pub fn chunked_reads(self, chunk: usize) -> Self {
+        let calls = std::sync::atomic::AtomicU32::new(0);
+        Self {
+            read_at: Arc::new(move |f, buf, offset| {
+                let n = calls.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+                if n.is_multiple_of(3) {
+                    return Err(io::ErrorKind::Interrupted.into());
+                }
+                let len = buf.len().min(chunk);
+                f.read_at(&mut buf[..len], offset)
+            }),
+            ..self
+        }
+    }
+
+    /// Each read first waits `delay`, as on a disk that is struggling.
+    #[cfg(test)]
+    pub fn slow_reads(self, delay: std::time::Duration) -> Self {
+        let read_at = self.read_at.clone();
+        Self {
+            read_at: Arc::new(move |f, buf, offset| {
+                std::thread::sleep(delay);
+                read_at(f, buf, offset)
+            }),
+            ..self
+        }
+    }
+
+    /// Listings break with `kind` after yielding `after` entries.
+    #[cfg(test)]
+    pub fn failing_listings(self, after: usize, kind: io::ErrorKind) -> Self {
+        let read_dir = self.read_dir.clone();
+        Self {
+            read_dir: Arc::new(move |dir| {
+                let entries = read_dir(dir)?.take(after);
+                Ok(Box::new(entries.chain(std::iter::once(Err(kind.into())))) as Listing)
+            }),
+            ..self
+        }
+    }
+
+    /// Listings start with `name`, which is gone by the time it is
+    /// looked at, as when a file is removed mid-listing.
+    #[cfg(test)]
+    pub fn vanished_entry(self, name: &str) -> Self {
+        let read_dir = self.read_dir.clone();
+        let name = name.to_string();
+        Self {
+            read_dir: Arc::new(move |dir| {
+                let gone = std::iter::once(Ok(dir.join(&name)));
+                Ok(Box::new(gone.chain(read_dir(dir)?)) as Listing)
+            }),
+            ..self
+        }
+    }
+
+    /// Every flush to disk fails with `kind`.
+    #[cfg(test)]
+    pub fn failing_sync(self, kind: io::ErrorKind) -> Self {
+        Self {
+            sync_data: Arc::new(move |_| Err(kind.into())),
+            ..self
+        }
+    }
+
+    pub fn xid(&self) -> u32 {
+        (self.xid)()
+    }
+
+    /// atime, mtime and ctime (unix seconds) reported for a file.
+    pub fn file_times(&self, meta: &fs::Metadata) -> [u32; 3] {
+        (self.file_times)(meta)
+    }
+
+    /// One positioned read, which may come back short.
+    pub fn read_at(&self, f: &fs::File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (self.read_at)(f, buf, offset)
+    }
+
+    /// List a directory; "." and ".." are not in it.
+    pub fn read_dir(&self, dir: &Path) -> io::Result<Listing> {
+        (self.read_dir)(dir)
+    }
+
+    /// Flush a file's data to stable storage.
+    pub fn sync_data(&self, f: &fs::File) -> io::Result<()> {
+        (self.sync_data)(f)
+    }
+}
+
+impl Default for Env {
+    fn default() -> Self {
+        Self::system()
+    }
+}
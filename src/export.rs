@@ -1,15 +1,336 @@
 // src/export.rs
 
-use std::{path::PathBuf, sync::Arc};
+use crate::archive::ArchiveFs;
+use crate::changeid::ChangeIds;
+use crate::peer::PeerInfo;
+use anyhow::{Context, Result, bail};
+use std::{
+    ffi::CString,
+    fs, io, mem,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    ptr,
+    sync::Arc,
+};
+
+// Groups with huge member lists need big buffers; stop somewhere.
+const MAX_PW_BUF: usize = 1 << 20;
+
+/// Host identity a request runs as, after squashing and id mapping.
+#[derive(Clone, Debug)]
+pub struct Cred {
+    pub uid: u32,
+    pub gid: u32,
+    pub gids: Vec<u32>,
+}
+
+/// One `client_start:host_start:count` range of an id map.
+#[derive(Clone, Copy, Debug)]
+pub struct IdRange {
+    pub client: u32,
+    pub host: u32,
+    pub count: u32,
+}
+
+/// Static uid or gid translation table, in the spirit of user namespaces.
+/// An empty map is the identity; a non-empty map only knows its ranges.
+#[derive(Clone, Debug, Default)]
+pub struct IdMap(Vec<IdRange>);
+
+impl IdMap {
+    pub fn parse(specs: &[String]) -> Result<Self> {
+        let mut v = Vec::with_capacity(specs.len());
+        for spec in specs {
+            let parts: Vec<&str> = spec.split(':').collect();
+            let [client, host, count] = parts[..] else {
+                bail!("invalid id map range '{spec}', expected client_start:host_start:count");
+            };
+            let num = |s: &str| {
+                s.trim()
+                    .parse::<u32>()
+                    .with_context(|| format!("id map range '{spec}'"))
+            };
+            let range = IdRange {
+                client: num(client)?,
+                host: num(host)?,
+                count: num(count)?,
+            };
+            if range.count == 0
+                || range.client.checked_add(range.count - 1).is_none()
+                || range.host.checked_add(range.count - 1).is_none()
+            {
+                bail!("invalid id map range '{spec}'");
+            }
+            v.push(range);
+        }
+        Ok(Self(v))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// host id -> id presented to the client
+    pub fn to_client(&self, host: u32) -> Option<u32> {
+        self.0
+            .iter()
+            .find(|r| host >= r.host && host - r.host < r.count)
+            .map(|r| r.client + (host - r.host))
+    }
+
+    /// client id -> host id
+    pub fn to_host(&self, client: u32) -> Option<u32> {
+        self.0
+            .iter()
+            .find(|r| client >= r.client && client - r.client < r.count)
+            .map(|r| r.host + (client - r.client))
+    }
+}
+
+/// uid of the system user `name` (getpwnam, so NSS sources count too).
+pub fn user_id(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        // SAFETY: a C struct of integers and pointers; all-zero is valid
+        let mut pw: libc::passwd = unsafe { mem::zeroed() };
+        let mut found = ptr::null_mut();
+        // SAFETY: every pointer is valid for the call, and nothing that
+        // points into `buf` is kept past it.
+        let rc = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut pw,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut found,
+            )
+        };
+        match rc {
+            0 if found.is_null() => return None,
+            0 => return Some(pw.pw_uid),
+            libc::ERANGE if buf.len() < MAX_PW_BUF => buf.resize(buf.len() * 2, 0),
+            _ => return None,
+        }
+    }
+}
+
+/// gid of the system group `name` (getgrnam).
+pub fn group_id(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        // SAFETY: as in `user_id`
+        let mut gr: libc::group = unsafe { mem::zeroed() };
+        let mut found = ptr::null_mut();
+        // SAFETY: as in `user_id`
+        let rc = unsafe {
+            libc::getgrnam_r(
+                name.as_ptr(),
+                &mut gr,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut found,
+            )
+        };
+        match rc {
+            0 if found.is_null() => return None,
+            0 => return Some(gr.gr_gid),
+            libc::ERANGE if buf.len() < MAX_PW_BUF => buf.resize(buf.len() * 2, 0),
+            _ => return None,
+        }
+    }
+}
 
-#[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub struct Export {
     pub path: PathBuf,
     pub read_only: bool,
+    /// identity for AUTH_NULL callers
     pub anon_uid: u32,
     pub anon_gid: u32,
+    /// identity squashed callers are mapped to
+    pub squash_uid: u32,
+    pub squash_gid: u32,
+    pub root_squash: bool,
+    pub all_squash: bool,
     pub clients: Vec<String>,
+    pub uid_map: IdMap,
+    pub gid_map: IdMap,
+    pub read_ahead: bool,
+    /// report this unix time as atime/mtime/ctime of every file
+    pub fixed_mtime: Option<u32>,
+    /// name clients mount and see in the export list, instead of `path`
+    pub alias: Option<String>,
+    /// READDIR leaves out, and LOOKUP refuses, names longer than this
+    /// many bytes
+    pub max_name_len: Option<usize>,
+    /// refuse READDIR; LOOKUP of known names still works
+    pub no_readdir: bool,
+    /// xattr holding RISC OS load/exec info, reported in fattr.rdev
+    pub riscos_xattr: Option<String>,
+    /// served to clients that no `clients` list names (`default_export`)
+    pub guest: bool,
+    /// report symlinks as their in-export targets instead of as links
+    pub deref_symlinks: bool,
+    /// WRITE and SETATTR may not grow a file past this many bytes
+    pub max_file_size: Option<u64>,
+    /// hand this export's root to calls that carry an empty handle
+    pub empty_fh_root_fallback: bool,
+    /// count our changes per inode and report them as ctime usecs
+    pub change_ids: Option<ChangeIds>,
+    /// serve this tar/zip instead of `path`, which is then only the
+    /// name the export goes by; always read-only
+    pub archive: Option<Arc<ArchiveFs>>,
+}
+
+/// Parse a `clients` entry: "*", an address, or a CIDR block such as
+/// "192.168.1.0/24". Host names are not resolved and do not parse.
+fn parse_client(spec: &str) -> Option<(IpAddr, Option<u32>)> {
+    let spec = spec.trim();
+    if spec == "*" {
+        return Some((IpAddr::from([0u8; 4]), Some(0)));
+    }
+    let (net, prefix) = match spec.split_once('/') {
+        Some((net, len)) => (net, Some(len.parse().ok()?)),
+        None => (spec, None),
+    };
+    Some((net.parse::<IpAddr>().ok()?.to_canonical(), prefix))
+}
+
+pub fn valid_client(spec: &str) -> bool {
+    parse_client(spec).is_some()
+}
+
+fn client_matches(spec: &str, ip: IpAddr) -> bool {
+    let Some((net, prefix)) = parse_client(spec) else {
+        return false;
+    };
+    if spec.trim() == "*" {
+        return true;
+    }
+
+    match (net, ip.to_canonical()) {
+        (IpAddr::V4(n), IpAddr::V4(a)) => {
+            let len = prefix.unwrap_or(32).min(32);
+            let mask = u32::MAX.checked_shl(32 - len).unwrap_or(0);
+            u32::from(n) & mask == u32::from(a) & mask
+        }
+        (IpAddr::V6(n), IpAddr::V6(a)) => {
+            let len = prefix.unwrap_or(128).min(128);
+            let mask = u128::MAX.checked_shl(128 - len).unwrap_or(0);
+            u128::from(n) & mask == u128::from(a) & mask
+        }
+        _ => false,
+    }
+}
+
+impl Export {
+    /// Whether the `clients` list admits `ip`. An empty list admits all.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        self.clients.is_empty() || self.clients.iter().any(|c| client_matches(c, ip))
+    }
+
+    /// Attributes clients see for `p`, which must lie in this export.
+    /// Symlinks are reported as links, or with `deref_symlinks` as
+    /// their target while that stays inside the export. The root itself
+    /// is always followed, like the path it was mounted by.
+    pub fn metadata(&self, p: &Path) -> io::Result<fs::Metadata> {
+        if p == self.path {
+            return fs::metadata(p);
+        }
+        let meta = fs::symlink_metadata(p)?;
+        if !meta.file_type().is_symlink() || !self.deref_symlinks {
+            return Ok(meta);
+        }
+        match (fs::canonicalize(p), fs::canonicalize(&self.path)) {
+            (Ok(target), Ok(root)) if target.starts_with(&root) => fs::metadata(target),
+            _ => Ok(meta),
+        }
+    }
+
+    /// Whether `p` names something inside this export once `..` and
+    /// symlinks in its directory part are resolved. The last component
+    /// is not followed: a link inside the export is inside, wherever it
+    /// points. Only paths that pass may be cached or served.
+    pub fn contains(&self, p: &Path) -> bool {
+        if p == self.path {
+            return true;
+        }
+        let Ok(root) = fs::canonicalize(&self.path) else {
+            return false;
+        };
+        let real = match (p.parent(), p.file_name()) {
+            (Some(dir), Some(name)) => fs::canonicalize(dir).map(|d| d.join(name)),
+            _ => fs::canonicalize(p),
+        };
+        real.is_ok_and(|r| r.starts_with(&root))
+    }
+
+    /// Stable id carried in file handles.
+    pub fn id(&self) -> u32 {
+        crc32fast::hash(self.path.as_os_str().as_encoded_bytes())
+    }
+
+    /// Client-facing export name: the alias if set, else the host path.
+    pub fn name(&self) -> String {
+        match &self.alias {
+            Some(a) => a.clone(),
+            None => self.path.to_string_lossy().into_owned(),
+        }
+    }
+
+    /// Owner uid as reported to clients. Unmapped ids become anon.
+    pub fn client_uid(&self, host: u32) -> u32 {
+        if self.uid_map.is_empty() {
+            return host;
+        }
+        self.uid_map.to_client(host).unwrap_or(self.anon_uid)
+    }
+
+    pub fn client_gid(&self, host: u32) -> u32 {
+        if self.gid_map.is_empty() {
+            return host;
+        }
+        self.gid_map.to_client(host).unwrap_or(self.anon_gid)
+    }
+
+    /// Host uid for a uid sent by a client. Unmapped ids become anon.
+    pub fn host_uid(&self, client: u32) -> u32 {
+        if self.uid_map.is_empty() {
+            return client;
+        }
+        self.uid_map.to_host(client).unwrap_or(self.anon_uid)
+    }
+
+    pub fn host_gid(&self, client: u32) -> u32 {
+        if self.gid_map.is_empty() {
+            return client;
+        }
+        self.gid_map.to_host(client).unwrap_or(self.anon_gid)
+    }
+
+    /// Host identity for a call: AUTH_NULL runs as anon, squashed
+    /// callers as the squash target, everyone else through the id map.
+    pub fn cred(&self, peer: &PeerInfo) -> Cred {
+        match &peer.cred {
+            None => Cred {
+                uid: self.anon_uid,
+                gid: self.anon_gid,
+                gids: Vec::new(),
+            },
+            Some(u) if self.all_squash || (self.root_squash && u.uid == 0) => Cred {
+                uid: self.squash_uid,
+                gid: self.squash_gid,
+                gids: Vec::new(),
+            },
+            Some(u) => Cred {
+                uid: self.host_uid(u.uid),
+                gid: self.host_gid(u.gid),
+                gids: u.aux_gids.iter().map(|&g| self.host_gid(g)).collect(),
+            },
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -22,11 +343,117 @@ impl Exports {
     pub fn list(&self) -> &[Export] {
         &self.0
     }
-    #[allow(dead_code)]
     pub fn by_path(&self, p: &str) -> Option<Export> {
         self.0
             .iter()
             .find(|e| e.path.to_string_lossy() == p)
             .cloned()
     }
+    /// Look up an export by the name clients mount it as.
+    pub fn by_name(&self, name: &str) -> Option<Export> {
+        self.0.iter().find(|e| e.name() == name).cloned()
+    }
+
+    /// Named by no non-empty `clients` list.
+    fn unlisted(&self, ip: IpAddr) -> bool {
+        !self
+            .0
+            .iter()
+            .any(|e| e.clients.iter().any(|c| client_matches(c, ip)))
+    }
+
+    /// Whether `ip` may use `export`: its `clients` list admits the
+    /// caller, or it is the guest export and the caller is unknown.
+    pub fn permits(&self, export: &Export, ip: IpAddr) -> bool {
+        export.allows(ip) || (export.guest && self.unlisted(ip))
+    }
+
+    /// Export served to `ip` for a MNT of `name`. Callers that no
+    /// `clients` list names fall back to the guest export, if any.
+    pub fn for_mount(&self, name: &str, ip: IpAddr) -> Option<Export> {
+        match self.by_name(name) {
+            Some(e) if self.permits(&e, ip) => Some(e),
+            _ if self.unlisted(ip) => self.0.iter().find(|e| e.guest).cloned(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::{RpcAuth, RpcAuthUnix};
+    use crate::testutil::{CLIENT, export};
+
+    fn peer(uid: u32, gid: u32) -> PeerInfo {
+        let auth = RpcAuth::Unix(RpcAuthUnix {
+            machine: "client".into(),
+            uid,
+            gid,
+            aux_gids: vec![gid + 1],
+        });
+        PeerInfo::new(CLIENT, &auth)
+    }
+
+    #[test]
+    fn id_map_translates_both_ways() {
+        let m = IdMap::parse(&["1000:100000:10".into(), "0:200000:1".into()]).unwrap();
+        assert_eq!(m.to_host(1005), Some(100005));
+        assert_eq!(m.to_host(0), Some(200000));
+        assert_eq!(m.to_host(1010), None);
+        assert_eq!(m.to_client(100009), Some(1009));
+        assert_eq!(m.to_client(99999), None);
+    }
+
+    #[test]
+    fn id_map_errors_name_the_range() {
+        let e = IdMap::parse(&["1000:x:10".into()]).unwrap_err();
+        assert!(format!("{e:#}").contains("id map range '1000:x:10'"));
+        assert!(IdMap::parse(&["1:2".into()]).is_err());
+        assert!(IdMap::parse(&["1:2:0".into()]).is_err());
+        assert!(IdMap::parse(&["4294967295:0:2".into()]).is_err());
+    }
+
+    #[test]
+    fn cred_goes_through_the_id_maps() {
+        let mut e = export(Path::new("/srv"));
+        e.uid_map = IdMap::parse(&["1000:100000:100".into()]).unwrap();
+        e.gid_map = IdMap::parse(&["1000:100000:100".into()]).unwrap();
+
+        let c = e.cred(&peer(1000, 1000));
+        assert_eq!((c.uid, c.gid, c.gids), (100000, 100000, vec![100001]));
+
+        // outside the map: anon, in both directions
+        let c = e.cred(&peer(5, 5));
+        assert_eq!((c.uid, c.gid), (e.anon_uid, e.anon_gid));
+        assert_eq!(e.client_uid(0), e.anon_uid);
+        assert_eq!(e.client_uid(100042), 1042);
+    }
+
+    #[test]
+    fn null_squashed_and_mapped_callers_get_distinct_ids() {
+        let mut e = Export {
+            anon_uid: 65534,
+            anon_gid: 65533,
+            squash_uid: 4000,
+            squash_gid: 4001,
+            root_squash: true,
+            ..export(Path::new("/srv"))
+        };
+        e.uid_map = IdMap::parse(&["1000:100000:100".into()]).unwrap();
+        e.gid_map = IdMap::parse(&["1000:100000:100".into()]).unwrap();
+        let ids = |c: Cred| (c.uid, c.gid, c.gids);
+
+        let null = PeerInfo::new(CLIENT, &RpcAuth::Null);
+        assert_eq!(ids(e.cred(&null)), (65534, 65533, vec![]));
+        assert_eq!(ids(e.cred(&peer(0, 0))), (4000, 4001, vec![]));
+        assert_eq!(
+            ids(e.cred(&peer(1000, 1000))),
+            (100000, 100000, vec![100001])
+        );
+
+        e.all_squash = true;
+        assert_eq!(ids(e.cred(&peer(1000, 1000))), (4000, 4001, vec![]));
+        assert_eq!(ids(e.cred(&null)), (65534, 65533, vec![]));
+    }
 }
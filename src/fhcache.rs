@@ -0,0 +1,61 @@
+// src/fhcache.rs
+
+use std::{
+    collections::HashMap,
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tracing::debug;
+
+// Dropped wholesale when full; repopulated by the next walks.
+const MAX_ENTRIES: usize = 65536;
+
+/// Remembers where (dev, ino) pairs were last seen so handle resolution
+/// can skip the export walk. Filled by every operation that already
+/// touched the path (MNT, LOOKUP, READDIR, successful walks). Entries
+/// are re-checked against the filesystem on use, so a rename or delete
+/// only costs a fresh walk.
+#[derive(Clone, Default)]
+pub struct HandleCache {
+    inner: Arc<Mutex<HashMap<(u64, u64), PathBuf>>>,
+}
+
+impl HandleCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, dev: u64, ino: u64, path: &Path) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.len() >= MAX_ENTRIES {
+            inner.clear();
+        }
+        inner.insert((dev, ino), path.to_path_buf());
+    }
+
+    pub fn insert_meta(&self, meta: &fs::Metadata, path: &Path) {
+        self.insert(meta.dev(), meta.ino(), path);
+    }
+
+    /// Cached path for (dev, ino), if it still names that inode.
+    pub fn get(&self, dev: u64, ino: u64) -> Option<PathBuf> {
+        let p = self.inner.lock().unwrap().get(&(dev, ino)).cloned()?;
+
+        match fs::symlink_metadata(&p) {
+            Ok(m) if m.dev() == dev && m.ino() == ino => {
+                debug!(path = %p.display(), "fhcache: hit");
+                Some(p)
+            }
+            _ => {
+                debug!(path = %p.display(), "fhcache: stale entry");
+                let mut inner = self.inner.lock().unwrap();
+                if inner.get(&(dev, ino)) == Some(&p) {
+                    inner.remove(&(dev, ino));
+                }
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,64 @@
+// src/fsqueue.rs
+
+use crate::metrics::{Gauge, METRICS};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinError;
+use tracing::Span;
+
+/// Calls allowed to touch the filesystem at once.
+pub const DEFAULT_FS_CONCURRENCY: usize = 32;
+
+/// Runs request handlers on the blocking pool, at most `limit` at a
+/// time. A burst of calls then waits here instead of piling thousands
+/// of syscalls onto a slow disk, which only makes every one of them
+/// slower. Shared by all services of the process.
+#[derive(Clone)]
+pub struct FsQueue {
+    slots: Arc<Semaphore>,
+    limit: usize,
+}
+
+impl FsQueue {
+    pub fn new(limit: usize) -> Self {
+        let limit = limit.max(1);
+        Self {
+            slots: Arc::new(Semaphore::new(limit)),
+            limit,
+        }
+    }
+
+    /// Calls that may run at once.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Run `f` once a slot is free. Errs if `f` panicked.
+    pub async fn run<T, F>(&self, f: F) -> Result<T, JoinError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let permit = {
+            let _queued = Gauge::inc(&METRICS.fs_queue_depth);
+            self.slots
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("fs queue semaphore is never closed")
+        };
+
+        let span = Span::current();
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            span.in_scope(f)
+        })
+        .await
+    }
+}
+
+impl Default for FsQueue {
+    fn default() -> Self {
+        Self::new(DEFAULT_FS_CONCURRENCY)
+    }
+}
@@ -1,21 +1,46 @@
 // src/main.rs
 
-use anyhow::Result;
-use std::collections::HashMap;
+use anyhow::{Context, Result, anyhow, bail};
 use std::fs;
+use std::net::SocketAddr;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
-use tokio::net::{TcpListener, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::signal;
 use tracing::{debug, info, warn};
 
+mod archive;
+mod changeid;
+mod combined;
+mod env;
 mod export;
+mod fhcache;
+mod fsqueue;
+mod metrics;
+#[cfg(feature = "metrics-http")]
+mod metrics_http;
 mod mountd;
 mod nfs2;
+mod peer;
+mod readahead;
 mod rpc;
+mod server;
+mod shutdown;
+mod tcp;
+#[cfg(test)]
+mod testutil;
+mod udp;
+mod upstream;
 mod xdr;
 
-use crate::export::{Export, Exports};
+use crate::archive::ArchiveFs;
+use crate::changeid::ChangeIds;
+use crate::export::{Export, Exports, IdMap};
+use crate::mountd::{MOUNT_PROG, MOUNT_SUPPORTED, MOUNT_VERS_MAX, MOUNT_VERS_MIN};
+use crate::nfs2::{FhKey, NFS_PROC_NAMES, NFS_PROG, NFS_SUPPORTED, NFS_VERS};
+use crate::server::{Instance, Server};
+use crate::upstream::Upstream;
 use serde::Deserialize;
 
 //
@@ -24,7 +49,115 @@ use serde::Deserialize;
 
 #[derive(Debug, Deserialize)]
 struct ExportsFile {
+    /// serve Prometheus metrics on this address (`metrics-http` builds)
+    metrics_addr: Option<SocketAddr>,
+
+    /// sign file handles so clients cannot forge them
+    #[serde(default)]
+    sign_handles: bool,
+
+    /// hex HMAC key; keeps signed handles valid across restarts
+    handle_secret: Option<String>,
+
+    /// calls one TCP connection may pipeline before we stop reading
+    #[serde(default = "default_tcp_max_inflight")]
+    tcp_max_inflight: usize,
+
+    /// calls allowed to touch the filesystem at once; the rest queue
+    #[serde(default = "default_fs_concurrency")]
+    fs_concurrency: usize,
+
+    /// serve NFS and MOUNT on the mountd port only
+    #[serde(default)]
+    single_port: bool,
+
+    /// also serve NFS and MOUNT on this Unix socket (record marked, no
+    /// rpcbind); with [[server]] blocks set it per block instead
+    unix_socket: Option<PathBuf>,
+
+    /// NFSv2 server to relay the procedures we do not implement to; our
+    /// handles are swapped for the ones its MNT and LOOKUP give
+    upstream: Option<SocketAddr>,
+
+    /// the upstream's mountd, if its rpcbind should not be asked
+    upstream_mountd: Option<SocketAddr>,
+
+    /// NFS procedures answered with PROC_UNAVAIL, e.g. ["WRITE"];
+    /// re-read on SIGHUP
+    #[serde(default)]
+    disabled_procs: Vec<String>,
+
+    /// warn about calls taking longer than this; 0 = off; re-read on SIGHUP
+    #[serde(default)]
+    slow_request_threshold_ms: u64,
+
+    /// read-only export served to clients no `clients` list names
+    default_export: Option<String>,
+
     export: Vec<ExportEntry>,
+
+    #[serde(default)]
+    server: Vec<ServerEntry>,
+}
+
+/// A `[[server]]` block: one nfsd + mountd pair with its own ports.
+#[derive(Debug, Deserialize)]
+struct ServerEntry {
+    name: String,
+
+    /// 0 picks an ephemeral port (find it through rpcbind)
+    #[serde(default)]
+    nfs_port: u16,
+
+    #[serde(default)]
+    mountd_port: u16,
+
+    /// rpcbind program numbers, must differ between instances
+    #[serde(default = "default_nfs_program")]
+    nfs_program: u32,
+
+    #[serde(default = "default_mount_program")]
+    mount_program: u32,
+
+    /// paths of the `[[export]]` entries served by this instance
+    exports: Vec<String>,
+
+    /// also serve this instance on a Unix socket
+    unix_socket: Option<PathBuf>,
+}
+
+/// A uid or gid, as a number or as a name looked up at load time.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum IdSpec {
+    Id(u32),
+    Name(String),
+}
+
+impl IdSpec {
+    fn uid(&self, export: &Path, key: &str) -> Result<u32> {
+        match self {
+            IdSpec::Id(id) => Ok(*id),
+            IdSpec::Name(name) => export::user_id(name).ok_or_else(|| {
+                anyhow!(
+                    "export {}: {key}: no user named '{name}' on this system",
+                    export.display()
+                )
+            }),
+        }
+    }
+
+    fn gid(&self, export: &Path, key: &str) -> Result<u32> {
+        match self {
+            IdSpec::Id(id) => Ok(*id),
+            IdSpec::Name(name) => export::group_id(name).ok_or_else(|| {
+                anyhow!(
+                    "export {}: {key}: no group named '{name}' on this system",
+                    export.display()
+                )
+            }),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -34,29 +167,137 @@ struct ExportEntry {
     #[serde(default)]
     read_only: bool,
 
-    #[serde(default = "default_anon_uid")]
-    anon_uid: u32,
+    /// a number or a user name, e.g. "nobody"
+    #[serde(default = "default_anon_id")]
+    anon_uid: IdSpec,
+
+    /// a number or a group name, e.g. "nogroup"
+    #[serde(default = "default_anon_id")]
+    anon_gid: IdSpec,
 
-    #[serde(default = "default_anon_gid")]
-    anon_gid: u32,
+    /// squash target, defaults to the anon identity
+    squash_uid: Option<IdSpec>,
+    squash_gid: Option<IdSpec>,
+
+    #[serde(default = "default_true")]
+    root_squash: bool,
+
+    #[serde(default)]
+    all_squash: bool,
 
     #[serde(default)]
     clients: Vec<String>,
+
+    /// "client_start:host_start:count" ranges
+    #[serde(default)]
+    uid_map: Vec<String>,
+
+    #[serde(default)]
+    gid_map: Vec<String>,
+
+    /// prefetch the next range on sequential READs
+    #[serde(default)]
+    read_ahead: bool,
+
+    /// unix timestamp reported for all file times (reproducible content)
+    fixed_mtime: Option<u32>,
+
+    /// path clients mount instead of the real one, e.g. "/public"
+    #[serde(alias = "export_as")]
+    alias: Option<String>,
+
+    /// hide directory entries with longer names (bytes) from READDIR
+    /// and answer LOOKUP of them with NFSERR_NAMETOOLONG
+    max_name_len: Option<usize>,
+
+    /// clients may open files by name but not list directories
+    #[serde(default)]
+    no_readdir: bool,
+
+    /// extended attribute with RISC OS load/exec or filetype, e.g.
+    /// "user.RISCOS.LoadExec"; reported in the rdev of regular files
+    riscos_xattr: Option<String>,
+
+    /// present symlinks as their target when it is inside the export
+    #[serde(default)]
+    deref_symlinks: bool,
+
+    /// bytes a file may grow to through WRITE or SETATTR
+    max_file_size: Option<u64>,
+
+    /// tar or zip to serve as a read-only tree; `path` is then only
+    /// the name clients mount it by
+    archive: Option<PathBuf>,
+
+    /// GETATTR/READDIR without a handle get this export's root (some
+    /// clients rely on it); off answers them NFSERR_STALE
+    #[serde(default = "default_true")]
+    empty_fh_root_fallback: bool,
+
+    /// number every WRITE/SETATTR per file in the ctime microseconds, so
+    /// clients notice changes made within the same second
+    #[serde(default)]
+    change_ids: bool,
 }
 
-fn default_anon_uid() -> u32 {
-    65534
+const EXPORTS_FILE: &str = "./exports.toml";
+
+// bytes of key material required in `handle_secret`
+const MIN_HANDLE_SECRET: usize = 16;
+
+/// Upper bound for the whole shutdown sequence.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn default_anon_id() -> IdSpec {
+    IdSpec::Id(65534)
+}
+fn default_true() -> bool {
+    true
+}
+fn default_tcp_max_inflight() -> usize {
+    tcp::DEFAULT_MAX_INFLIGHT
+}
+fn default_fs_concurrency() -> usize {
+    fsqueue::DEFAULT_FS_CONCURRENCY
 }
-fn default_anon_gid() -> u32 {
-    65534
+fn default_nfs_program() -> u32 {
+    NFS_PROG
+}
+fn default_mount_program() -> u32 {
+    MOUNT_PROG
+}
+
+struct Config {
+    exports: Exports,
+    instances: Vec<Instance>,
+    metrics_addr: Option<SocketAddr>,
+    fh_key: FhKey,
+    tcp_max_inflight: usize,
+    fs_concurrency: usize,
+    single_port: bool,
+    upstream: Option<Upstream>,
+    disabled_procs: u32,
+    slow_request_threshold: Duration,
 }
 
-fn load_exports(path: &str) -> Result<Exports> {
+fn load_config(path: &str) -> Result<Config> {
     debug!(path, "checking exports file");
 
     if !Path::new(path).exists() {
         warn!(path, "exports file not found");
-        return Ok(Exports::new(Vec::new()));
+        let exports = Exports::new(Vec::new());
+        return Ok(Config {
+            instances: vec![Instance::default_for(exports.clone())],
+            exports,
+            metrics_addr: None,
+            fh_key: FhKey::default(),
+            tcp_max_inflight: tcp::DEFAULT_MAX_INFLIGHT,
+            fs_concurrency: fsqueue::DEFAULT_FS_CONCURRENCY,
+            single_port: false,
+            upstream: None,
+            disabled_procs: 0,
+            slow_request_threshold: Duration::ZERO,
+        });
     }
 
     info!(path, "reading exports file");
@@ -64,33 +305,300 @@ fn load_exports(path: &str) -> Result<Exports> {
     let data = fs::read_to_string(path)?;
     let parsed: ExportsFile = toml::from_str(&data)?;
 
+    if parsed.tcp_max_inflight == 0 {
+        bail!("tcp_max_inflight must be at least 1");
+    }
+    if parsed.fs_concurrency == 0 {
+        bail!("fs_concurrency must be at least 1");
+    }
+    if parsed.upstream_mountd.is_some() && parsed.upstream.is_none() {
+        bail!("upstream_mountd is set but upstream is not");
+    }
+    let upstream = parsed
+        .upstream
+        .map(|a| Upstream::new(a).mountd(parsed.upstream_mountd));
+    let disabled_procs = parse_procs(&parsed.disabled_procs)?;
+    let slow_request_threshold = Duration::from_millis(parsed.slow_request_threshold_ms);
+
+    let fh_key = match &parsed.handle_secret {
+        Some(s) => {
+            let secret = hex::decode(s.trim())?;
+            if secret.len() < MIN_HANDLE_SECRET {
+                bail!("handle_secret must be at least {MIN_HANDLE_SECRET} bytes (hex encoded)");
+            }
+            FhKey::new(secret)
+        }
+        None if parsed.sign_handles => {
+            info!("signing file handles with a random key; they will not survive a restart");
+            FhKey::random()
+        }
+        None => FhKey::default(),
+    };
+
     let exports = parsed
         .export
         .into_iter()
-        .map(|e| Export {
-            path: e.path,
-            read_only: e.read_only,
-            anon_uid: e.anon_uid,
-            anon_gid: e.anon_gid,
-            clients: e.clients,
+        .map(|e| {
+            if let Some(a) = &e.alias
+                && !a.starts_with('/')
+            {
+                bail!(
+                    "export {}: alias '{}' must be absolute",
+                    e.path.display(),
+                    a
+                );
+            }
+            let archive = match &e.archive {
+                Some(a) => Some(Arc::new(ArchiveFs::open(a).with_context(|| {
+                    format!("export {}: archive {}", e.path.display(), a.display())
+                })?)),
+                None => None,
+            };
+            let anon_uid = e.anon_uid.uid(&e.path, "anon_uid")?;
+            let anon_gid = e.anon_gid.gid(&e.path, "anon_gid")?;
+            let squash_uid = match &e.squash_uid {
+                Some(s) => s.uid(&e.path, "squash_uid")?,
+                None => anon_uid,
+            };
+            let squash_gid = match &e.squash_gid {
+                Some(s) => s.gid(&e.path, "squash_gid")?,
+                None => anon_gid,
+            };
+            Ok(Export {
+                uid_map: IdMap::parse(&e.uid_map)?,
+                gid_map: IdMap::parse(&e.gid_map)?,
+                path: e.path,
+                read_only: e.read_only || archive.is_some(),
+                anon_uid,
+                anon_gid,
+                squash_uid,
+                squash_gid,
+                root_squash: e.root_squash,
+                all_squash: e.all_squash,
+                clients: e.clients,
+                read_ahead: e.read_ahead,
+                fixed_mtime: e.fixed_mtime,
+                alias: e.alias,
+                max_name_len: e.max_name_len,
+                no_readdir: e.no_readdir,
+                riscos_xattr: e.riscos_xattr,
+                guest: false,
+                deref_symlinks: e.deref_symlinks,
+                max_file_size: e.max_file_size,
+                empty_fh_root_fallback: e.empty_fh_root_fallback,
+                change_ids: e.change_ids.then(ChangeIds::new),
+                archive,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut exports = exports;
+    if let Some(name) = &parsed.default_export {
+        let Some(guest) = exports
+            .iter_mut()
+            .find(|e| e.path.to_string_lossy() == *name || e.name() == *name)
+        else {
+            bail!("default_export: unknown export {name}");
+        };
+        if !guest.read_only {
+            bail!("default_export: export {name} must be read_only");
+        }
+        guest.guest = true;
+    }
+
+    // Handles tell exports apart by id alone, so ids must be unique. Two
+    // exports of one directory (a bind mount, say) would still hand out
+    // the same files under two sets of rules; refuse that outright.
+    let roots: Vec<_> = exports
+        .iter()
+        .map(|e| {
+            let meta = fs::metadata(&e.path).ok().filter(|_| e.archive.is_none());
+            meta.map(|m| (m.dev(), m.ino()))
         })
         .collect();
+    for (i, a) in exports.iter().enumerate() {
+        if let Some(b) = exports[..i].iter().find(|b| b.id() == a.id()) {
+            bail!(
+                "exports {} and {} map to the same handle id; rename one of them",
+                b.path.display(),
+                a.path.display()
+            );
+        }
+        if let Some(j) = roots[..i]
+            .iter()
+            .position(|r| r.is_some() && *r == roots[i])
+        {
+            bail!(
+                "exports {} and {} are the same directory (bind mount or symlink); export it once",
+                exports[j].path.display(),
+                a.path.display()
+            );
+        }
+    }
+    let exports = Exports::new(exports);
+
+    // No [[server]] blocks: one instance serving everything.
+    if parsed.server.is_empty() {
+        let mut instance = Instance::default_for(exports.clone());
+        instance.unix_socket = parsed.unix_socket;
+        return Ok(Config {
+            instances: vec![instance],
+            exports,
+            metrics_addr: parsed.metrics_addr,
+            fh_key,
+            tcp_max_inflight: parsed.tcp_max_inflight,
+            fs_concurrency: parsed.fs_concurrency,
+            single_port: parsed.single_port,
+            upstream,
+            disabled_procs,
+            slow_request_threshold,
+        });
+    }
+
+    if parsed.unix_socket.is_some() {
+        bail!("unix_socket: with [[server]] blocks, set it in the block that should serve it");
+    }
 
-    Ok(Exports::new(exports))
+    let instances = parsed
+        .server
+        .into_iter()
+        .map(|s| {
+            let subset = s
+                .exports
+                .iter()
+                .map(|p| {
+                    exports
+                        .by_path(p)
+                        .ok_or_else(|| anyhow!("server '{}': unknown export {}", s.name, p))
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            Ok(Instance {
+                name: s.name,
+                exports: Exports::new(subset),
+                nfs_port: s.nfs_port,
+                mountd_port: s.mountd_port,
+                nfs_prog: s.nfs_program,
+                mount_prog: s.mount_program,
+                unix_socket: s.unix_socket,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Config {
+        exports,
+        instances,
+        metrics_addr: parsed.metrics_addr,
+        fh_key,
+        tcp_max_inflight: parsed.tcp_max_inflight,
+        fs_concurrency: parsed.fs_concurrency,
+        single_port: parsed.single_port,
+        upstream,
+        disabled_procs,
+        slow_request_threshold,
+    })
 }
 
-async fn unregister_services() -> anyhow::Result<()> {
-    // mountd: versions 1,2,3 on both transports
-    for v in [1u32, 2u32, 3u32] {
-        rpc::rpcbind_unregister(100005, v, "udp").await?;
-        rpc::rpcbind_unregister(100005, v, "tcp").await?;
+/// NFS procedure names -> bitmask of procedure numbers.
+fn parse_procs(names: &[String]) -> Result<u32> {
+    let mut mask = 0;
+    for name in names {
+        let Some(n) = NFS_PROC_NAMES
+            .iter()
+            .position(|p| p.eq_ignore_ascii_case(name))
+        else {
+            bail!("disabled_procs: unknown NFSv2 procedure '{name}'");
+        };
+        mask |= 1 << n;
     }
+    Ok(mask)
+}
 
-    // nfs v2
-    rpc::rpcbind_unregister(100003, 2, "udp").await?;
-    rpc::rpcbind_unregister(100003, 2, "tcp").await?;
+/// SIGHUP: re-read the exports file and apply what can change at
+/// runtime. Everything else still needs a restart.
+fn reload(server: &Server, path: &str) {
+    info!(path, "reloading configuration");
+    match load_config(path) {
+        Ok(config) => {
+            server.set_disabled_procs(config.disabled_procs);
+            server.set_slow_threshold(config.slow_request_threshold);
+            info!(
+                disabled_procs = format_args!("{:#x}", config.disabled_procs),
+                slow_ms = config.slow_request_threshold.as_millis() as u64,
+                "configuration reloaded (only disabled_procs and slow_request_threshold_ms apply without a restart)"
+            );
+        }
+        Err(e) => warn!(?e, "reload failed, keeping the current configuration"),
+    }
+}
 
-    Ok(())
+/// Look for settings that are legal but probably not what the admin
+/// meant. Purely advisory: nothing returned here blocks startup.
+fn lint_exports(exports: &Exports) -> Vec<String> {
+    let mut out = Vec::new();
+
+    for e in exports.list() {
+        let p = e.path.display();
+
+        if e.archive.is_none() && !e.path.is_dir() {
+            out.push(format!(
+                "export {p} is not an existing directory; MNT requests for it will fail"
+            ));
+        }
+        for c in &e.clients {
+            if !export::valid_client(c) {
+                out.push(format!(
+                    "export {p}: client '{c}' is not an address or CIDR block and never matches"
+                ));
+            }
+        }
+        if e.clients.is_empty() {
+            out.push(format!(
+                "export {p} has no client restriction and is world-accessible; set `clients`"
+            ));
+        }
+        if !e.read_only && !e.all_squash {
+            out.push(format!(
+                "export {p} is writable without all_squash; clients can write as any uid they claim"
+            ));
+        }
+        if !e.root_squash && !e.all_squash {
+            out.push(format!(
+                "export {p} disables root_squash; remote root gets root access to the export"
+            ));
+        }
+        if e.anon_uid == 0 || e.squash_uid == 0 {
+            out.push(format!(
+                "export {p} maps anonymous or squashed users to uid 0, which defeats squashing"
+            ));
+        }
+    }
+
+    out
+}
+
+/// `--version`: what this binary speaks, taken from the dispatchers'
+/// own tables so it cannot drift from what is actually served.
+fn print_version() {
+    let names = |procs: &[(u32, &str)]| procs.iter().map(|(_, n)| *n).collect::<Vec<_>>().join(" ");
+
+    println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    println!(
+        "NFS   program {NFS_PROG} version {NFS_VERS}: {}",
+        names(NFS_SUPPORTED)
+    );
+    println!(
+        "MOUNT program {MOUNT_PROG} versions {MOUNT_VERS_MIN}-{MOUNT_VERS_MAX}: {}",
+        names(MOUNT_SUPPORTED)
+    );
+    println!(
+        "metrics-http: {}",
+        if cfg!(feature = "metrics-http") {
+            "yes"
+        } else {
+            "no"
+        }
+    );
 }
 
 //
@@ -99,6 +607,11 @@ async fn unregister_services() -> anyhow::Result<()> {
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    if std::env::args().any(|a| a == "--version") {
+        print_version();
+        return Ok(());
+    }
+
     tracing_subscriber::fmt()
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
         .init();
@@ -109,82 +622,259 @@ async fn main() -> Result<()> {
     // ---- Load exports ----
     //
 
-    let exports = load_exports("./exports.toml")?;
-
-    if exports.list().is_empty() {
-        warn!("no exports configured");
-    }
-
-    //
-    // ---- Allocate mount table ----
-    //
-    let mount_table: mountd::MountTable = Arc::new(Mutex::new(HashMap::new()));
+    let check_only = std::env::args().any(|a| a == "--check-config");
 
-    let mountd = mountd::Mountd::new(exports.clone(), mount_table.clone());
-    let nfsd = nfs2::Nfs2::new(exports, mount_table.clone());
+    let config = load_config(EXPORTS_FILE)?;
+    let exports = config.exports;
+    let lints = lint_exports(&exports);
 
-    const MOUNTD_PORT: u16 = 20048;
-
-    //
-    // ---- Unregister from rpcbind ----
-    //
-    unregister_services().await?;
-
-    //
-    // ---- Bind UDP sockets ----
-    //
+    let server = config
+        .instances
+        .into_iter()
+        .fold(Server::new(), Server::instance)
+        .metrics_addr(config.metrics_addr)
+        .fh_key(config.fh_key)
+        .tcp_max_inflight(config.tcp_max_inflight)
+        .fs_concurrency(config.fs_concurrency)
+        .single_port(config.single_port)
+        .upstream(config.upstream);
+    server.validate()?;
+    server.set_disabled_procs(config.disabled_procs);
+    server.set_slow_threshold(config.slow_request_threshold);
+
+    if check_only {
+        for l in &lints {
+            println!("warning: {l}");
+        }
+        println!(
+            "{EXPORTS_FILE}: ok, {} export(s), {} warning(s)",
+            exports.list().len(),
+            lints.len()
+        );
+        return Ok(());
+    }
 
-    let mountd_udp = UdpSocket::bind(("0.0.0.0", MOUNTD_PORT)).await?;
-    let mountd_udp_port = mountd_udp.local_addr()?.port();
+    for l in &lints {
+        warn!("{l}");
+    }
 
-    let nfs_udp = UdpSocket::bind("0.0.0.0:0").await?;
-    let nfs_udp_port = nfs_udp.local_addr()?.port();
+    if exports.list().is_empty() {
+        warn!("no exports configured");
+    }
 
     //
-    // ---- Bind TCP sockets ----
+    // ---- Start nfsd/mountd instances ----
     //
 
-    let mountd_tcp = TcpListener::bind(("0.0.0.0", MOUNTD_PORT)).await?;
-    let mountd_tcp_port = mountd_tcp.local_addr()?.port();
-
-    let nfs_tcp = TcpListener::bind("0.0.0.0:0").await?;
-    let nfs_tcp_port = nfs_tcp.local_addr()?.port();
-
-    //
-    // ---- Register with rpcbind ----
-    //
+    server.start().await?;
 
-    rpc::rpcbind_register_udp(100005, 1, mountd_udp_port).await?;
-    rpc::rpcbind_register_udp(100003, 2, nfs_udp_port).await?;
+    info!("nfs2-rs started");
 
-    rpc::rpcbind_register_tcp(100005, 1, mountd_tcp_port).await?;
-    rpc::rpcbind_register_tcp(100003, 2, nfs_tcp_port).await?;
+    let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            res = signal::ctrl_c() => {
+                res?;
+                break;
+            }
+            _ = hangup.recv() => reload(&server, EXPORTS_FILE),
+        }
+    }
+    info!("shutdown requested");
 
-    // mountd versions commonly queried by clients
-    for v in [1u32, 2u32, 3u32] {
-        rpc::rpcbind_register_udp(100005, v, mountd_udp_port).await?;
-        rpc::rpcbind_register_tcp(100005, v, mountd_tcp_port).await?;
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, server.shutdown())
+        .await
+        .is_err()
+    {
+        warn!("shutdown timed out, exiting anyway");
     }
 
-    //
-    // ---- Start servers ----
-    //
+    info!("shutdown complete");
+    Ok(())
+}
 
-    tokio::spawn(mountd.clone().run_udp(mountd_udp));
-    tokio::spawn(mountd.run_tcp(mountd_tcp));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::PeerInfo;
+    use crate::rpc::{RpcAuth, RpcAuthUnix};
+    use crate::testutil::{CLIENT, TempDir};
+
+    fn load(dir: &TempDir, export: &str) -> Result<Config> {
+        let path = dir.path().join("exports.toml");
+        let share = dir.path().display();
+        fs::write(&path, format!("[[export]]\npath = \"{share}\"\n{export}")).unwrap();
+        load_config(path.to_str().unwrap())
+    }
 
-    tokio::spawn(nfsd.clone().run_udp(nfs_udp));
-    tokio::spawn(nfsd.run_tcp(nfs_tcp));
+    #[test]
+    fn anon_ids_may_be_names() {
+        let dir = TempDir::new();
+        let config = load(
+            &dir,
+            "anon_uid = \"nobody\"\nanon_gid = 0\nsquash_uid = \"root\"\n",
+        )
+        .unwrap();
+        let e = &config.exports.list()[0];
+        assert_eq!(
+            e.anon_uid,
+            export::user_id("nobody").expect("a nobody user")
+        );
+        assert_eq!(e.anon_gid, 0);
+        assert_eq!((e.squash_uid, e.squash_gid), (0, 0));
+
+        // the names are what calls actually run as
+        let config = load(&dir, "anon_uid = \"nobody\"\nanon_gid = \"root\"\n").unwrap();
+        let e = &config.exports.list()[0];
+        assert!(e.root_squash);
+        let nobody = export::user_id("nobody").unwrap();
+        let root = RpcAuth::Unix(RpcAuthUnix {
+            machine: "client".into(),
+            uid: 0,
+            gid: 0,
+            aux_gids: vec![],
+        });
+        for auth in [RpcAuth::Null, root] {
+            let cred = e.cred(&PeerInfo::new(CLIENT, &auth));
+            assert_eq!((cred.uid, cred.gid), (nobody, 0));
+        }
+
+        let Err(err) = load(&dir, "anon_uid = \"no-such-user\"\n") else {
+            panic!("unknown user accepted");
+        };
+        assert!(
+            err.to_string().contains("no user named 'no-such-user'"),
+            "{err}"
+        );
+        assert_eq!(export::user_id("root"), Some(0));
+        assert_eq!(export::group_id("root"), Some(0));
+        assert_eq!(export::user_id("nul\0byte"), None);
+    }
 
-    info!("nfs2-rs started");
-    signal::ctrl_c().await?;
-    info!("shutdown requested");
+    #[test]
+    fn lint_flags_each_risky_setting() {
+        const CLEAN: &str = "read_only = true\nclients = [\"192.0.2.0/24\"]\n";
+        let dir = TempDir::new();
+        let lints = |export: &str| lint_exports(&load(&dir, export).unwrap().exports);
+
+        assert_eq!(lints(CLEAN), Vec::<String>::new());
+        for (export, expect) in [
+            (
+                "read_only = true\nclients = [\"192.0.2.0/24\", \"fileserver\"]\n",
+                "client 'fileserver' is not an address",
+            ),
+            ("read_only = true\n", "has no client restriction"),
+            (
+                "clients = [\"192.0.2.0/24\"]\n",
+                "writable without all_squash",
+            ),
+            (
+                "read_only = true\nclients = [\"192.0.2.0/24\"]\nroot_squash = false\n",
+                "disables root_squash",
+            ),
+            (
+                "read_only = true\nclients = [\"192.0.2.0/24\"]\nanon_uid = 0\n",
+                "to uid 0",
+            ),
+            (
+                "read_only = true\nclients = [\"192.0.2.0/24\"]\nsquash_uid = \"root\"\n",
+                "to uid 0",
+            ),
+            (
+                &format!(
+                    "{CLEAN}\n[[export]]\npath = \"{}\"\n{CLEAN}",
+                    dir.path().join("missing").display()
+                ),
+                "is not an existing directory",
+            ),
+        ] {
+            let out = lints(export);
+            assert_eq!(out.len(), 1, "{export}: {out:?}");
+            assert!(out[0].contains(expect), "{export}: {out:?}");
+        }
+    }
 
-    info!("unregistering RPC services");
-    if let Err(e) = unregister_services().await {
-        warn!(?e, "rpcbind unregister failed");
+    #[test]
+    fn exports_of_one_directory_are_refused() {
+        let dir = TempDir::new();
+        let link = dir.path().join("again");
+        std::os::unix::fs::symlink(dir.path(), &link).unwrap();
+
+        let Err(err) = load(
+            &dir,
+            &format!("\n[[export]]\npath = \"{}\"\n", link.display()),
+        ) else {
+            panic!("two exports of one directory accepted");
+        };
+        let msg = err.to_string();
+        assert!(msg.contains("same directory"), "{msg}");
+        assert!(msg.contains(&dir.path().display().to_string()), "{msg}");
+        assert!(msg.contains(&link.display().to_string()), "{msg}");
     }
 
-    info!("shutdown complete");
-    Ok(())
+    #[test]
+    fn reload_disables_procs_without_a_restart() {
+        use crate::nfs2::fh_from_path;
+        use crate::rpc::{PROC_UNAVAIL, Reply, SUCCESS};
+        use crate::testutil::{CLIENT, accepted, call};
+        use crate::xdr::XdrW;
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new();
+        let path = dir.path().join("exports.toml");
+        let file = dir.path().join("f");
+        fs::write(&file, b"").unwrap();
+        // writable by the squashed caller
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o666)).unwrap();
+        let write = |top: &str| {
+            let share = dir.path().display();
+            fs::write(&path, format!("{top}\n[[export]]\npath = \"{share}\"\n")).unwrap();
+        };
+        write("");
+        let config = load_config(path.to_str().unwrap()).unwrap();
+        let instance = config.instances[0].clone();
+        let server = Server::new().instance(instance.clone());
+        server.set_disabled_procs(config.disabled_procs);
+        let (_, nfsd) = instance.services(&server);
+
+        let export = &config.exports.list()[0];
+        let mut getattr = XdrW::new();
+        getattr.put_fixed(&fh_from_path(&FhKey::default(), export, dir.path()));
+        let getattr = getattr.into_vec();
+        let mut write_args = XdrW::new();
+        write_args.put_fixed(&fh_from_path(&FhKey::default(), export, &file));
+        write_args.put_u32(0); // beginoffset
+        write_args.put_u32(0); // offset
+        write_args.put_u32(0); // totalcount
+        write_args.put_opaque(b"data");
+        let write_args = write_args.into_vec();
+
+        // accept_stat, and the NFS status when there is one
+        let nfs = |procid: u32, args: &[u8]| match nfsd
+            .handle_call(&call(NFS_PROG, NFS_VERS, procid, Some(0), args), CLIENT)
+        {
+            Reply::Ready(Some(reply)) => {
+                let (stat, mut r) = accepted(&reply);
+                (stat, r.get_u32().ok())
+            }
+            _ => panic!("no reply on the spot"),
+        };
+        assert_eq!(nfs(8, &write_args), (SUCCESS, Some(0)));
+
+        write("disabled_procs = [\"WRITE\"]");
+        reload(&server, path.to_str().unwrap());
+        assert_eq!(nfs(8, &write_args), (PROC_UNAVAIL, None));
+        // only WRITE is off
+        assert_eq!(nfs(0, &[]), (SUCCESS, None));
+        assert_eq!(nfs(1, &getattr), (SUCCESS, Some(0)));
+
+        // a broken file keeps what is in force
+        write("disabled_procs = [\"NO_SUCH_PROC\"]");
+        reload(&server, path.to_str().unwrap());
+        assert_eq!(nfs(8, &write_args), (PROC_UNAVAIL, None));
+
+        write("");
+        reload(&server, path.to_str().unwrap());
+        assert_eq!(nfs(8, &write_args), (SUCCESS, Some(0)));
+    }
 }
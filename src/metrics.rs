@@ -0,0 +1,102 @@
+// src/metrics.rs
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::mountd::MOUNT_PROC_NAMES;
+use crate::nfs2::NFS_PROC_NAMES;
+
+/// Process-wide counters. Updates are single relaxed atomic ops so they
+/// are always on; rendering them is the optional part (`metrics-http`).
+pub static METRICS: Metrics = Metrics::new();
+
+/// Upper bucket bounds in microseconds (100us .. 1s, then +Inf).
+pub const LATENCY_BUCKETS_US: [u64; 9] = [
+    100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000,
+];
+
+pub struct Histogram {
+    // one slot per bucket plus +Inf, not cumulative
+    pub buckets: [AtomicU64; LATENCY_BUCKETS_US.len() + 1],
+    pub sum_us: AtomicU64,
+    pub count: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS_US.len() + 1],
+            sum_us: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, d: Duration) {
+        let us = d.as_micros().min(u64::MAX as u128) as u64;
+        let slot = LATENCY_BUCKETS_US
+            .iter()
+            .position(|&b| us <= b)
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        self.buckets[slot].fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub struct Metrics {
+    // last slot counts procedure numbers we do not know
+    pub nfs_calls: [AtomicU64; NFS_PROC_NAMES.len() + 1],
+    pub mount_calls: [AtomicU64; MOUNT_PROC_NAMES.len() + 1],
+    pub nfs_latency: Histogram,
+    pub mount_latency: Histogram,
+    pub read_bytes: AtomicU64,
+    pub write_bytes: AtomicU64,
+    // gauges
+    pub nfs_connections: AtomicU64,
+    pub mount_connections: AtomicU64,
+    pub readahead_chunks: AtomicU64,
+    pub fs_queue_depth: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            nfs_calls: [const { AtomicU64::new(0) }; NFS_PROC_NAMES.len() + 1],
+            mount_calls: [const { AtomicU64::new(0) }; MOUNT_PROC_NAMES.len() + 1],
+            nfs_latency: Histogram::new(),
+            mount_latency: Histogram::new(),
+            read_bytes: AtomicU64::new(0),
+            write_bytes: AtomicU64::new(0),
+            nfs_connections: AtomicU64::new(0),
+            mount_connections: AtomicU64::new(0),
+            readahead_chunks: AtomicU64::new(0),
+            fs_queue_depth: AtomicU64::new(0),
+        }
+    }
+
+    pub fn nfs_call(&self, procid: u32) {
+        let slot = (procid as usize).min(NFS_PROC_NAMES.len());
+        self.nfs_calls[slot].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn mount_call(&self, procid: u32) {
+        let slot = (procid as usize).min(MOUNT_PROC_NAMES.len());
+        self.mount_calls[slot].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Keeps a connection gauge raised while alive.
+pub struct Gauge(&'static AtomicU64);
+
+impl Gauge {
+    pub fn inc(g: &'static AtomicU64) -> Self {
+        g.fetch_add(1, Ordering::Relaxed);
+        Self(g)
+    }
+}
+
+impl Drop for Gauge {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
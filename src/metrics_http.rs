@@ -0,0 +1,253 @@
+// src/metrics_http.rs
+
+use crate::metrics::{Histogram, LATENCY_BUCKETS_US, METRICS};
+use crate::mountd::MOUNT_PROC_NAMES;
+use crate::nfs2::NFS_PROC_NAMES;
+use crate::shutdown::Shutdown;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+// A scrape request is one short GET; anything bigger or slower is dropped.
+const MAX_REQUEST: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn get(v: &AtomicU64) -> u64 {
+    v.load(Ordering::Relaxed)
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn calls(out: &mut String, name: &str, names: &[&str], counts: &[AtomicU64]) {
+    for (proc, n) in names.iter().chain(["unknown"].iter()).zip(counts) {
+        let _ = writeln!(out, "{name}{{proc=\"{proc}\"}} {}", get(n));
+    }
+}
+
+fn histogram(out: &mut String, name: &str, service: &str, h: &Histogram) {
+    let mut cumulative = 0;
+    for (i, n) in h.buckets.iter().enumerate() {
+        cumulative += get(n);
+        let le = match LATENCY_BUCKETS_US.get(i) {
+            Some(us) => format!("{}", *us as f64 / 1e6),
+            None => "+Inf".into(),
+        };
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{service=\"{service}\",le=\"{le}\"}} {cumulative}"
+        );
+    }
+    let _ = writeln!(
+        out,
+        "{name}_sum{{service=\"{service}\"}} {}",
+        get(&h.sum_us) as f64 / 1e6
+    );
+    let _ = writeln!(
+        out,
+        "{name}_count{{service=\"{service}\"}} {}",
+        get(&h.count)
+    );
+}
+
+/// Prometheus text exposition (format 0.0.4) of all metrics.
+pub fn render() -> String {
+    let m = &METRICS;
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "nfs2_calls_total",
+        "counter",
+        "NFSv2 calls by procedure.",
+    );
+    calls(&mut out, "nfs2_calls_total", &NFS_PROC_NAMES, &m.nfs_calls);
+
+    header(
+        &mut out,
+        "mountd_calls_total",
+        "counter",
+        "MOUNT calls by procedure.",
+    );
+    calls(
+        &mut out,
+        "mountd_calls_total",
+        &MOUNT_PROC_NAMES,
+        &m.mount_calls,
+    );
+
+    header(
+        &mut out,
+        "nfs2_read_bytes_total",
+        "counter",
+        "Bytes returned by READ.",
+    );
+    let _ = writeln!(out, "nfs2_read_bytes_total {}", get(&m.read_bytes));
+
+    header(
+        &mut out,
+        "nfs2_write_bytes_total",
+        "counter",
+        "Bytes stored by WRITE.",
+    );
+    let _ = writeln!(out, "nfs2_write_bytes_total {}", get(&m.write_bytes));
+
+    header(
+        &mut out,
+        "rpc_tcp_connections",
+        "gauge",
+        "Open TCP connections.",
+    );
+    let _ = writeln!(
+        out,
+        "rpc_tcp_connections{{service=\"nfs\"}} {}",
+        get(&m.nfs_connections)
+    );
+    let _ = writeln!(
+        out,
+        "rpc_tcp_connections{{service=\"mount\"}} {}",
+        get(&m.mount_connections)
+    );
+
+    header(
+        &mut out,
+        "nfs2_readahead_chunks",
+        "gauge",
+        "Prefetched chunks held by the read-ahead cache.",
+    );
+    let _ = writeln!(out, "nfs2_readahead_chunks {}", get(&m.readahead_chunks));
+
+    header(
+        &mut out,
+        "rpc_fs_queue_depth",
+        "gauge",
+        "Calls waiting for a filesystem slot (fs_concurrency).",
+    );
+    let _ = writeln!(out, "rpc_fs_queue_depth {}", get(&m.fs_queue_depth));
+
+    header(
+        &mut out,
+        "rpc_request_duration_seconds",
+        "histogram",
+        "Time spent handling one RPC call.",
+    );
+    histogram(
+        &mut out,
+        "rpc_request_duration_seconds",
+        "nfs",
+        &m.nfs_latency,
+    );
+    histogram(
+        &mut out,
+        "rpc_request_duration_seconds",
+        "mount",
+        &m.mount_latency,
+    );
+
+    out
+}
+
+async fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut req = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !req.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || req.len() + n > MAX_REQUEST {
+            return Ok(());
+        }
+        req.extend_from_slice(&buf[..n]);
+    }
+
+    let line = String::from_utf8_lossy(&req);
+    let mut parts = line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render()),
+        (Some("GET"), _) => ("404 Not Found", "not found\n".into()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".into()),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Minimal HTTP/1.1 responder serving `GET /metrics`.
+pub async fn run(listener: TcpListener, stop: Shutdown) {
+    let local = listener.local_addr().ok();
+    info!(?local, "metrics listening (HTTP)");
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            _ = stop.stopped() => break,
+            res = listener.accept() => match res {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!(?e, "metrics accept failed");
+                    continue;
+                }
+            },
+        };
+
+        tokio::spawn(async move {
+            match tokio::time::timeout(REQUEST_TIMEOUT, respond(stream)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!(%peer, ?e, "metrics request failed"),
+                Err(_) => debug!(%peer, "metrics request timed out"),
+            }
+        });
+    }
+
+    info!(?local, "metrics stopped (HTTP)");
+}
+
+#[cfg(all(test, feature = "metrics-http"))]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    async fn fetch(addr: SocketAddr, path: &str) -> String {
+        let mut s = TcpStream::connect(addr).await.unwrap();
+        s.write_all(format!("GET {path} HTTP/1.1\r\nHost: x\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut out = String::new();
+        s.read_to_string(&mut out).await.unwrap();
+        out
+    }
+
+    #[tokio::test]
+    async fn serves_metrics_and_nothing_else() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stop = Shutdown::new();
+        let srv = tokio::spawn(run(listener, stop.clone()));
+
+        let reply = fetch(addr, "/metrics").await;
+        assert!(reply.starts_with("HTTP/1.1 200 OK\r\n"), "{reply}");
+        for line in [
+            "nfs2_calls_total{proc=\"null\"} ",
+            "nfs2_calls_total{proc=\"read\"} ",
+            "rpc_tcp_connections{service=\"nfs\"} ",
+            "rpc_request_duration_seconds_bucket{service=\"nfs\",le=\"+Inf\"} ",
+            "rpc_request_duration_seconds_sum{service=\"nfs\"} ",
+            "rpc_request_duration_seconds_count{service=\"mount\"} ",
+        ] {
+            assert!(reply.lines().any(|l| l.starts_with(line)), "missing {line}");
+        }
+
+        let reply = fetch(addr, "/").await;
+        assert!(reply.starts_with("HTTP/1.1 404 Not Found\r\n"), "{reply}");
+
+        stop.trigger();
+        srv.await.unwrap();
+    }
+}
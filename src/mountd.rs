@@ -2,70 +2,172 @@
 
 use crate::{
     export::Exports,
-    rpc::{decode_call, rpc_accept_reply},
-    xdr::{XdrR, XdrW},
+    fsqueue::FsQueue,
+    metrics::{Gauge, METRICS},
+    nfs2::{FhKey, root_fh},
+    peer::PeerInfo,
+    rpc::{
+        GARBAGE_ARGS, PROC_UNAVAIL, PROG_UNAVAIL, RpcCall, SUCCESS, decode_call, next_conn_id,
+        rpc_accept_header, rpc_accept_reply, rpc_prog_mismatch_reply,
+    },
+    server::SlowThreshold,
+    shutdown::Shutdown,
+    tcp, udp,
+    xdr::{XdrError, XdrR},
 };
-use std::path::PathBuf;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
 use tokio::net::{TcpListener, UdpSocket};
-use tracing::{info, warn};
+use tracing::{Instrument, Span, debug, field, info, info_span, warn, warn_span};
 
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
-pub type MountTable = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+/// Active mounts, keyed by (client address, mounted path).
+pub type MountTable = Arc<Mutex<HashMap<(IpAddr, String), Vec<u8>>>>;
 
-// Mount v1
-const MOUNT_PROG: u32 = 100005;
-const MOUNT_VERS: u32 = 1;
+pub const MOUNT_PROG: u32 = 100005;
+// v1 semantics, answered for v2 and v3 callers too
+pub const MOUNT_VERS_MIN: u32 = 1;
+pub const MOUNT_VERS_MAX: u32 = 3;
+
+/// MOUNT procedure names, indexed by procedure number.
+pub const MOUNT_PROC_NAMES: [&str; 7] = [
+    "null",
+    "mnt",
+    "dump",
+    "umnt",
+    "umntall",
+    "export",
+    "exportall",
+];
+
+/// Procedures `handle_call` implements; anything else gets PROC_UNAVAIL.
+pub const MOUNT_SUPPORTED: &[(u32, &str)] = &[(0, "NULL"), (1, "MNT"), (3, "UMNT"), (5, "EXPORT")];
+
+// Longest MNT path we are willing to decode (Linux PATH_MAX)
+const PATH_MAX: usize = 4096;
+
+const MNT3ERR_NAMETOOLONG: u32 = 63;
 
 #[derive(Clone)]
 pub struct Mountd {
     exports: Exports,
     mounts: MountTable,
+    fh_key: FhKey,
+    // program number registered with rpcbind (MOUNT_PROG unless overridden)
+    prog: u32,
+    slow: SlowThreshold,
 }
 
 impl Mountd {
-    pub fn new(exports: Exports, mounts: MountTable) -> Self {
-        Self { exports, mounts }
+    pub fn new(exports: Exports, mounts: MountTable, fh_key: FhKey, prog: u32) -> Self {
+        Self {
+            exports,
+            mounts,
+            fh_key,
+            prog,
+            slow: SlowThreshold::default(),
+        }
+    }
+
+    /// Warn about calls slower than this.
+    pub fn slow_threshold(mut self, slow: SlowThreshold) -> Self {
+        self.slow = slow;
+        self
     }
 
     /// Core mountd RPC handler (UDP + TCP)
-    pub fn handle_call(&self, buf: &[u8]) -> Option<Vec<u8>> {
-        let (call, ofs) = decode_call(buf)?;
+    pub fn handle_call(&self, buf: &[u8], addr: SocketAddr) -> Option<Vec<u8>> {
+        let (call, ofs) = match decode_call(buf) {
+            Ok(v) => v,
+            Err(e) => return e.reply(),
+        };
+        let peer = PeerInfo::new(addr, &call.auth);
+        // WARN level so xid/path still decorate the slow-request warning
+        // when only warnings are logged
+        let _span = warn_span!("rpc", xid = call.xid, path = field::Empty).entered();
+
+        if call.prog != MOUNT_PROG && call.prog != self.prog {
+            // answer instead of dropping so a misdirected client fails fast
+            debug!(%peer, prog = call.prog, "mountd: call for unknown program");
+            return Some(rpc_accept_reply(call.xid, PROG_UNAVAIL, &[]));
+        }
+        if !(MOUNT_VERS_MIN..=MOUNT_VERS_MAX).contains(&call.vers) {
+            debug!(%peer, vers = call.vers, "mountd: unsupported version");
+            return Some(rpc_prog_mismatch_reply(
+                call.xid,
+                MOUNT_VERS_MIN,
+                MOUNT_VERS_MAX,
+            ));
+        }
+
+        METRICS.mount_call(call.procid);
 
-        if call.prog != MOUNT_PROG {
-            return None;
+        if !MOUNT_SUPPORTED.iter().any(|&(p, _)| p == call.procid) {
+            debug!(procid = call.procid, "mountd: unsupported proc");
+            return Some(rpc_accept_reply(call.xid, PROC_UNAVAIL, &[]));
         }
-        // accept v1..v3
-        if call.vers < MOUNT_VERS || call.vers > 3 {
-            return None;
+
+        let t0 = Instant::now();
+        let reply = self.dispatch(&call, &buf[ofs..], &peer);
+        let elapsed = t0.elapsed();
+        METRICS.mount_latency.observe(elapsed);
+        if self.slow.exceeded_by(elapsed) {
+            warn!(
+                %peer,
+                proc = MOUNT_PROC_NAMES[call.procid as usize],
+                ms = elapsed.as_millis() as u64,
+                "mountd: slow request"
+            );
         }
 
-        let mut r = XdrR::new(&buf[ofs..]);
+        Some(reply)
+    }
+
+    fn dispatch(&self, call: &RpcCall, args: &[u8], peer: &PeerInfo) -> Vec<u8> {
+        let mut r = XdrR::new(args);
 
-        let reply = match call.procid {
+        match call.procid {
             0 => {
                 // NULL
                 info!("mountd: NULL");
-                let w = XdrW::new();
-                rpc_accept_reply(call.xid, 0, &w.buf)
+                rpc_accept_header(call.xid, SUCCESS).into_vec()
             }
 
             1 => {
                 // MNT
-                let path = r.get_string().unwrap_or_default();
+                let path = match r.get_string_max(PATH_MAX) {
+                    Ok(p) => p,
+                    Err(XdrError::StrTooLong) => {
+                        warn!("mountd: MNT path longer than PATH_MAX");
+                        let mut w = rpc_accept_header(call.xid, SUCCESS);
+                        w.put_u32(MNT3ERR_NAMETOOLONG);
+                        return w.into_vec();
+                    }
+                    Err(e) => {
+                        warn!(?e, "mountd: malformed MNT arguments");
+                        return rpc_accept_reply(call.xid, GARBAGE_ARGS, &[]);
+                    }
+                };
                 info!(path = %path, "mountd: MNT");
+                Span::current().record("path", path.as_str());
 
-                let allowed = self.exports.list().iter().any(|e| e.path == path); // PathBuf::from(&path));
+                let export = self.exports.for_mount(&path, peer.ip());
+                if let Some(e) = &export
+                    && e.name() != path
+                {
+                    info!(%peer, path = %path, guest = %e.name(), "mountd: serving guest export");
+                }
 
-                let mut w = XdrW::new();
+                let mut w = rpc_accept_header(call.xid, SUCCESS);
 
-                if allowed {
+                if let Some(export) = export {
                     w.put_u32(0); // OK
 
-                    let p = PathBuf::from(&path);
-                    let fh = crate::nfs2::fh_from_path(&p);
+                    // handles always name the real host path, never the alias
+                    let p = &export.path;
+                    let fh = root_fh(&self.fh_key, &export);
 
                     info!(
                         "mountd: issuing FH for path={} len={} hex={}",
@@ -74,41 +176,59 @@ impl Mountd {
                         hex::encode(&fh)
                     );
 
-                    let fh = crate::nfs2::fh_from_path(&p);
-
-                    self.mounts.lock().unwrap().insert(path.clone(), fh.clone());
+                    self.mounts
+                        .lock()
+                        .unwrap()
+                        .insert((peer.ip(), path.clone()), fh.clone());
 
-                    w.put_opaque(&fh);
-                    // auth flavors
-                    w.put_u32(1); // count
-                    w.put_u32(1); // AUTH_UNIX
+                    if call.vers < 3 {
+                        // fhstatus: fixed 32 byte fhandle
+                        w.put_fixed(&fh);
+                    } else {
+                        // mountres3: fhandle3 opaque and auth flavors
+                        w.put_opaque(&fh);
+                        w.put_u32(1); // count
+                        w.put_u32(1); // AUTH_UNIX
+                    }
                 } else {
                     w.put_u32(13); // NFSERR_ACCES
                 }
 
-                rpc_accept_reply(call.xid, 0, &w.buf)
+                w.into_vec()
             }
 
             3 => {
                 // UMNT
-                let _ = r.get_string();
-                info!("mountd: UMNT");
-                let w = XdrW::new();
-                rpc_accept_reply(call.xid, 0, &w.buf)
+                let Ok(path) = r.get_string_max(PATH_MAX) else {
+                    warn!("mountd: malformed UMNT arguments");
+                    return rpc_accept_reply(call.xid, GARBAGE_ARGS, &[]);
+                };
+
+                // Only this client's entry goes; UMNT of something the
+                // client never mounted still succeeds (there is no error reply).
+                let removed = self
+                    .mounts
+                    .lock()
+                    .unwrap()
+                    .remove(&(peer.ip(), path.clone()))
+                    .is_some();
+                info!(path = %path, removed, "mountd: UMNT");
+
+                rpc_accept_header(call.xid, SUCCESS).into_vec()
             }
 
             5 => {
                 // EXPORT
                 info!("mountd: EXPORT");
 
-                let mut w = XdrW::new();
+                let mut w = rpc_accept_header(call.xid, SUCCESS);
 
                 let exports = self.exports.list();
 
                 // export list (linked list)
                 for ex in exports {
                     w.put_u32(1); // exportnode present
-                    w.put_string(&ex.path.to_string_lossy());
+                    w.put_string(&ex.name());
 
                     // groups list (empty)
                     w.put_u32(0);
@@ -116,89 +236,290 @@ impl Mountd {
 
                 w.put_u32(0); // end of export list
 
-                rpc_accept_reply(call.xid, 0, &w.buf)
+                w.into_vec()
             }
 
             p => {
-                warn!(procid = p, "mountd: unsupported proc");
-                let w = XdrW::new();
-                rpc_accept_reply(call.xid, 0, &w.buf)
+                warn!(
+                    procid = p,
+                    "mountd: proc listed in MOUNT_SUPPORTED but not dispatched"
+                );
+                rpc_accept_reply(call.xid, PROC_UNAVAIL, &[])
             }
-        };
-
-        Some(reply)
+        }
     }
 
     /// UDP server
-    pub async fn run_udp(self, sock: UdpSocket) {
+    pub async fn run_udp(self, sock: UdpSocket, stop: Shutdown, queue: FsQueue) {
         let local = sock.local_addr().ok();
         info!(?local, "mountd listening (UDP)");
 
-        let mut buf = vec![0u8; 8192];
+        udp::serve(sock, stop, queue, move |buf, peer| {
+            info!(%peer, size = buf.len(), "mountd UDP request");
+            self.handle_call(buf, peer)
+        })
+        .await;
 
-        loop {
-            let Ok((n, peer)) = sock.recv_from(&mut buf).await else {
-                continue;
-            };
-
-            info!(%peer, size = n, "mountd UDP request");
-
-            if let Some(reply) = self.handle_call(&buf[..n])
-                && let Err(e) = sock.send_to(&reply, peer).await
-            {
-                warn!(?e, %peer, "mountd UDP send failed");
-            }
-        }
+        info!(?local, "mountd stopped (UDP)");
     }
 
     /// TCP server (record-marked RPC)
-    pub async fn run_tcp(self, listener: TcpListener) {
+    pub async fn run_tcp(
+        self,
+        listener: TcpListener,
+        stop: Shutdown,
+        queue: FsQueue,
+        max_inflight: usize,
+    ) {
         let local = listener.local_addr().ok();
         info!(?local, "mountd listening (TCP)");
 
         loop {
-            let (mut stream, peer) = match listener.accept().await {
-                Ok(v) => v,
-                Err(e) => {
-                    warn!(?e, "mountd TCP accept failed");
-                    continue;
-                }
+            let (stream, peer) = tokio::select! {
+                _ = stop.stopped() => break,
+                res = listener.accept() => match res {
+                    Ok(v) => v,
+                    Err(e) => {
+                        warn!(?e, "mountd TCP accept failed");
+                        continue;
+                    }
+                },
             };
 
             let this = self.clone();
+            let stop = stop.clone();
+            let queue = queue.clone();
+            let conn_id = next_conn_id();
 
-            tokio::spawn(async move {
-                info!(%peer, "mountd TCP connected");
+            tokio::spawn(
+                async move {
+                    info!(%peer, "mountd TCP connected");
+                    let _conn = Gauge::inc(&METRICS.mount_connections);
 
-                loop {
-                    let mut hdr = [0u8; 4];
-                    if stream.read_exact(&mut hdr).await.is_err() {
-                        break;
-                    }
+                    tcp::serve(stream, stop, queue, max_inflight, move |buf| {
+                        this.handle_call(buf, peer)
+                    })
+                    .await;
 
-                    let marker = u32::from_be_bytes(hdr);
-                    let len = (marker & 0x7fff_ffff) as usize;
+                    info!(%peer, "mountd TCP disconnected");
+                }
+                .instrument(info_span!("tcp", conn_id, %peer)),
+            );
+        }
 
-                    let mut buf = vec![0u8; len];
-                    if stream.read_exact(&mut buf).await.is_err() {
-                        break;
-                    }
+        info!(?local, "mountd stopped (TCP)");
+    }
+}
 
-                    if let Some(reply) = this.handle_call(&buf) {
-                        let mut out = Vec::with_capacity(4 + reply.len());
-                        out.extend_from_slice(&(0x8000_0000u32 | reply.len() as u32).to_be_bytes());
-                        out.extend_from_slice(&reply);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::Export;
+    use crate::nfs2::NFS_PROG;
+    use crate::testutil::{CLIENT, TempDir, accepted, call, export};
+    use crate::xdr::XdrW;
+    use std::fs;
+
+    fn mountd(exports: Vec<Export>) -> Mountd {
+        Mountd::new(
+            Exports::new(exports),
+            MountTable::default(),
+            FhKey::default(),
+            MOUNT_PROG,
+        )
+    }
 
-                        if stream.write_all(&out).await.is_err() {
-                            break;
-                        }
-                    } else {
-                        break;
-                    }
-                }
+    fn mnt(m: &Mountd, path: &str) -> Vec<u8> {
+        let mut args = XdrW::new();
+        args.put_string(path);
+        m.handle_call(&call(MOUNT_PROG, 1, 1, Some(0), &args.buf), CLIENT)
+            .unwrap()
+    }
 
-                info!(%peer, "mountd TCP disconnected");
-            });
+    #[test]
+    fn unknown_program_is_prog_unavail() {
+        let m = mountd(Vec::new());
+        for prog in [100099, NFS_PROG] {
+            let reply = m.handle_call(&call(prog, 1, 0, None, &[]), CLIENT).unwrap();
+            assert_eq!(accepted(&reply).0, PROG_UNAVAIL, "{prog}");
         }
     }
+
+    #[test]
+    fn mnt_path_over_path_max_is_nametoolong() {
+        let dir = TempDir::new();
+        let m = mountd(vec![export(dir.path())]);
+
+        let long = format!("/{}", "a".repeat(PATH_MAX));
+        let reply = mnt(&m, &long);
+        let (stat, mut r) = accepted(&reply);
+        assert_eq!(stat, SUCCESS);
+        assert_eq!(r.get_u32().unwrap(), MNT3ERR_NAMETOOLONG);
+        assert!(m.mounts.lock().unwrap().is_empty());
+
+        // the export itself still mounts
+        let reply = mnt(&m, dir.path().to_str().unwrap());
+        let (_, mut r) = accepted(&reply);
+        assert_eq!(r.get_u32().unwrap(), 0);
+    }
+
+    #[test]
+    fn mnt_path_length_is_checked_before_allocating() {
+        use crate::testutil::allocations;
+
+        let m = mountd(Vec::new());
+        let status = |args: &[u8]| {
+            let reply = m
+                .handle_call(&call(MOUNT_PROG, 1, 1, Some(0), args), CLIENT)
+                .unwrap();
+            let (stat, mut r) = accepted(&reply);
+            (stat, r.get_u32().ok())
+        };
+
+        // claims ~4 GiB of path and sends none of it: malformed
+        let missing = 0xffff_fff0u32.to_be_bytes();
+        let before = allocations();
+        let res = XdrR::new(&missing).get_string_max(PATH_MAX);
+        assert_eq!(allocations() - before, 0);
+        assert!(matches!(res, Err(XdrError::Underrun)));
+        assert_eq!(status(&missing), (GARBAGE_ARGS, None));
+
+        // all there, just longer than any path
+        let mut long = ((PATH_MAX + 1) as u32).to_be_bytes().to_vec();
+        long.resize(4 + (PATH_MAX + 4), b'a');
+        let before = allocations();
+        let res = XdrR::new(&long).get_string_max(PATH_MAX);
+        assert_eq!(allocations() - before, 0);
+        assert!(matches!(res, Err(XdrError::StrTooLong)));
+        assert_eq!(status(&long), (SUCCESS, Some(MNT3ERR_NAMETOOLONG)));
+    }
+
+    #[test]
+    fn umnt_only_drops_the_callers_mount() {
+        let dir = TempDir::new();
+        let m = mountd(vec![export(dir.path())]);
+        let path = dir.path().to_str().unwrap();
+        let b: SocketAddr = "192.0.2.2:800".parse().unwrap();
+
+        mnt(&m, path);
+        let mut args = XdrW::new();
+        args.put_string(path);
+        let reply = m
+            .handle_call(&call(MOUNT_PROG, 1, 3, Some(0), &args.buf), b)
+            .unwrap();
+        assert_eq!(accepted(&reply).0, SUCCESS);
+
+        let mounts = m.mounts.lock().unwrap();
+        assert!(mounts.contains_key(&(CLIENT.ip(), path.to_string())));
+        assert_eq!(mounts.len(), 1);
+    }
+
+    #[test]
+    fn alias_mounts_and_serves_the_real_directory() {
+        use crate::env::Env;
+        use crate::nfs2::{DisabledProcs, NFS_VERS, Nfs2};
+        use crate::rpc::Reply;
+
+        let dir = TempDir::new();
+        fs::write(dir.path().join("hello"), b"hello").unwrap();
+        let e = Export {
+            alias: Some("/public".into()),
+            ..export(dir.path())
+        };
+        let m = mountd(vec![e.clone()]);
+        let nfsd = Nfs2::new(
+            Exports::new(vec![e]),
+            m.mounts.clone(),
+            FhKey::default(),
+            DisabledProcs::default(),
+            Env::default(),
+            NFS_PROG,
+            FsQueue::default(),
+        );
+        let nfs = |procid, args: &[u8]| match nfsd
+            .handle_call(&call(NFS_PROG, NFS_VERS, procid, Some(0), args), CLIENT)
+        {
+            Reply::Ready(Some(reply)) => reply,
+            _ => panic!("no reply on the spot"),
+        };
+
+        let reply = mnt(&m, "/public");
+        let (_, mut r) = accepted(&reply);
+        assert_eq!(r.get_u32().unwrap(), 0);
+        let root = r.get_fixed(32).unwrap();
+        // the host path is not a second name for it
+        let reply = mnt(&m, dir.path().to_str().unwrap());
+        assert_eq!(accepted(&reply).1.get_u32().unwrap(), 13);
+
+        // GETATTR on the root, LOOKUP and READ below it
+        let reply = nfs(1, &root);
+        let (_, mut r) = accepted(&reply);
+        assert_eq!((r.get_u32().unwrap(), r.get_u32().unwrap()), (0, 2)); // NFS_OK, NFDIR
+        let mut args = XdrW::new();
+        args.put_fixed(&root);
+        args.put_string("hello");
+        let reply = nfs(4, &args.buf);
+        let (_, mut r) = accepted(&reply);
+        assert_eq!(r.get_u32().unwrap(), 0);
+        let file = r.get_fixed(32).unwrap();
+        let mut args = XdrW::new();
+        args.put_fixed(&file);
+        for v in [0, 5, 0] {
+            args.put_u32(v);
+        }
+        let reply = nfs(6, &args.buf);
+        let (_, mut r) = accepted(&reply);
+        assert_eq!(r.get_u32().unwrap(), 0);
+        for _ in 0..17 {
+            r.get_u32().unwrap();
+        }
+        assert_eq!(r.get_opaque().unwrap(), b"hello");
+
+        // EXPORT lists the alias only
+        let reply = m
+            .handle_call(&call(MOUNT_PROG, 1, 5, Some(0), &[]), CLIENT)
+            .unwrap();
+        let (_, mut r) = accepted(&reply);
+        assert_eq!(r.get_u32().unwrap(), 1);
+        assert_eq!(r.get_string().unwrap(), "/public");
+        assert_eq!(r.get_u32().unwrap(), 0); // no groups
+        assert_eq!(r.get_u32().unwrap(), 0); // end of list
+    }
+
+    #[test]
+    fn unlisted_client_gets_the_guest_export_or_nothing() {
+        let (private, public) = (TempDir::new(), TempDir::new());
+        let private = Export {
+            clients: vec!["198.51.100.0/24".into()],
+            ..export(private.path())
+        };
+        let guest = Export {
+            read_only: true,
+            guest: true,
+            ..export(public.path())
+        };
+        let insider: SocketAddr = "198.51.100.5:800".parse().unwrap();
+        let path = private.path.to_str().unwrap();
+        let mnt_from = |m: &Mountd, addr| {
+            let mut args = XdrW::new();
+            args.put_string(path);
+            let reply = m
+                .handle_call(&call(MOUNT_PROG, 1, 1, Some(0), &args.buf), addr)
+                .unwrap();
+            let (_, mut r) = accepted(&reply);
+            match r.get_u32().unwrap() {
+                0 => Ok(r.get_fixed(32).unwrap()),
+                err => Err(err),
+            }
+        };
+
+        let m = mountd(vec![private.clone(), guest.clone()]);
+        let key = FhKey::default();
+        assert_eq!(mnt_from(&m, insider), Ok(root_fh(&key, &private)));
+        assert_eq!(mnt_from(&m, CLIENT), Ok(root_fh(&key, &guest)));
+
+        let m = mountd(vec![private.clone()]);
+        assert_eq!(mnt_from(&m, CLIENT), Err(13)); // MNT3ERR_ACCES
+        assert!(m.mounts.lock().unwrap().is_empty());
+    }
 }
@@ -1,73 +1,247 @@
 // src/nfs2.rs
 
-use crate::export::Exports;
+use crate::archive::{ArchiveFs, Kind, Node, ROOT_INO};
+use crate::env::Env;
+use crate::export::{Cred, Export, Exports};
+use crate::fhcache::HandleCache;
+use crate::fsqueue::FsQueue;
+use crate::metrics::{Gauge, METRICS};
 use crate::mountd::MountTable;
-use crate::rpc::{decode_call, rpc_accept_reply, rpc_prog_mismatch_reply};
-use crate::xdr::{XdrR, XdrW};
+use crate::peer::PeerInfo;
+use crate::readahead::ReadAhead;
+use crate::rpc::{
+    AUTH_NULL, AUTH_UNIX, GARBAGE_ARGS, PROC_UNAVAIL, PROG_UNAVAIL, Reply, RpcAuthUnix, RpcCall,
+    SUCCESS, SYSTEM_ERR, decode_call, encode_auth_unix, next_conn_id, rpc_accept_header,
+    rpc_accept_reply, rpc_prog_mismatch_reply,
+};
+use crate::server::SlowThreshold;
+use crate::shutdown::Shutdown;
+use crate::tcp;
+use crate::udp;
+use crate::upstream::{self, HandleError, Upstream};
+use crate::xdr::{XdrError, XdrR, XdrW};
 #[allow(clippy::single_component_path_imports)]
 use hex;
 //use tracing_subscriber::field::debug;
 
 use std::{
+    ffi::CString,
     fs,
+    net::SocketAddr,
     //io::{Read, Seek},
-    os::unix::fs::MetadataExt,
+    os::unix::fs::{FileExt, FileTypeExt, MetadataExt},
     path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, UdpSocket};
-use tracing::{debug, info, warn};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 
-const NFS_PROG: u32 = 100003;
-const NFS_VERS: u32 = 2;
+use tokio::net::{TcpListener, UdpSocket};
+use tracing::{Instrument, Span, debug, field, info, info_span, warn, warn_span};
+
+pub const NFS_PROG: u32 = 100003;
+pub const NFS_VERS: u32 = 2;
+
+/// NFSv2 procedure names, indexed by procedure number.
+pub const NFS_PROC_NAMES: [&str; 18] = [
+    "null",
+    "getattr",
+    "setattr",
+    "root",
+    "lookup",
+    "readlink",
+    "read",
+    "writecache",
+    "write",
+    "create",
+    "remove",
+    "rename",
+    "link",
+    "symlink",
+    "mkdir",
+    "rmdir",
+    "readdir",
+    "statfs",
+];
+
+/// Procedures `handle_call` implements; anything else gets PROC_UNAVAIL.
+pub const NFS_SUPPORTED: &[(u32, &str)] = &[
+    (0, "NULL"),
+    (1, "GETATTR"),
+    (2, "SETATTR"),
+    (4, "LOOKUP"),
+    (5, "READLINK"),
+    (6, "READ"),
+    (8, "WRITE"),
+    (16, "READDIR"),
+];
+
+// Largest READ payload allowed by NFSv2
+const NFS_MAXDATA: usize = 8192;
+
+// Longest READLINK target NFSv2 can carry
+const NFS_MAXPATHLEN: usize = 1024;
 
 // NFSv2 status codes
 const NFS_OK: u32 = 0;
+const NFSERR_PERM: u32 = 1;
 const NFSERR_NOENT: u32 = 2;
-//const NFSERR_ACCES: u32 = 13;
+const NFSERR_IO: u32 = 5;
+const NFSERR_NXIO: u32 = 6;
+const NFSERR_ACCES: u32 = 13;
+const NFSERR_XDEV: u32 = 18;
+const NFSERR_NOTDIR: u32 = 20;
+const NFSERR_ISDIR: u32 = 21;
+const NFSERR_FBIG: u32 = 27;
+const NFSERR_NOSPC: u32 = 28;
+const NFSERR_ROFS: u32 = 30;
+const NFSERR_NAMETOOLONG: u32 = 63;
+const NFSERR_DQUOT: u32 = 69;
 const NFSERR_STALE: u32 = 70;
+// NFSERR_WFLUSH (99) belongs to the never-implemented WRITECACHE proc;
+// failed WRITE flushes are reported as IO/NOSPC/DQUOT instead.
+
+// NFSv2 file types
+const NFREG: u32 = 1;
+const NFDIR: u32 = 2;
+const NFBLK: u32 = 3;
+const NFCHR: u32 = 4;
+const NFLNK: u32 = 5;
 
 // ------------------------------------------------------------
 // File handle helpers
 // ------------------------------------------------------------
 
-pub fn fh_from_path(path: &Path) -> Vec<u8> {
-    let meta = fs::metadata(path).ok();
+// Handle layout (32 bytes, big endian):
+//   0..8   dev
+//   8..16  ino
+//  16..20  export id
+//  20..24  generation (birth time hash, 0 if unknown)
+//  24..32  truncated HMAC-SHA256 of bytes 0..24, zero when unsigned
+pub const FH_SIZE: usize = 32;
+const FH_SIGNED: usize = 24;
+
+/// Server secret for signing handles. Without one handles carry no MAC
+/// and anybody who can guess an inode number can forge them.
+#[derive(Clone, Default)]
+pub struct FhKey(Option<Arc<Vec<u8>>>);
+
+impl FhKey {
+    pub fn new(secret: Vec<u8>) -> Self {
+        Self(Some(Arc::new(secret)))
+    }
 
-    let mut w = XdrW::new();
+    /// Fresh random secret: handles stop working across restarts.
+    pub fn random() -> Self {
+        Self::new(rand::random::<[u8; 32]>().to_vec())
+    }
 
-    let (dev, ino) = if let Some(m) = meta {
-        (m.dev(), m.ino())
-    } else {
-        (0, 0)
+    fn mac(&self, fields: &[u8]) -> Option<Hmac<Sha256>> {
+        let key = self.0.as_ref()?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes any key length");
+        mac.update(fields);
+        Some(mac)
+    }
+}
+
+/// Fields of a decoded handle.
+#[derive(Debug)]
+struct Fh {
+    dev: u64,
+    ino: u64,
+    export_id: u32,
+    generation: u32,
+}
+
+/// Distinguishes an inode from a later file that reuses its number.
+fn generation(meta: &fs::Metadata) -> u32 {
+    meta.created()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| (d.as_secs() as u32) ^ d.subsec_nanos())
+}
+
+pub fn fh_from_path(key: &FhKey, export: &Export, path: &Path) -> Vec<u8> {
+    let (dev, ino, generation) = match export.metadata(path) {
+        Ok(m) => (m.dev(), m.ino(), generation(&m)),
+        Err(_) => (0, 0, 0),
     };
+    fh_encode(key, dev, ino, export.id(), generation)
+}
 
-    // Very simple, stable handle
+/// Handle of archive member `ino`: no device, and the archive's stamp
+/// as generation so a replaced archive makes old handles stale.
+fn archive_fh(key: &FhKey, export: &Export, fs: &ArchiveFs, ino: u64) -> Vec<u8> {
+    fh_encode(key, 0, ino, export.id(), fs.stamp())
+}
+
+/// Handle of an export's root, as MNT hands it out.
+pub fn root_fh(key: &FhKey, export: &Export) -> Vec<u8> {
+    match &export.archive {
+        Some(fs) => archive_fh(key, export, fs, ROOT_INO),
+        None => fh_from_path(key, export, &export.path),
+    }
+}
+
+fn fh_encode(key: &FhKey, dev: u64, ino: u64, export_id: u32, generation: u32) -> Vec<u8> {
+    let mut w = XdrW::new();
     w.put_u32((dev >> 32) as u32);
     w.put_u32(dev as u32);
     w.put_u32((ino >> 32) as u32);
     w.put_u32(ino as u32);
+    w.put_u32(export_id);
+    w.put_u32(generation);
 
-    let mut v = w.buf.to_vec();
-    v.resize(32, 0);
+    let mut v = w.into_vec();
+    match key.mac(&v) {
+        Some(mac) => v.extend_from_slice(&mac.finalize().into_bytes()[..FH_SIZE - FH_SIGNED]),
+        None => v.resize(FH_SIZE, 0),
+    }
     v
 }
 
-fn path_from_fh(root: &Path, fh: &[u8]) -> Option<PathBuf> {
-    debug!("nfs2: path_from_fh fh_hex={}", hex::encode(fh));
-    if fh.len() != 32 {
-        debug!("nfs2: path_from_fh invalid fh length={}", fh.len());
+/// Parse a handle, checking its MAC when handles are signed.
+fn fh_decode(key: &FhKey, fh: &[u8]) -> Option<Fh> {
+    if fh.len() != FH_SIZE {
+        debug!("nfs2: invalid fh length={}", fh.len());
+        return None;
+    }
+
+    if let Some(mac) = key.mac(&fh[..FH_SIGNED])
+        && mac.verify_truncated_left(&fh[FH_SIGNED..]).is_err()
+    {
+        debug!(fh_hex = hex::encode(fh), "nfs2: fh MAC mismatch");
         return None;
     }
 
-    let ino =
-        ((fh[8] as u64) << 24) | ((fh[9] as u64) << 16) | ((fh[10] as u64) << 8) | (fh[11] as u64);
+    let u32_at = |i: usize| u32::from_be_bytes(fh[i..i + 4].try_into().unwrap());
+    Some(Fh {
+        dev: u64::from_be_bytes(fh[0..8].try_into().unwrap()),
+        ino: u64::from_be_bytes(fh[8..16].try_into().unwrap()),
+        export_id: u32_at(16),
+        generation: u32_at(20),
+    })
+}
+
+#[cfg(test)]
+thread_local! {
+    /// `path_from_fh` walks started on this thread.
+    static WALKS: std::cell::Cell<u32> = const { std::cell::Cell::new(0) };
+}
+
+fn path_from_fh(root: &Path, dev: u64, ino: u64) -> Option<PathBuf> {
+    #[cfg(test)]
+    WALKS.with(|n| n.set(n.get() + 1));
 
-    fn walk(base: &Path, target: u64) -> Option<PathBuf> {
+    fn walk(base: &Path, dev: u64, target: u64) -> Option<PathBuf> {
         let meta = fs::symlink_metadata(base).ok()?;
         debug!("nfs2: path_from_fh walking base={}", base.display());
-        if meta.ino() == target {
+        if meta.ino() == target && meta.dev() == dev {
             debug!("nfs2: path_from_fh found target={}", target);
             return Some(base.to_path_buf());
         }
@@ -76,7 +250,7 @@ fn path_from_fh(root: &Path, fh: &[u8]) -> Option<PathBuf> {
             debug!("nfs2: path_from_fh walking dir={}", base.display());
             for e in fs::read_dir(base).ok()? {
                 let p = e.ok()?.path();
-                if let Some(found) = walk(&p, target) {
+                if let Some(found) = walk(&p, dev, target) {
                     debug!("nfs2: path_from_fh found target={}", target);
                     return Some(found);
                 }
@@ -85,276 +259,1550 @@ fn path_from_fh(root: &Path, fh: &[u8]) -> Option<PathBuf> {
         None
     }
 
-    debug!("path_from_fh: extracted ino={} (0x{:x})", ino, ino);
-    walk(root, ino)
+    debug!(
+        "path_from_fh: extracted dev={} ino={} (0x{:x})",
+        dev, ino, ino
+    );
+    walk(root, dev, ino)
 }
 
-fn nfs_err(errcode: u32) -> Vec<u8> {
-    let mut w = XdrW::new();
+fn nfs_err(xid: u32, errcode: u32) -> Vec<u8> {
+    let mut w = rpc_accept_header(xid, SUCCESS);
     w.put_u32(errcode);
-    w.buf.to_vec()
+    w.into_vec()
 }
 
-// ------------------------------------------------------------
-// XDR helpers
-// ------------------------------------------------------------
+/// A call on its way to the upstream: checked, made as the caller's
+/// export identity, and still carrying our handles.
+struct Relayed {
+    xid: u32,
+    procid: u32,
+    call: Vec<u8>,
+    /// where the arguments start in `call`
+    args: usize,
+    /// our handles in the arguments and the files they name
+    handles: Vec<(RelayedFh, PathBuf)>,
+    /// the one export of those files and the identity the call is made
+    /// as; None for calls without handles
+    export: Option<(Export, RpcAuthUnix)>,
+    peer: PeerInfo,
+}
 
-fn put_fattr(w: &mut XdrW, meta: &std::fs::Metadata, path: &Path) {
-    use std::os::unix::fs::MetadataExt;
+/// Relay a call we cannot answer to the `upstream` server and hand its
+/// reply back. If it does not answer the client gets SYSTEM_ERR rather
+/// than waiting out its own timeout. The round trip counts as the
+/// call's latency, slow-request warning included.
+async fn forward(
+    up: Upstream,
+    relayed: Relayed,
+    env: Env,
+    key: FhKey,
+    slow: SlowThreshold,
+) -> Vec<u8> {
+    let (procid, peer) = (relayed.procid, relayed.peer.clone());
+    let t0 = Instant::now();
+    let reply = exchange(&up, relayed, &env, &key).await;
+    let elapsed = t0.elapsed();
+    METRICS.nfs_latency.observe(elapsed);
+    if slow.exceeded_by(elapsed) {
+        warn!(
+            %peer,
+            proc = NFS_PROC_NAMES.get(procid as usize).copied().unwrap_or("unknown"),
+            ms = elapsed.as_millis() as u64,
+            upstream = %up.addr(),
+            "nfs2: slow request"
+        );
+    }
+    reply
+}
 
-    let is_dir = meta.is_dir();
+/// Swap our handles for the upstream's, make the call and translate
+/// the reply back.
+async fn exchange(up: &Upstream, relayed: Relayed, env: &Env, key: &FhKey) -> Vec<u8> {
+    let Relayed {
+        xid,
+        procid,
+        mut call,
+        args,
+        handles,
+        export,
+        peer,
+    } = relayed;
+    if let Some((export, cred)) = &export {
+        for (fh, path) in &handles {
+            match up.handle(env, export, path, cred).await {
+                Ok(theirs) => call[args + fh.at..][..FH_SIZE].copy_from_slice(&theirs),
+                Err(HandleError::Status(status)) => {
+                    info!(%peer, procid, path = %path.display(), status, "nfs2: upstream has no handle for file");
+                    // a file of ours it does not have: to the upstream
+                    // our handle names nothing
+                    let status = if status == NFSERR_NOENT {
+                        NFSERR_STALE
+                    } else {
+                        status
+                    };
+                    return nfs_err(xid, status);
+                }
+                Err(HandleError::Io(e)) => {
+                    warn!(%peer, procid, upstream = %up.addr(), ?e, "nfs2: upstream handle lookup failed");
+                    return rpc_accept_reply(xid, SYSTEM_ERR, &[]);
+                }
+            }
+        }
+    }
 
-    // --- ftype ---
-    let ftype = if is_dir { 2 } else { 1 }; // NFDIR = 2, NFREG = 1
-    w.put_u32(ftype);
+    let reply = match up.forward(call).await {
+        Ok(reply) => {
+            debug!(%peer, procid, upstream = %up.addr(), "nfs2: forwarded to upstream");
+            reply
+        }
+        Err(e) => {
+            warn!(%peer, procid, upstream = %up.addr(), ?e, "nfs2: upstream failed");
+            return rpc_accept_reply(xid, SYSTEM_ERR, &[]);
+        }
+    };
+    match &export {
+        Some((export, _)) => translate_reply(up, env, key, export, &handles, xid, procid, reply),
+        None => reply,
+    }
+}
 
-    // --- mode ---
-    let mut mode = meta.mode() & 0o777;
-    if is_dir {
-        mode |= 0o040000;
-    } else {
-        mode |= 0o100000;
+/// The upstream's reply as the client must see it: the handle CREATE
+/// and MKDIR return swapped for ours, and what we know of its handles
+/// brought up to date.
+#[allow(clippy::too_many_arguments)]
+fn translate_reply(
+    up: &Upstream,
+    env: &Env,
+    key: &FhKey,
+    export: &Export,
+    handles: &[(RelayedFh, PathBuf)],
+    xid: u32,
+    procid: u32,
+    reply: Vec<u8>,
+) -> Vec<u8> {
+    let Some(mut r) = upstream::results(&reply).map(XdrR::new) else {
+        return reply;
+    };
+    // the file each handle's directory and name stand for
+    let named = |i: usize| {
+        let (fh, dir) = handles.get(i)?;
+        let name = fh.name.as_deref().filter(|n| valid_name(n))?;
+        Some(dir.join(name))
+    };
+    match (procid, r.get_u32()) {
+        // e.g. the upstream was restarted and issues new handles
+        (_, Ok(NFSERR_STALE)) => up.forget_export(export),
+        // CREATE, MKDIR
+        (9 | 14, Ok(NFS_OK)) => {
+            let (Some(p), Ok(theirs)) = (named(0), r.get_fixed(FH_SIZE)) else {
+                return nfs_err(xid, NFSERR_IO);
+            };
+            up.learn(export, &p, &theirs);
+            return match export.metadata(&p) {
+                Ok(meta) => {
+                    let mut w = rpc_accept_header(xid, SUCCESS);
+                    w.put_u32(NFS_OK);
+                    w.put_fixed(&fh_from_path(key, export, &p));
+                    put_fattr(&mut w, &meta, &p, export, env);
+                    w.into_vec()
+                }
+                Err(e) => {
+                    warn!(path = %p.display(), ?e, "nfs2: upstream made a file we cannot see");
+                    nfs_err(xid, NFSERR_IO)
+                }
+            };
+        }
+        // REMOVE, RENAME, RMDIR: nothing is left under the old names
+        (10 | 11 | 15, Ok(NFS_OK)) => {
+            for p in (0..handles.len()).filter_map(named) {
+                up.forget(export, &p);
+            }
+        }
+        _ => {}
     }
-    w.put_u32(mode);
+    reply
+}
 
-    // --- nlink ---
-    let nlink = if is_dir { 2 } else { meta.nlink() as u32 };
-    w.put_u32(nlink);
+/// A file handle in the arguments of a relayed call: where it starts,
+/// and the name after it for a directory handle that comes with one.
+struct RelayedFh {
+    at: usize,
+    fh: Vec<u8>,
+    name: Option<String>,
+}
 
-    // --- uid / gid ---
-    w.put_u32(meta.uid());
-    w.put_u32(meta.gid());
+/// File handles in the arguments of a procedure we relay, so that they
+/// can be checked and translated before the call leaves.
+fn relayed_handles(procid: u32, args: &[u8]) -> Result<Vec<RelayedFh>, XdrError> {
+    let mut r = XdrR::new(args);
+    let mut fh = |named: bool| -> Result<RelayedFh, XdrError> {
+        let at = r.pos;
+        let fh = r.get_fixed(FH_SIZE)?;
+        let name = if named { Some(r.get_string()?) } else { None };
+        Ok(RelayedFh { at, fh, name })
+    };
+    Ok(match procid {
+        // CREATE, REMOVE, SYMLINK, MKDIR, RMDIR: directory and name
+        9 | 10 | 13 | 14 | 15 => vec![fh(true)?],
+        // RENAME: directory and name, twice
+        11 => vec![fh(true)?, fh(true)?],
+        // LINK: the file, then the directory and the new name
+        12 => vec![fh(false)?, fh(true)?],
+        // STATFS
+        17 => vec![fh(false)?],
+        // ROOT, WRITECACHE: no arguments
+        _ => vec![],
+    })
+}
 
-    // --- size ---
-    let size = if is_dir { 512 } else { meta.len() as u32 };
-    w.put_u32(size);
+/// AUTH_UNIX credential for the host identity `export` gives the caller.
+fn export_auth(export: &Export, peer: &PeerInfo) -> RpcAuthUnix {
+    let cred = export.cred(peer);
+    RpcAuthUnix {
+        machine: peer.machine_name.clone().unwrap_or_default(),
+        uid: cred.uid,
+        gid: cred.gid,
+        aux_gids: cred.gids,
+    }
+}
 
-    // --- blocksize ---
-    w.put_u32(512);
+/// `buf` with the credential replaced by `auth` and a null verifier.
+fn with_cred(buf: &[u8], ofs: usize, auth: &RpcAuthUnix) -> Vec<u8> {
+    let body = encode_auth_unix(auth);
 
-    // --- rdev ---
+    let mut w = XdrW::with_capacity(buf.len() + body.len());
+    // xid, message type, RPC version, program, version, procedure
+    w.buf.extend_from_slice(&buf[..24]);
+    w.put_u32(AUTH_UNIX);
+    w.put_opaque(&body);
+    w.put_u32(AUTH_NULL);
     w.put_u32(0);
+    w.buf.extend_from_slice(&buf[ofs..]);
+    w.into_vec()
+}
+
+/// Map a host I/O error to the closest NFSv2 status.
+fn nfs_status(e: &std::io::Error) -> u32 {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => NFSERR_NOENT,
+        std::io::ErrorKind::PermissionDenied => NFSERR_ACCES,
+        std::io::ErrorKind::NotADirectory => NFSERR_NOTDIR,
+        std::io::ErrorKind::FileTooLarge => NFSERR_FBIG,
+        std::io::ErrorKind::StorageFull => NFSERR_NOSPC,
+        std::io::ErrorKind::ReadOnlyFilesystem => NFSERR_ROFS,
+        std::io::ErrorKind::QuotaExceeded => NFSERR_DQUOT,
+        _ => NFSERR_IO,
+    }
+}
+
+/// Whether a client-supplied file name names one entry of the directory
+/// it is looked up in. "..", "." and anything with a separator would
+/// walk somewhere else, out of the export at its root.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\0'])
+}
+
+/// Whether `name` is longer than the export lets clients see. READDIR
+/// leaves such names out; LOOKUP answers NFSERR_NAMETOOLONG.
+fn name_too_long(export: &Export, name: &str) -> bool {
+    export.max_name_len.is_some_and(|max| name.len() > max)
+}
+
+/// READDIR entry filter: whether `name` is listed to clients.
+/// Skipped entries still consume a cookie, so cookies stay stable.
+fn readdir_visible(export: &Export, name: &str) -> bool {
+    if name_too_long(export, name) {
+        debug!(name, "nfs2: READDIR skipping over-long name");
+        return false;
+    }
+    true
+}
+
+/// Bytes a READDIR entry for `name` adds to the reply:
+/// bool(4) + fileid(4) + string(4 + len + pad) + cookie(4).
+fn dirent_bytes(name: &str) -> usize {
+    let pad = (4 - name.len() % 4) % 4;
+    4 + 4 + (4 + name.len() + pad) + 4
+}
+
+/// READDIR cookie that resumes after the entry at position `idx`.
+/// Cookie 0 is reserved for "from the start", so entry n hands out
+/// n + 1: an entry given 0 would send the client back to the top
+/// forever. Whatever scheme replaces positions must keep 0 free.
+fn resume_cookie(idx: u32) -> u32 {
+    idx + 1
+}
+
+/// Read until `buf` is full or the file ends. A single `read_at` may
+/// come back short (signals, network filesystems), and NFSv2 clients
+/// take any short READ reply as end of file.
+pub fn read_full(env: &Env, f: &fs::File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    let mut done = 0;
+    while done < buf.len() {
+        match env.read_at(f, &mut buf[done..], offset + done as u64) {
+            Ok(0) => break,
+            Ok(n) => done += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(done)
+}
+
+// permission bits for may()
+const MAY_READ: u32 = 0o4;
+const MAY_WRITE: u32 = 0o2;
+
+/// Write `data` at `offset` and flush it to stable storage before
+/// returning. NFSv2 WRITE is synchronous: a reply of NFS_OK promises the
+/// data is on disk, so a failed flush must fail the WRITE.
+fn write_sync(env: &Env, p: &Path, data: &[u8], offset: u64) -> std::io::Result<()> {
+    let f = fs::OpenOptions::new().write(true).open(p)?;
+    f.write_all_at(data, offset)?;
+    env.sync_data(&f)
+}
+
+// permission bits of `want` (MAY_*) on `meta` for `cred`.
+fn may(meta: &fs::Metadata, cred: &Cred, want: u32) -> bool {
+    may_mode(meta.mode(), meta.uid(), meta.gid(), cred, want)
+}
 
-    // --- blocks ---
-    let blocks = if is_dir {
-        1
+fn may_mode(mode: u32, uid: u32, gid: u32, cred: &Cred, want: u32) -> bool {
+    let bits = if cred.uid == 0 {
+        return true;
+    } else if cred.uid == uid {
+        mode >> 6
+    } else if cred.gid == gid || cred.gids.contains(&gid) {
+        mode >> 3
     } else {
-        //((meta.len().div_ceil(512) + 511) / 512) as u32
-        meta.len().div_ceil(512) as u32
+        mode
+    };
+    bits & want == want
+}
+
+/// Access check for one call. Evaluated against the caller's credentials
+/// on every request and never cached per handle: handle bytes are shared
+/// by every client, and the same handle must give different answers to
+/// identities with different rights.
+fn allowed(export: &Export, peer: &PeerInfo, meta: &fs::Metadata, want: u32) -> bool {
+    let cred = export.cred(peer);
+    let ok = may(meta, &cred, want);
+    debug!(
+        uid = cred.uid,
+        gid = cred.gid,
+        want,
+        ok,
+        "nfs2: access check"
+    );
+    ok
+}
+
+/// Encode a host dev_t into the 32 bit NFSv2 rdev field
+/// (Linux "new" encoding: 12 bit major, 20 bit minor).
+fn nfs_rdev(rdev: u64) -> u32 {
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+    ((minor & 0xff) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12)) as u32
+}
+
+/// RISC OS load address for a file, taken from an extended attribute.
+/// Accepts the 8 byte load/exec pair (little endian, as RISC OS stores
+/// it) or a bare 3 digit hex filetype such as "ffb". The filetype sits
+/// in bits 8..20 of a stamped load address (0xFFFtttxx).
+fn riscos_load(path: &Path, attr: &str) -> Option<u32> {
+    let v = match xattr::get_deref(path, attr) {
+        Ok(Some(v)) => v,
+        Ok(None) => return None,
+        Err(e) => {
+            debug!(path = %path.display(), attr, ?e, "nfs2: getxattr failed");
+            return None;
+        }
     };
-    w.put_u32(blocks);
+    riscos_load_value(&v)
+}
+
+/// Load address from the bytes of the RISC OS attribute.
+fn riscos_load_value(v: &[u8]) -> Option<u32> {
+    if v.len() == 8 {
+        return Some(u32::from_le_bytes(v[0..4].try_into().unwrap()));
+    }
+    let ft = std::str::from_utf8(v).ok()?.trim_end_matches('\0').trim();
+    if ft.len() != 3 {
+        return None;
+    }
+    let ft = u32::from_str_radix(ft, 16).ok()?;
+    Some(0xfff0_0000 | (ft << 8))
+}
+
+// ------------------------------------------------------------
+// XDR helpers
+// ------------------------------------------------------------
+
+/// fattr fields, in wire order (times without their usecs).
+struct Fattr {
+    ftype: u32,
+    mode: u32,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    size: u32,
+    rdev: u32,
+    blocks: u32,
+    fileid: u32,
+    atime: u32,
+    mtime: u32,
+    ctime: u32,
+    ctime_usec: u32,
+}
+
+impl Fattr {
+    fn put(&self, w: &mut XdrW) {
+        w.put_u32(self.ftype);
+        w.put_u32(self.mode);
+        w.put_u32(self.nlink);
+        w.put_u32(self.uid);
+        w.put_u32(self.gid);
+        w.put_u32(self.size);
+        w.put_u32(512); // blocksize
+        w.put_u32(self.rdev);
+        w.put_u32(self.blocks);
+        w.put_u32(1); // fsid
+        w.put_u32(self.fileid);
+        w.put_u32(self.atime);
+        w.put_u32(0);
+        w.put_u32(self.mtime);
+        w.put_u32(0);
+        w.put_u32(self.ctime);
+        w.put_u32(self.ctime_usec);
+    }
+}
+
+fn put_fattr(w: &mut XdrW, meta: &std::fs::Metadata, path: &Path, export: &Export, env: &Env) {
+    use std::os::unix::fs::MetadataExt;
+
+    let is_dir = meta.is_dir();
+    let ft = meta.file_type();
+    let is_dev = ft.is_block_device() || ft.is_char_device();
 
-    // --- fsid ---
-    w.put_u32(1);
+    // --- ftype ---
+    let (ftype, fmt) = if is_dir {
+        (NFDIR, 0o040000)
+    } else if ft.is_block_device() {
+        (NFBLK, 0o060000)
+    } else if ft.is_char_device() {
+        (NFCHR, 0o020000)
+    } else if ft.is_symlink() {
+        (NFLNK, 0o120000)
+    } else {
+        (NFREG, 0o100000)
+    };
 
-    // --- fileid (DO NOT USE inode) ---
-    let fileid = crc32fast::hash(path.to_string_lossy().as_bytes());
-    w.put_u32(fileid);
+    // --- rdev ---
+    // Unused for regular files, so RISC OS clients get the load address there.
+    let rdev = if is_dev {
+        nfs_rdev(meta.rdev())
+    } else if let Some(attr) = export.riscos_xattr.as_deref().filter(|_| ftype == NFREG) {
+        riscos_load(path, attr).unwrap_or(0)
+    } else {
+        0
+    };
 
     // --- times ---
-    let atime = meta.atime() as u32;
-    let mtime = meta.mtime() as u32;
-    let ctime = meta.ctime() as u32;
+    let [atime, mtime, ctime] = match export.fixed_mtime {
+        Some(t) => [t; 3],
+        None => env.file_times(meta),
+    };
 
-    w.put_u32(atime);
-    w.put_u32(0);
-    w.put_u32(mtime);
-    w.put_u32(0);
-    w.put_u32(ctime);
-    w.put_u32(0);
+    let fa = Fattr {
+        ftype,
+        mode: (meta.mode() & 0o777) | fmt,
+        nlink: if is_dir { 2 } else { meta.nlink() as u32 },
+        uid: export.client_uid(meta.uid()),
+        gid: export.client_gid(meta.gid()),
+        size: if is_dir { 512 } else { meta.len() as u32 },
+        rdev,
+        blocks: if is_dir {
+            1
+        } else {
+            meta.len().div_ceil(512) as u32
+        },
+        // DO NOT USE inode
+        fileid: crc32fast::hash(path.to_string_lossy().as_bytes()),
+        atime,
+        mtime,
+        ctime,
+        ctime_usec: export.change_ids.as_ref().map_or(0, |c| c.get(meta)),
+    };
     // log all fattr fields
     debug!(
         path = %path.display(),
         ftype,
-        mode = format_args!("{:o}", mode),
-        nlink,
-        uid = meta.uid(),
-        gid = meta.gid(),
-        size,
-        blocks,
-        fileid,
+        mode = format_args!("{:o}", fa.mode),
+        nlink = fa.nlink,
+        uid = fa.uid,
+        gid = fa.gid,
+        size = fa.size,
+        rdev,
+        blocks = fa.blocks,
+        fileid = fa.fileid,
         atime,
         mtime,
         ctime,
         "nfs2: file attributes"
     );
+    fa.put(w);
+}
+
+/// Attributes of an archive member. Its inode number is the fileid,
+/// and all three times are its mtime.
+fn archive_fattr(node: &Node, ino: u64, export: &Export) -> Fattr {
+    let (ftype, fmt) = match node.kind {
+        Kind::Dir => (NFDIR, 0o040000),
+        Kind::Symlink(_) => (NFLNK, 0o120000),
+        Kind::File => (NFREG, 0o100000),
+    };
+    let is_dir = node.kind == Kind::Dir;
+    let time = export.fixed_mtime.unwrap_or(node.mtime);
+    Fattr {
+        ftype,
+        mode: (node.mode & 0o777) | fmt,
+        nlink: if is_dir { 2 } else { 1 },
+        uid: export.client_uid(node.uid),
+        gid: export.client_gid(node.gid),
+        size: if is_dir { 512 } else { node.size as u32 },
+        rdev: 0,
+        blocks: if is_dir {
+            1
+        } else {
+            node.size.div_ceil(512) as u32
+        },
+        fileid: ino as u32,
+        atime: time,
+        mtime: time,
+        ctime: time,
+        ctime_usec: 0,
+    }
 }
 
 // ------------------------------------------------------------
 
+/// WRITE arguments. On the wire: fhandle, beginoffset, offset,
+/// totalcount, data. beginoffset and totalcount are unused since the
+/// RFC but still take up their slots before the data.
+struct WriteArgs {
+    fh: Vec<u8>,
+    offset: u64,
+    data: Vec<u8>,
+}
+
+impl WriteArgs {
+    fn decode(r: &mut XdrR) -> Result<Self, XdrError> {
+        let fh = r.get_fixed(FH_SIZE)?;
+        let _beginoffset = r.get_u32()?;
+        let offset = r.get_u32()? as u64;
+        let _totalcount = r.get_u32()?;
+        let data = r.get_opaque_max(NFS_MAXDATA)?;
+        Ok(Self { fh, offset, data })
+    }
+}
+
+/// One sattr time. RFC 1094 marks an unset field with all ones; the
+/// Sun convention of useconds = 1000000 asks for the server's clock,
+/// which is how `touch` without a time reaches us.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SetTime {
+    Keep,
+    Now,
+    At(SystemTime),
+}
+
+impl SetTime {
+    fn decode(r: &mut XdrR) -> Result<Self, XdrError> {
+        let secs = r.get_u32()?;
+        let usecs = r.get_u32()?;
+        Ok(if secs == u32::MAX {
+            SetTime::Keep
+        } else if usecs == 1_000_000 {
+            SetTime::Now
+        } else {
+            SetTime::At(UNIX_EPOCH + Duration::new(secs as u64, usecs.min(999_999) * 1000))
+        })
+    }
+
+    /// As utimensat(2) takes it.
+    fn timespec(self) -> libc::timespec {
+        let (tv_sec, tv_nsec) = match self {
+            SetTime::Keep => (0, libc::UTIME_OMIT),
+            SetTime::Now => (0, libc::UTIME_NOW),
+            SetTime::At(t) => {
+                let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+                (
+                    d.as_secs() as libc::time_t,
+                    d.subsec_nanos() as libc::c_long,
+                )
+            }
+        };
+        libc::timespec { tv_sec, tv_nsec }
+    }
+}
+
+/// SETATTR attributes; `None` fields stay as they are.
+#[derive(Debug)]
+struct Sattr {
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    size: Option<u32>,
+    atime: SetTime,
+    mtime: SetTime,
+}
+
+fn get_sattr(r: &mut XdrR) -> Result<Sattr, XdrError> {
+    let mut field = || r.get_u32().map(|v| (v != u32::MAX).then_some(v));
+    let mode = field()?;
+    let uid = field()?;
+    let gid = field()?;
+    let size = field()?;
+    Ok(Sattr {
+        mode,
+        uid,
+        gid,
+        size,
+        atime: SetTime::decode(r)?,
+        mtime: SetTime::decode(r)?,
+    })
+}
+
+/// Apply `sa` to `p`. Ids arrive in client terms and go through the
+/// export's maps. Nothing here opens `p`: an open would block on a FIFO
+/// and reach the host device behind a device node. Times are set with
+/// utimensat on the path, which leaves a UTIME_OMIT time untouched and
+/// stamps UTIME_NOW with the server's clock.
+fn set_attrs(p: &Path, export: &Export, sa: &Sattr) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::PermissionsExt;
+
+    let cpath = CString::new(p.as_os_str().as_bytes())?;
+    if let Some(size) = sa.size {
+        // SAFETY: `cpath` is a valid NUL terminated string
+        if unsafe { libc::truncate(cpath.as_ptr(), size as libc::off_t) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    if let Some(mode) = sa.mode {
+        fs::set_permissions(p, fs::Permissions::from_mode(mode & 0o7777))?;
+    }
+    if sa.uid.is_some() || sa.gid.is_some() {
+        std::os::unix::fs::chown(
+            p,
+            sa.uid.map(|u| export.host_uid(u)),
+            sa.gid.map(|g| export.host_gid(g)),
+        )?;
+    }
+
+    if sa.atime != SetTime::Keep || sa.mtime != SetTime::Keep {
+        let times = [sa.atime.timespec(), sa.mtime.timespec()];
+        // SAFETY: `cpath` is NUL terminated and `times` holds the two
+        // entries utimensat reads
+        let rc = unsafe {
+            libc::utimensat(
+                libc::AT_FDCWD,
+                cpath.as_ptr(),
+                times.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        };
+        if rc != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Whether `cred` may change `sa` on `meta`. Like utimensat(2): owner
+/// only for mode, ids and explicit times; write access truncates, and
+/// either one stamps the current time.
+fn setattr_status(export: &Export, meta: &fs::Metadata, cred: &Cred, sa: &Sattr) -> u32 {
+    let owner = cred.uid == 0 || cred.uid == meta.uid();
+    let explicit = matches!(sa.atime, SetTime::At(_)) || matches!(sa.mtime, SetTime::At(_));
+    if !owner && (sa.mode.is_some() || sa.uid.is_some() || sa.gid.is_some() || explicit) {
+        return NFSERR_PERM;
+    }
+    let stamps = sa.atime == SetTime::Now || sa.mtime == SetTime::Now;
+    let writable = may(meta, cred, MAY_WRITE);
+    if (sa.size.is_some() && !writable) || (stamps && !owner && !writable) {
+        return NFSERR_ACCES;
+    }
+    if sa.size.is_some() && meta.is_dir() {
+        return NFSERR_ISDIR;
+    }
+    // only regular files have a size to set
+    if sa.size.is_some() && !meta.is_file() {
+        return NFSERR_NXIO;
+    }
+    if sa
+        .size
+        .is_some_and(|size| over_cap(export, meta.len(), size as u64))
+    {
+        return NFSERR_FBIG;
+    }
+    NFS_OK
+}
+
+/// Whether growing a file from `cur` to `end` bytes breaks the export's
+/// `max_file_size`. Files already past it may still be rewritten in
+/// place or shrunk.
+fn over_cap(export: &Export, cur: u64, end: u64) -> bool {
+    export
+        .max_file_size
+        .is_some_and(|max| end > max && end > cur)
+}
+
+/// Note a change we made to this file, for exports with `change_ids`.
+fn bump_change_id(export: &Export, meta: &std::fs::Metadata) {
+    if let Some(c) = &export.change_ids {
+        c.bump(meta);
+    }
+}
+
+/// NFS procedures the operator switched off, one bit per procedure
+/// number. Shared with the config reloader, so a change applies to the
+/// next call.
+#[derive(Clone, Default)]
+pub struct DisabledProcs(Arc<AtomicU32>);
+
+impl DisabledProcs {
+    pub fn set(&self, mask: u32) {
+        self.0.store(mask, Ordering::Relaxed);
+    }
+
+    fn contains(&self, procid: u32) -> bool {
+        procid < 32 && self.0.load(Ordering::Relaxed) & (1 << procid) != 0
+    }
+}
+
 #[derive(Clone)]
 pub struct Nfs2 {
-    #[allow(dead_code)]
     exports: Exports,
     mounts: MountTable,
+    read_ahead: ReadAhead,
+    handles: HandleCache,
+    fh_key: FhKey,
+    disabled: DisabledProcs,
+    env: Env,
+    // program number registered with rpcbind (NFS_PROG unless overridden)
+    prog: u32,
+    upstream: Option<Upstream>,
+    slow: SlowThreshold,
 }
 
 impl Nfs2 {
-    pub fn new(exports: Exports, mounts: MountTable) -> Self {
-        Self { exports, mounts }
+    pub fn new(
+        exports: Exports,
+        mounts: MountTable,
+        fh_key: FhKey,
+        disabled: DisabledProcs,
+        env: Env,
+        prog: u32,
+        queue: FsQueue,
+    ) -> Self {
+        Self {
+            exports,
+            mounts,
+            read_ahead: ReadAhead::new(queue, env.clone()),
+            handles: HandleCache::new(),
+            fh_key,
+            disabled,
+            env,
+            prog,
+            upstream: None,
+            slow: SlowThreshold::default(),
+        }
     }
 
-    // --------------------------------------------------------
-    // Core RPC handler
-    // --------------------------------------------------------
+    /// Relay procedures we do not implement to this server.
+    pub fn upstream(mut self, upstream: Option<Upstream>) -> Self {
+        self.upstream = upstream;
+        self
+    }
 
-    fn handle_call(&self, buf: &[u8], peer: &str) -> Option<Vec<u8>> {
-        let (call, ofs) = decode_call(buf)?;
+    /// Warn about calls slower than this.
+    pub fn slow_threshold(mut self, slow: SlowThreshold) -> Self {
+        self.slow = slow;
+        self
+    }
 
-        // Explicit NFSv3 rejection (THIS FIXES macOS)
-        if call.prog == NFS_PROG && call.vers != NFS_VERS {
-            info!(
-                peer,
-                vers = call.vers,
-                "nfs2: rejecting unsupported NFS version"
-            );
-            return Some(rpc_prog_mismatch_reply(call.xid, 2, 2));
+    /// Find the export a handle belongs to and the host path it names.
+    /// Handles of exports the caller may not use do not resolve.
+    fn resolve(&self, fh: &[u8], peer: &PeerInfo) -> Option<(&Export, PathBuf)> {
+        debug!("nfs2: resolve fh_hex={}", hex::encode(fh));
+        let fh = fh_decode(&self.fh_key, fh)?;
+        let export = self
+            .exports
+            .list()
+            .iter()
+            .find(|e| e.id() == fh.export_id)?;
+
+        if !self.exports.permits(export, peer.ip()) {
+            info!(%peer, export = %export.name(), "nfs2: client not allowed for export");
+            return None;
         }
 
-        if call.prog != NFS_PROG || call.vers != NFS_VERS {
+        // The cache is shared by all exports; a path is only used for
+        // this one if it really lies inside it.
+        let p = match self.handles.get(fh.dev, fh.ino) {
+            Some(p) if export.contains(&p) => p,
+            _ => {
+                let p = path_from_fh(&export.path, fh.dev, fh.ino)?;
+                if !export.contains(&p) {
+                    return None;
+                }
+                self.handles.insert(fh.dev, fh.ino, &p);
+                p
+            }
+        };
+
+        // same inode number, different file
+        if fh.generation != 0
+            && fs::symlink_metadata(&p).map(|m| generation(&m)).ok() != Some(fh.generation)
+        {
+            debug!(path = %p.display(), "nfs2: fh generation mismatch");
             return None;
         }
+        Span::current().record("path", field::display(p.display()));
+        Some((export, p))
+    }
 
-        let mut r = XdrR::new(&buf[ofs..]);
-        let root = Path::new("/tmp");
+    /// Handle used when GETATTR or READDIR arrives without one, which
+    /// some clients do for the mount root: the root of a mount, the
+    /// caller's own first, whose export keeps `empty_fh_root_fallback`.
+    fn root_fallback(&self, peer: &PeerInfo) -> Option<Vec<u8>> {
+        let ip = peer.ip();
+        let mounts = self.mounts.lock().unwrap();
+        let mut candidates: Vec<_> = mounts.iter().collect();
+        candidates.sort_by_key(|((mip, _), _)| *mip != ip);
+
+        let fh = candidates.into_iter().map(|(_, fh)| fh).find(|fh| {
+            fh_decode(&self.fh_key, fh).is_some_and(|fh| {
+                self.exports
+                    .list()
+                    .iter()
+                    .any(|e| e.id() == fh.export_id && e.empty_fh_root_fallback)
+            })
+        });
+        debug!(%peer, found = fh.is_some(), "nfs2: empty fh, root fallback");
+        fh.cloned()
+    }
 
-        info!(peer, xid = call.xid, procid = call.procid, "nfs2: request");
+    /// READ payload, served from the read-ahead cache when the export
+    /// enables it and a prefetched chunk covers the range.
+    fn read_data(
+        &self,
+        export: &Export,
+        p: &Path,
+        meta: &fs::Metadata,
+        peer: &PeerInfo,
+        offset: u64,
+        count: usize,
+    ) -> std::io::Result<Vec<u8>> {
+        // Nothing to fetch, so no open and no read-ahead bookkeeping.
+        // The caller checked access all the same: whether READ is
+        // allowed does not depend on count.
+        if count == 0 {
+            return Ok(Vec::new());
+        }
 
-        let reply = match call.procid {
-            // NULL
-            0 => {
-                let w = XdrW::new();
-                rpc_accept_reply(call.xid, 0, &w.buf)
+        let cached = if export.read_ahead {
+            self.read_ahead.get(meta, offset, count)
+        } else {
+            None
+        };
+
+        let data = match cached {
+            Some(d) => d,
+            None => {
+                let mut data = vec![0u8; count];
+                let n = read_full(&self.env, &fs::File::open(p)?, &mut data, offset)?;
+                data.truncate(n);
+                data
             }
+        };
+
+        if export.read_ahead {
+            // streams are per client host
+            let client = peer.ip().to_string();
+            self.read_ahead
+                .observe(p, meta, &client, offset, data.len());
+        }
+        Ok(data)
+    }
+
+    /// Calls on handles of an archive export, answered from its index.
+    /// `None` for every other handle, which `dispatch` serves.
+    fn archive_call(&self, call: &RpcCall, args: &[u8], peer: &PeerInfo) -> Option<Vec<u8>> {
+        if !matches!(call.procid, 1 | 2 | 4 | 5 | 6 | 8 | 16) {
+            return None;
+        }
+        let (fh, rest) = match args.split_at_checked(FH_SIZE) {
+            Some((fh, rest)) => (fh.to_vec(), rest),
+            // GETATTR and READDIR without a handle: as in `dispatch`
+            None if matches!(call.procid, 1 | 16) => (self.root_fallback(peer)?, args),
+            None => return None,
+        };
+        let f = fh_decode(&self.fh_key, &fh)?;
+        let export = self.exports.list().iter().find(|e| e.id() == f.export_id)?;
+        let fs = export.archive.as_deref()?;
+
+        let mut r = XdrR::new(rest);
+        let mut w = rpc_accept_header(call.xid, SUCCESS);
+
+        let node = fs
+            .node(f.ino)
+            .filter(|_| f.dev == 0 && f.generation == fs.stamp());
+        let Some(node) = node else {
+            debug!(
+                %peer,
+                ino = f.ino,
+                "nfs2: archive handle does not match the archive"
+            );
+            w.put_u32(NFSERR_STALE);
+            return Some(w.into_vec());
+        };
+        if !self.exports.permits(export, peer.ip()) {
+            info!(%peer, export = %export.name(), "nfs2: client not allowed for export");
+            w.put_u32(NFSERR_STALE);
+            return Some(w.into_vec());
+        }
+        let is_dir = node.kind == Kind::Dir;
 
+        match call.procid {
             // GETATTR
             1 => {
-                let mut fh = r.get_opaque().unwrap_or_default();
+                w.put_u32(NFS_OK);
+                archive_fattr(node, f.ino, export).put(&mut w);
+            }
 
-                if fh.is_empty() {
-                    if let Some((_, root_fh)) = self.mounts.lock().unwrap().iter().next() {
-                        fh = root_fh.clone();
-                    } else {
-                        return Some(nfs_err(NFSERR_STALE));
-                    }
-                }
-                let mut w = XdrW::new();
+            // SETATTR, WRITE
+            2 | 8 => w.put_u32(NFSERR_ROFS),
 
-                info!(
-                    "nfs2: GETATTR raw file handle fh_len={}, fh_hex={}",
-                    fh.len(),
-                    hex::encode(&fh)
-                );
-                if let Some(p) = path_from_fh(root, &fh) {
-                    debug!("nfs2: GETATTR resolved path={}", p.display());
-                    if let Ok(meta) = fs::metadata(&p) {
-                        info!(
-                            peer,
-                            path = %p.display(),
-                            size = meta.len(),
-                            ino = meta.ino(),
-                            mode = format_args!("{:o}", meta.mode()),
-                            "nfs2: GETATTR metadata"
-                        );
-                        w.put_u32(NFS_OK);
-                        put_fattr(&mut w, &meta, &p);
-                    } else {
-                        w.put_u32(NFSERR_NOENT);
-                        // Log meta failure
-                        info!(peer, path = %p.display(), "nfs2: GETATTR metadata failed");
-                    }
+            // LOOKUP
+            4 => {
+                let name = r.get_string().unwrap_or_default();
+                if !valid_name(&name) {
+                    info!(%peer, name, "nfs2: LOOKUP refused name");
+                    w.put_u32(NFSERR_ACCES);
+                } else if !is_dir {
+                    w.put_u32(NFSERR_NOTDIR);
+                } else if name_too_long(export, &name) {
+                    w.put_u32(NFSERR_NAMETOOLONG);
+                } else if let Some(ino) = fs.lookup(f.ino, &name) {
+                    debug!(%peer, name, ino, "nfs2: LOOKUP in archive");
+                    w.put_u32(NFS_OK);
+                    w.put_fixed(&archive_fh(&self.fh_key, export, fs, ino));
+                    archive_fattr(fs.node(ino)?, ino, export).put(&mut w);
                 } else {
                     w.put_u32(NFSERR_NOENT);
                 }
-
-                rpc_accept_reply(call.xid, 0, &w.buf)
             }
 
-            // LOOKUP
-            4 => {
-                info!(
-                    peer,
-                    vers = call.vers,
-                    auth = ?call.auth,
+            // READLINK
+            5 => match &node.kind {
+                Kind::Symlink(target) if target.len() > NFS_MAXPATHLEN => {
+                    w.put_u32(NFSERR_NAMETOOLONG)
+                }
+                Kind::Symlink(target) => {
+                    w.put_u32(NFS_OK);
+                    w.put_opaque(target.as_bytes());
+                }
+                _ => w.put_u32(NFSERR_NXIO),
+            },
+
+            // READ
+            6 => {
+                let offset = r.get_u32().unwrap_or(0) as u64;
+                let count = (r.get_u32().unwrap_or(0) as usize).min(NFS_MAXDATA);
+                let cred = export.cred(peer);
+
+                if is_dir {
+                    w.put_u32(NFSERR_ISDIR);
+                } else if node.kind != Kind::File {
+                    w.put_u32(NFSERR_NXIO);
+                } else if !may_mode(node.mode, node.uid, node.gid, &cred, MAY_READ) {
+                    info!(%peer, ino = f.ino, cred = ?peer.cred, "nfs2: READ access denied");
+                    w.put_u32(NFSERR_ACCES);
+                } else {
+                    match fs.read(&self.env, f.ino, offset, count) {
+                        Ok(data) => {
+                            debug!(
+                                %peer,
+                                ino = f.ino,
+                                offset,
+                                count,
+                                n = data.len(),
+                                "nfs2: READ from archive"
+                            );
+                            METRICS
+                                .read_bytes
+                                .fetch_add(data.len() as u64, Ordering::Relaxed);
+                            w.put_u32(NFS_OK);
+                            archive_fattr(node, f.ino, export).put(&mut w);
+                            w.put_opaque(&data);
+                        }
+                        Err(e) => {
+                            warn!(%peer, ino = f.ino, ?e, "nfs2: READ from archive failed");
+                            w.put_u32(nfs_status(&e));
+                        }
+                    }
+                }
+            }
+
+            // READDIR
+            16 => {
+                let cookie = r.get_u32().unwrap_or(0) as usize;
+                let count = r.get_u32().unwrap_or(0) as usize;
+                let max_bytes = if count == 0 { 4096 } else { count };
+
+                if export.no_readdir {
+                    w.put_u32(NFSERR_ACCES);
+                } else if !is_dir {
+                    w.put_u32(NFSERR_NOTDIR);
+                } else {
+                    // the client's count covers the result, not our RPC header
+                    let header = w.buf.len();
+                    w.put_u32(NFS_OK);
+                    let mut eof = true;
+                    let mut emitted = 0;
+                    for (idx, &ino) in node.children.iter().enumerate().skip(cookie) {
+                        let name = &fs.node(ino)?.name;
+                        if !readdir_visible(export, name) {
+                            continue;
+                        }
+                        // always at least one entry, as in `dispatch`
+                        if w.buf.len() - header + dirent_bytes(name) + 8 > max_bytes && emitted > 0
+                        {
+                            eof = false;
+                            break;
+                        }
+                        w.put_u32(1);
+                        w.put_u32(ino as u32);
+                        w.put_string(name);
+                        w.put_u32(resume_cookie(idx as u32));
+                        emitted += 1;
+                    }
+                    w.put_u32(0);
+                    w.put_u32(eof as u32);
+                }
+            }
+
+            _ => unreachable!("procedure filtered above"),
+        }
+        Some(w.into_vec())
+    }
+
+    // --------------------------------------------------------
+    // Core RPC handler
+    // --------------------------------------------------------
+
+    /// Whether calls for `prog` are ours. Clients always speak NFS_PROG;
+    /// a custom number only exists so that several instances can be
+    /// registered with rpcbind.
+    pub fn serves(&self, prog: u32) -> bool {
+        prog == NFS_PROG || prog == self.prog
+    }
+
+    pub fn handle_call(&self, buf: &[u8], addr: SocketAddr) -> Reply {
+        let (call, ofs) = match decode_call(buf) {
+            Ok(v) => v,
+            Err(e) => return e.reply().into(),
+        };
+        let peer = &PeerInfo::new(addr, &call.auth);
+        // WARN level so xid/path still decorate the slow-request warning
+        // when only warnings are logged
+        let _span = warn_span!("rpc", xid = call.xid, path = field::Empty).entered();
+
+        let ours = self.serves(call.prog);
+
+        // Explicit NFSv3 rejection (THIS FIXES macOS)
+        if ours && call.vers != NFS_VERS {
+            info!(
+                %peer,
+                vers = call.vers,
+                "nfs2: rejecting unsupported NFS version"
+            );
+            return Some(rpc_prog_mismatch_reply(call.xid, NFS_VERS, NFS_VERS)).into();
+        }
+
+        if !ours {
+            // answer instead of dropping so a misdirected client fails fast
+            debug!(%peer, prog = call.prog, "nfs2: call for unknown program");
+            return Some(rpc_accept_reply(call.xid, PROG_UNAVAIL, &[])).into();
+        }
+
+        info!(%peer, xid = call.xid, procid = call.procid, "nfs2: request");
+        METRICS.nfs_call(call.procid);
+
+        if !NFS_SUPPORTED.iter().any(|&(p, _)| p == call.procid) {
+            if let Some(up) = self
+                .upstream
+                .as_ref()
+                .filter(|_| !self.disabled.contains(call.procid))
+            {
+                return self.relay(up, &call, buf, ofs, peer);
+            }
+            debug!(%peer, procid = call.procid, "nfs2: unimplemented proc");
+            return Some(rpc_accept_reply(call.xid, PROC_UNAVAIL, &[])).into();
+        }
+        if self.disabled.contains(call.procid) {
+            debug!(%peer, procid = call.procid, "nfs2: proc disabled by config");
+            return Some(rpc_accept_reply(call.xid, PROC_UNAVAIL, &[])).into();
+        }
+
+        let t0 = Instant::now();
+        let reply = match self.archive_call(&call, &buf[ofs..], peer) {
+            Some(reply) => reply,
+            None => self.dispatch(&call, &buf[ofs..], peer),
+        };
+        let elapsed = t0.elapsed();
+        METRICS.nfs_latency.observe(elapsed);
+        if self.slow.exceeded_by(elapsed) {
+            warn!(
+                %peer,
+                proc = NFS_PROC_NAMES[call.procid as usize],
+                ms = elapsed.as_millis() as u64,
+                "nfs2: slow request"
+            );
+        }
+
+        Some(reply).into()
+    }
+
+    /// Hand a procedure we do not implement to the upstream server after
+    /// the checks we would make ourselves: each handle must belong to an
+    /// export the caller may use, nothing changes on a read-only export,
+    /// and the upstream sees the caller as squashed and mapped.
+    fn relay(
+        &self,
+        up: &Upstream,
+        call: &RpcCall,
+        buf: &[u8],
+        ofs: usize,
+        peer: &PeerInfo,
+    ) -> Reply {
+        let Ok(fhs) = relayed_handles(call.procid, &buf[ofs..]) else {
+            return Some(rpc_accept_reply(call.xid, GARBAGE_ARGS, &[])).into();
+        };
+
+        let mut exports = vec![];
+        let mut handles = vec![];
+        for fh in fhs {
+            match self.resolve(&fh.fh, peer) {
+                Some((e, p)) => {
+                    exports.push(e);
+                    handles.push((fh, p));
+                }
+                None => {
+                    info!(%peer, procid = call.procid, "nfs2: not forwarding, handle refused");
+                    return Some(nfs_err(call.xid, NFSERR_STALE)).into();
+                }
+            }
+        }
+
+        // RENAME and LINK carry one credential for both handles, so they
+        // must share the export whose squash and id maps it is made with
+        if exports.windows(2).any(|w| w[0].id() != w[1].id()) {
+            info!(%peer, procid = call.procid, "nfs2: not forwarding, handles in two exports");
+            return Some(nfs_err(call.xid, NFSERR_XDEV)).into();
+        }
+        // CREATE through RMDIR
+        if (9..=15).contains(&call.procid) && exports.iter().any(|e| e.read_only) {
+            return Some(nfs_err(call.xid, NFSERR_ROFS)).into();
+        }
+
+        let export = exports
+            .first()
+            .map(|e| ((*e).clone(), export_auth(e, peer)));
+        let relayed = match &export {
+            Some((_, auth)) => with_cred(buf, ofs, auth),
+            None => buf.to_vec(),
+        };
+        let relayed = Relayed {
+            xid: call.xid,
+            procid: call.procid,
+            args: relayed.len() - (buf.len() - ofs),
+            call: relayed,
+            handles,
+            export,
+            peer: peer.clone(),
+        };
+        Reply::Pending(Box::pin(
+            forward(
+                up.clone(),
+                relayed,
+                self.env.clone(),
+                self.fh_key.clone(),
+                self.slow.clone(),
+            )
+            .in_current_span(),
+        ))
+    }
+
+    fn dispatch(&self, call: &RpcCall, args: &[u8], peer: &PeerInfo) -> Vec<u8> {
+        let mut r = XdrR::new(args);
+
+        match call.procid {
+            // NULL
+            0 => rpc_accept_header(call.xid, SUCCESS).into_vec(),
+
+            // GETATTR
+            1 => {
+                let mut fh = r.get_fixed(FH_SIZE).unwrap_or_default();
+
+                if fh.is_empty() {
+                    match self.root_fallback(peer) {
+                        Some(root_fh) => fh = root_fh,
+                        None => return nfs_err(call.xid, NFSERR_STALE),
+                    }
+                }
+                let mut w = rpc_accept_header(call.xid, SUCCESS);
+
+                info!(
+                    "nfs2: GETATTR raw file handle fh_len={}, fh_hex={}",
+                    fh.len(),
+                    hex::encode(&fh)
+                );
+                if let Some((export, p)) = self.resolve(&fh, peer) {
+                    debug!("nfs2: GETATTR resolved path={}", p.display());
+                    if let Ok(meta) = export.metadata(&p) {
+                        info!(
+                            %peer,
+                            path = %p.display(),
+                            size = meta.len(),
+                            ino = meta.ino(),
+                            mode = format_args!("{:o}", meta.mode()),
+                            "nfs2: GETATTR metadata"
+                        );
+                        w.put_u32(NFS_OK);
+                        put_fattr(&mut w, &meta, &p, export, &self.env);
+                    } else {
+                        w.put_u32(NFSERR_NOENT);
+                        // Log meta failure
+                        info!(%peer, path = %p.display(), "nfs2: GETATTR metadata failed");
+                    }
+                } else {
+                    w.put_u32(NFSERR_STALE);
+                }
+
+                w.into_vec()
+            }
+
+            // SETATTR
+            2 => {
+                let (Ok(fh), Ok(sa)) = (r.get_fixed(FH_SIZE), get_sattr(&mut r)) else {
+                    warn!(%peer, "nfs2: malformed SETATTR arguments");
+                    return rpc_accept_reply(call.xid, GARBAGE_ARGS, &[]);
+                };
+
+                let mut w = rpc_accept_header(call.xid, SUCCESS);
+
+                match self.resolve(&fh, peer) {
+                    None => w.put_u32(NFSERR_STALE),
+                    Some((export, _)) if export.read_only => w.put_u32(NFSERR_ROFS),
+                    Some((export, p)) => match export.metadata(&p) {
+                        Err(e) => w.put_u32(nfs_status(&e)),
+                        // chmod and friends would act on the target
+                        Ok(meta) if meta.is_symlink() => {
+                            info!(%peer, path = %p.display(), "nfs2: SETATTR refused on symlink");
+                            w.put_u32(NFSERR_NXIO);
+                        }
+                        Ok(meta) => match setattr_status(export, &meta, &export.cred(peer), &sa) {
+                            NFS_OK => {
+                                let res = set_attrs(&p, export, &sa);
+                                if sa.size.is_some() {
+                                    self.read_ahead.forget(&meta);
+                                }
+                                match res.and_then(|_| fs::metadata(&p)) {
+                                    Ok(meta) => {
+                                        debug!(%peer, path = %p.display(), ?sa, "nfs2: SETATTR");
+                                        bump_change_id(export, &meta);
+                                        w.put_u32(NFS_OK);
+                                        put_fattr(&mut w, &meta, &p, export, &self.env);
+                                    }
+                                    Err(e) => {
+                                        info!(%peer, path = %p.display(), ?e, "nfs2: SETATTR failed");
+                                        w.put_u32(nfs_status(&e));
+                                    }
+                                }
+                            }
+                            status => {
+                                info!(%peer, path = %p.display(), cred = ?peer.cred, status, "nfs2: SETATTR refused");
+                                w.put_u32(status);
+                            }
+                        },
+                    },
+                }
+
+                w.into_vec()
+            }
+
+            // LOOKUP
+            4 => {
+                info!(
+                    %peer,
+                    vers = call.vers,
+                    cred = ?peer.cred,
                     "nfs2: LOOKUP entered"
                 );
-                let dirfh = r.get_opaque().unwrap_or_default();
+                let dirfh = r.get_fixed(FH_SIZE).unwrap_or_default();
                 let name = r.get_string().unwrap_or_default();
-                let mut w = XdrW::new();
+                let mut w = rpc_accept_header(call.xid, SUCCESS);
 
                 info!(
-                    peer,
+                    %peer,
                     "nfs2: LOOKUP start fh_len={} fh_hex={} name='{}'",
                     dirfh.len(),
                     hex::encode(&dirfh),
                     name
                 );
 
-                if let Some(dir) = path_from_fh(root, &dirfh) {
+                if !valid_name(&name) {
+                    info!(%peer, name, "nfs2: LOOKUP refused name");
+                    w.put_u32(NFSERR_ACCES);
+                } else if let Some((export, dir)) = self.resolve(&dirfh, peer) {
                     let p = dir.join(&name);
 
                     info!(
-                        peer,
+                        %peer,
                         "nfs2: LOOKUP resolved dir='{}' path='{}'",
                         dir.display(),
                         p.display()
                     );
 
-                    if let Ok(meta) = fs::metadata(&p) {
+                    if name_too_long(export, &name) {
+                        info!(%peer, name, "nfs2: LOOKUP name over max_name_len");
+                        w.put_u32(NFSERR_NAMETOOLONG);
+                    } else if !export.contains(&p) {
+                        // through a symlinked directory to outside the export
+                        info!(%peer, path = %p.display(), "nfs2: LOOKUP outside export");
+                        w.put_u32(NFSERR_ACCES);
+                    } else if let Ok(meta) = export.metadata(&p) {
                         info!(
-                            peer,
+                            %peer,
                             "nfs2: LOOKUP success path='{}' mode={:o} ino={}",
                             p.display(),
                             meta.mode(),
                             meta.ino()
                         );
 
+                        self.handles.insert_meta(&meta, &p);
                         w.put_u32(NFS_OK);
-                        w.put_opaque(&fh_from_path(&p));
-                        put_fattr(&mut w, &meta, &p);
+                        w.put_fixed(&fh_from_path(&self.fh_key, export, &p));
+                        put_fattr(&mut w, &meta, &p, export, &self.env);
                     } else {
-                        info!(peer, "nfs2: LOOKUP metadata failed path='{}'", p.display());
+                        info!(%peer, "nfs2: LOOKUP metadata failed path='{}'", p.display());
                         w.put_u32(NFSERR_NOENT);
                     }
                 } else {
                     info!(
-                        peer,
+                        %peer,
                         "nfs2: LOOKUP invalid dirfh fh_hex={}",
                         hex::encode(&dirfh)
                     );
-                    w.put_u32(NFSERR_NOENT);
+                    w.put_u32(NFSERR_STALE);
+                }
+
+                info!(%peer, "nfs2: LOOKUP end");
+
+                w.into_vec()
+            }
+
+            // READLINK
+            5 => {
+                let Ok(fh) = r.get_fixed(FH_SIZE) else {
+                    warn!(%peer, "nfs2: malformed READLINK arguments");
+                    return rpc_accept_reply(call.xid, GARBAGE_ARGS, &[]);
+                };
+
+                let mut w = rpc_accept_header(call.xid, SUCCESS);
+
+                match self.resolve(&fh, peer) {
+                    None => w.put_u32(NFSERR_STALE),
+                    Some((export, p)) => match export.metadata(&p) {
+                        Err(e) => w.put_u32(nfs_status(&e)),
+                        // also a link the export presents as its target
+                        Ok(meta) if !meta.is_symlink() => {
+                            info!(%peer, path = %p.display(), "nfs2: READLINK on a non-link");
+                            w.put_u32(NFSERR_NXIO);
+                        }
+                        // The target goes back verbatim; the client
+                        // resolves it, as with any NFS symlink.
+                        Ok(_) => match fs::read_link(&p) {
+                            Ok(target) if target.as_os_str().len() > NFS_MAXPATHLEN => {
+                                w.put_u32(NFSERR_NAMETOOLONG)
+                            }
+                            Ok(target) => {
+                                debug!(%peer, path = %p.display(), target = %target.display(), "nfs2: READLINK");
+                                w.put_u32(NFS_OK);
+                                w.put_opaque(target.as_os_str().as_encoded_bytes());
+                            }
+                            Err(e) => w.put_u32(nfs_status(&e)),
+                        },
+                    },
+                }
+
+                w.into_vec()
+            }
+
+            // READ
+            6 => {
+                let fh = r.get_fixed(FH_SIZE).unwrap_or_default();
+                let offset = r.get_u32().unwrap_or(0) as u64;
+                let count = (r.get_u32().unwrap_or(0) as usize).min(NFS_MAXDATA);
+                let _totalcount = r.get_u32().unwrap_or(0);
+
+                let mut w = rpc_accept_header(call.xid, SUCCESS);
+
+                if let Some((export, p)) = self.resolve(&fh, peer) {
+                    match export.metadata(&p) {
+                        Ok(meta) => {
+                            let ft = meta.file_type();
+                            if ft.is_dir() {
+                                // e.g. a file handle whose inode was reused by a directory
+                                info!(%peer, path = %p.display(), "nfs2: READ on a directory");
+                                w.put_u32(NFSERR_ISDIR);
+                            } else if ft.is_block_device()
+                                || ft.is_char_device()
+                                || ft.is_fifo()
+                                || ft.is_socket()
+                                || ft.is_symlink()
+                            {
+                                // Never open host devices, nor follow a link the
+                                // export reports as a link.
+                                info!(%peer, path = %p.display(), "nfs2: READ refused on special file");
+                                w.put_u32(NFSERR_NXIO);
+                            } else if !allowed(export, peer, &meta, MAY_READ) {
+                                info!(%peer, path = %p.display(), cred = ?peer.cred, "nfs2: READ access denied");
+                                w.put_u32(NFSERR_ACCES);
+                            } else {
+                                match self.read_data(export, &p, &meta, peer, offset, count) {
+                                    Ok(data) => {
+                                        debug!(%peer, path = %p.display(), offset, count, n = data.len(), "nfs2: READ");
+                                        METRICS
+                                            .read_bytes
+                                            .fetch_add(data.len() as u64, Ordering::Relaxed);
+                                        w.put_u32(NFS_OK);
+                                        put_fattr(&mut w, &meta, &p, export, &self.env);
+                                        w.put_opaque(&data);
+                                    }
+                                    Err(e) => {
+                                        info!(%peer, path = %p.display(), ?e, "nfs2: READ failed");
+                                        w.put_u32(nfs_status(&e));
+                                    }
+                                }
+                            }
+                        }
+                        Err(e) => w.put_u32(nfs_status(&e)),
+                    }
+                } else {
+                    w.put_u32(NFSERR_STALE);
                 }
 
-                info!(peer, "nfs2: LOOKUP end");
+                w.into_vec()
+            }
+
+            // WRITE
+            8 => {
+                let Ok(WriteArgs { fh, offset, data }) = WriteArgs::decode(&mut r) else {
+                    warn!(%peer, "nfs2: malformed WRITE arguments");
+                    return rpc_accept_reply(call.xid, GARBAGE_ARGS, &[]);
+                };
+
+                let mut w = rpc_accept_header(call.xid, SUCCESS);
+
+                match self.resolve(&fh, peer) {
+                    None => w.put_u32(NFSERR_STALE),
+                    Some((export, _)) if export.read_only => w.put_u32(NFSERR_ROFS),
+                    Some((export, p)) => match export.metadata(&p) {
+                        Err(e) => w.put_u32(nfs_status(&e)),
+                        Ok(meta) if meta.is_dir() => {
+                            info!(%peer, path = %p.display(), "nfs2: WRITE on a directory");
+                            w.put_u32(NFSERR_ISDIR);
+                        }
+                        Ok(meta) if !meta.is_file() => {
+                            info!(%peer, path = %p.display(), "nfs2: WRITE refused on special file");
+                            w.put_u32(NFSERR_NXIO);
+                        }
+                        Ok(meta) if !allowed(export, peer, &meta, MAY_WRITE) => {
+                            info!(%peer, path = %p.display(), cred = ?peer.cred, "nfs2: WRITE access denied");
+                            w.put_u32(NFSERR_ACCES);
+                        }
+                        // NFSv2 offsets and sizes are 32 bit
+                        Ok(_) if offset + data.len() as u64 > u32::MAX as u64 => {
+                            w.put_u32(NFSERR_FBIG)
+                        }
+                        Ok(meta) if over_cap(export, meta.len(), offset + data.len() as u64) => {
+                            info!(%peer, path = %p.display(), offset, n = data.len(), "nfs2: WRITE past max_file_size");
+                            w.put_u32(NFSERR_FBIG)
+                        }
+                        Ok(meta) => {
+                            let res = write_sync(&self.env, &p, &data, offset);
+                            self.read_ahead.forget(&meta);
+                            match res.and_then(|_| fs::metadata(&p)) {
+                                Ok(meta) => {
+                                    debug!(%peer, path = %p.display(), offset, n = data.len(), "nfs2: WRITE");
+                                    bump_change_id(export, &meta);
+                                    METRICS
+                                        .write_bytes
+                                        .fetch_add(data.len() as u64, Ordering::Relaxed);
+                                    w.put_u32(NFS_OK);
+                                    put_fattr(&mut w, &meta, &p, export, &self.env);
+                                }
+                                Err(e) => {
+                                    warn!(%peer, path = %p.display(), ?e, "nfs2: WRITE failed");
+                                    w.put_u32(nfs_status(&e));
+                                }
+                            }
+                        }
+                    },
+                }
 
-                rpc_accept_reply(call.xid, 0, &w.buf)
+                w.into_vec()
             }
 
             // READDIR
             16 => {
-                let mut fh = r.get_opaque().unwrap_or_default();
+                let mut fh = r.get_fixed(FH_SIZE).unwrap_or_default();
 
                 if fh.is_empty() {
-                    if let Some((_, root_fh)) = self.mounts.lock().unwrap().iter().next() {
-                        fh = root_fh.clone();
-                    } else {
-                        return Some(nfs_err(NFSERR_STALE));
+                    match self.root_fallback(peer) {
+                        Some(root_fh) => fh = root_fh,
+                        None => return nfs_err(call.xid, NFSERR_STALE),
                     }
                 }
 
                 let cookie = r.get_u32().unwrap_or(0);
                 let count = r.get_u32().unwrap_or(0) as usize;
 
-                let mut w = XdrW::new();
+                let mut w = rpc_accept_header(call.xid, SUCCESS);
+                // the client's count covers the result, not our RPC header
+                let header = w.buf.len();
 
                 info!(
                     "nfs2: READDIR raw file handle fh_len={}, fh_hex={}",
                     fh.len(),
                     hex::encode(&fh)
                 );
-                if let Some(dir) = path_from_fh(root, &fh) {
+                if let Some((export, dir)) = self.resolve(&fh, peer) {
                     debug!("nfs2: READDIR resolved dir={}", dir.display());
-                    if let Ok(rd) = fs::read_dir(&dir) {
+                    if export.no_readdir {
+                        // opaque export: names can be looked up, not listed
+                        info!(%peer, dir = %dir.display(), "nfs2: READDIR refused (no_readdir)");
+                        w.put_u32(NFSERR_ACCES);
+                    } else if export.metadata(&dir).is_ok_and(|m| !m.is_dir()) {
+                        // e.g. a directory handle whose inode was reused by a file
+                        info!(%peer, dir = %dir.display(), "nfs2: READDIR on a non-directory");
+                        w.put_u32(NFSERR_NOTDIR);
+                    } else if let Ok(rd) = self.env.read_dir(&dir) {
                         w.put_u32(NFS_OK);
 
                         // If client sends 0, pick a sane cap to avoid giant replies.
@@ -362,41 +1810,98 @@ impl Nfs2 {
                         let max_bytes = if count == 0 { 4096 } else { count };
 
                         let mut idx = 0u32;
+                        let mut emitted = 0u32;
                         let mut eof = true;
-
-                        for e in rd.flatten() {
+                        let mut failed = None;
+
+                        for e in rd {
+                            // The listing itself broke: what we have is
+                            // not the whole directory.
+                            let path = match e {
+                                Ok(path) => path,
+                                Err(e) => {
+                                    failed = Some(e);
+                                    break;
+                                }
+                            };
+                            // cookie n resumes at position n; 0 skips nothing
                             if idx < cookie {
                                 idx += 1;
                                 continue;
                             }
 
-                            let name = e.file_name().to_string_lossy().into_owned();
-                            let ino = e.metadata().map(|m| m.ino() as u32).unwrap_or(0);
-
-                            // Estimate how many bytes this entry will add in XDR.
-                            // entry = bool(4) + fileid(4) + string(len+pad+4) + cookie(4)
-                            // string encoding = u32 len + bytes + padding
-                            //let name_len = name.as_bytes().len();
-                            let name_len = name.len();
-                            let name_pad = (4 - (name_len % 4)) % 4;
-                            let entry_bytes = 4 + 4 + (4 + name_len + name_pad) + 4;
-
-                            // +8 for end markers (final 0 + eof bool) to keep room
-                            if w.buf.len() + entry_bytes + 8 > max_bytes {
-                                eof = false;
-                                break;
+                            let name = path
+                                .file_name()
+                                .unwrap_or_default()
+                                .to_string_lossy()
+                                .into_owned();
+                            if !readdir_visible(export, &name) {
+                                idx += 1;
+                                continue;
+                            }
+                            // Clients usually stat every listed name next;
+                            // remember where each inode lives so those
+                            // handles resolve without a walk. `dir` passed
+                            // the containment check in resolve() and
+                            // read_dir never yields "." or "..", so every
+                            // entry is inside the export. An entry that
+                            // vanished since the listing is skipped.
+                            let ino = match export.metadata(&path) {
+                                Ok(m) => {
+                                    self.handles.insert_meta(&m, &path);
+                                    m.ino() as u32
+                                }
+                                Err(err) => {
+                                    debug!(name, ?err, "nfs2: READDIR skipping entry");
+                                    idx += 1;
+                                    continue;
+                                }
+                            };
+
+                            let entry_bytes = dirent_bytes(&name);
+
+                            // +8 for end markers (final 0 + eof bool) to keep room.
+                            // The first entry is always sent, even over budget:
+                            // an empty non-EOF reply would have the client ask
+                            // for the same cookie forever.
+                            if w.buf.len() - header + entry_bytes + 8 > max_bytes {
+                                if emitted > 0 {
+                                    eof = false;
+                                    break;
+                                }
+                                debug!(
+                                    count,
+                                    entry_bytes, "nfs2: READDIR count too small, sending one entry"
+                                );
                             }
 
                             w.put_u32(1); // entry follows
                             w.put_u32(ino); // fileid
                             w.put_string(&name); // filename
-                            w.put_u32(idx + 1); // cookie for next call
+                            w.put_u32(resume_cookie(idx)); // cookie for next call
                             idx += 1;
+                            emitted += 1;
                         }
 
-                        w.put_u32(0); // end of entry list
-                        w.put_u32(if eof { 1 } else { 0 }); // EOF flag
-                        debug!("nfs2: READDIR reply={:?}", w.buf);
+                        match failed {
+                            // nothing to hand back, and an empty non-EOF
+                            // reply would loop the client
+                            Some(e) if emitted == 0 => {
+                                warn!(%peer, dir = %dir.display(), ?e, "nfs2: READDIR failed");
+                                w = rpc_accept_header(call.xid, SUCCESS);
+                                w.put_u32(nfs_status(&e));
+                            }
+                            failed => {
+                                if let Some(e) = failed {
+                                    // partial listing; the client asks again from here
+                                    warn!(%peer, dir = %dir.display(), ?e, emitted, "nfs2: READDIR cut short");
+                                    eof = false;
+                                }
+                                w.put_u32(0); // end of entry list
+                                w.put_u32(if eof { 1 } else { 0 }); // EOF flag
+                                debug!("nfs2: READDIR reply={:?}", &w.buf[header..]);
+                            }
+                        }
                     } else {
                         w.put_u32(NFSERR_NOENT);
                         debug!("nfs2: READDIR no entry");
@@ -405,92 +1910,1662 @@ impl Nfs2 {
                     w.put_u32(NFSERR_STALE);
                 }
                 info!(
-                    peer,
+                    %peer,
                     cookie,
                     count,
-                    reply_size = w.buf.len(),
+                    reply_size = w.buf.len() - header,
                     "nfs2: READDIR reply"
                 );
-                rpc_accept_reply(call.xid, 0, &w.buf)
+                w.into_vec()
             }
 
             _ => {
-                warn!(peer, procid = call.procid, "nfs2: unimplemented proc");
-                let w = XdrW::new();
-                rpc_accept_reply(call.xid, 0, &w.buf)
+                warn!(
+                    %peer,
+                    procid = call.procid,
+                    "nfs2: proc listed in NFS_SUPPORTED but not dispatched"
+                );
+                rpc_accept_reply(call.xid, PROC_UNAVAIL, &[])
             }
-        };
-
-        Some(reply)
+        }
     }
 
     // --------------------------------------------------------
     // UDP server
     // --------------------------------------------------------
 
-    pub async fn run_udp(self, sock: UdpSocket) {
-        let mut buf = vec![0u8; 65536];
+    pub async fn run_udp(self, sock: UdpSocket, stop: Shutdown, queue: FsQueue) {
         info!("nfsd listening (UDP)");
 
-        loop {
-            let Ok((n, peer)) = sock.recv_from(&mut buf).await else {
-                continue;
-            };
-
-            let peer_s = peer.to_string();
+        udp::serve(sock, stop, queue, move |buf, peer| {
+            self.handle_call(buf, peer)
+        })
+        .await;
 
-            if let Some(reply) = self.handle_call(&buf[..n], &peer_s) {
-                let _ = sock.send_to(&reply, peer).await;
-            }
-        }
+        info!("nfsd stopped (UDP)");
     }
 
     // --------------------------------------------------------
     // TCP server (record-marked)
     // --------------------------------------------------------
 
-    pub async fn run_tcp(self, listener: TcpListener) {
+    pub async fn run_tcp(
+        self,
+        listener: TcpListener,
+        stop: Shutdown,
+        queue: FsQueue,
+        max_inflight: usize,
+    ) {
         info!("nfsd listening (TCP)");
 
         loop {
-            let (mut stream, peer) = match listener.accept().await {
-                Ok(v) => v,
-                Err(_) => continue,
+            let (stream, peer) = tokio::select! {
+                _ = stop.stopped() => break,
+                res = listener.accept() => match res {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                },
             };
 
             let this = self.clone();
-            let peer_s = peer.to_string();
+            let stop = stop.clone();
+            let queue = queue.clone();
+            let conn_id = next_conn_id();
 
-            info!("nfs2 TCP connected peer={}", peer_s);
+            info!(conn_id, "nfs2 TCP connected peer={}", peer);
 
-            tokio::spawn(async move {
-                loop {
-                    let mut hdr = [0u8; 4];
-                    if stream.read_exact(&mut hdr).await.is_err() {
-                        break;
-                    }
+            tokio::spawn(
+                async move {
+                    let _conn = Gauge::inc(&METRICS.nfs_connections);
 
-                    let marker = u32::from_be_bytes(hdr);
-                    let len = (marker & 0x7fff_ffff) as usize;
+                    tcp::serve(stream, stop, queue, max_inflight, move |buf| {
+                        this.handle_call(buf, peer)
+                    })
+                    .await;
 
-                    let mut buf = vec![0u8; len];
-                    if stream.read_exact(&mut buf).await.is_err() {
-                        break;
-                    }
+                    info!("nfs2 TCP disconnected peer={}", peer);
+                }
+                .instrument(info_span!("tcp", conn_id, %peer)),
+            );
+        }
 
-                    if let Some(reply) = this.handle_call(&buf, &peer_s) {
-                        let mut out = Vec::with_capacity(4 + reply.len());
-                        out.extend_from_slice(&(0x8000_0000u32 | reply.len() as u32).to_be_bytes());
-                        out.extend_from_slice(&reply);
+        info!("nfsd stopped (TCP)");
+    }
+}
 
-                        if stream.write_all(&out).await.is_err() {
-                            break;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::RpcAuth;
+    use crate::testutil::{CLIENT, TempDir, accepted, call, export, tar, warnings};
+
+    fn server(exports: Vec<Export>) -> Nfs2 {
+        Nfs2::new(
+            Exports::new(exports),
+            MountTable::default(),
+            FhKey::default(),
+            DisabledProcs::default(),
+            Env::default(),
+            NFS_PROG,
+            FsQueue::default(),
+        )
+    }
+
+    /// Run one call as `uid` and return the reply.
+    fn nfs(s: &Nfs2, procid: u32, uid: u32, args: &[u8]) -> Vec<u8> {
+        match s.handle_call(&call(NFS_PROG, NFS_VERS, procid, Some(uid), args), CLIENT) {
+            Reply::Ready(Some(reply)) => reply,
+            _ => panic!("no reply on the spot"),
+        }
+    }
+
+    /// NFS status of a reply, leaving `r` at the result body.
+    fn status(reply: &[u8]) -> (u32, XdrR<'_>) {
+        let (stat, mut r) = accepted(reply);
+        assert_eq!(stat, SUCCESS);
+        (r.get_u32().unwrap(), r)
+    }
+
+    fn fattr(r: &mut XdrR) -> [u32; 17] {
+        std::array::from_fn(|_| r.get_u32().unwrap())
+    }
+
+    fn fh_args(fh: &[u8]) -> Vec<u8> {
+        let mut w = XdrW::new();
+        w.put_fixed(fh);
+        w.into_vec()
+    }
+
+    fn read_args(fh: &[u8], offset: u32, count: u32) -> Vec<u8> {
+        let mut w = XdrW::new();
+        w.put_fixed(fh);
+        w.put_u32(offset);
+        w.put_u32(count);
+        w.put_u32(0);
+        w.into_vec()
+    }
+
+    #[test]
+    fn read_full_assembles_short_reads() {
+        let dir = TempDir::new();
+        let p = dir.path().join("f");
+        fs::write(&p, b"0123456789").unwrap();
+        let f = fs::File::open(&p).unwrap();
+        let env = Env::default().chunked_reads(1);
+
+        let mut buf = [0; 8];
+        assert_eq!(read_full(&env, &f, &mut buf, 1).unwrap(), 8);
+        assert_eq!(&buf, b"12345678");
+
+        // at the end: exactly what is left, not a partial zero fill
+        let mut buf = [0xff; 8];
+        assert_eq!(read_full(&env, &f, &mut buf, 6).unwrap(), 4);
+        assert_eq!(&buf, b"6789\xff\xff\xff\xff");
+        assert_eq!(read_full(&env, &f, &mut buf, 10).unwrap(), 0);
+    }
+
+    #[test]
+    fn read_reply_is_whole_despite_short_reads() {
+        let dir = TempDir::new();
+        let f = dir.path().join("f");
+        let data: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        fs::write(&f, &data).unwrap();
+        let e = export(dir.path());
+        let mut s = server(vec![e.clone()]);
+        s.env = Env::default().chunked_reads(7);
+        let fh = fh_from_path(&FhKey::default(), &e, &f);
+
+        let reply = nfs(&s, 6, 0, &read_args(&fh, 100, 4096));
+        let (st, mut r) = status(&reply);
+        assert_eq!(st, NFS_OK);
+        fattr(&mut r);
+        assert_eq!(r.get_opaque().unwrap(), &data[100..4196]);
+    }
+
+    #[test]
+    fn unknown_program_is_prog_unavail() {
+        let s = server(Vec::new());
+        let reply = match s.handle_call(&call(100099, 1, 0, None, &[]), CLIENT) {
+            Reply::Ready(Some(reply)) => reply,
+            _ => panic!("unknown program dropped"),
+        };
+        assert_eq!(reply[..4], 0x1234_5678u32.to_be_bytes(), "xid");
+        assert_eq!(accepted(&reply).0, PROG_UNAVAIL);
+        assert_eq!(reply.len(), 24, "no result body");
+    }
+
+    /// WRITE of "hello" at 8192, laid out as the Linux client sends it:
+    /// beginoffset repeats the offset and totalcount the data length.
+    const LINUX_WRITE: [u8; 60] = [
+        // fhandle
+        0x00, 0x00, 0x08, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x2a, 0x51, 0x07, 0x4e, 0x9b, 0x3c,
+        0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, //
+        0x00, 0x00, 0x20, 0x00, // beginoffset
+        0x00, 0x00, 0x20, 0x00, // offset
+        0x00, 0x00, 0x00, 0x05, // totalcount
+        0x00, 0x00, 0x00, 0x05, b'h', b'e', b'l', b'l', b'o', 0x00, 0x00, 0x00, // data
+        // the next record, if the reader overran
+        0xde, 0xad, 0xbe, 0xef,
+    ];
+
+    #[test]
+    fn write_args_skip_beginoffset_and_totalcount() {
+        let mut r = XdrR::new(&LINUX_WRITE);
+        let args = WriteArgs::decode(&mut r).unwrap();
+        assert_eq!(args.fh, LINUX_WRITE[..FH_SIZE]);
+        assert_eq!(args.offset, 8192);
+        assert_eq!(args.data, b"hello");
+        assert_eq!(r.get_u32().unwrap(), 0xdead_beef);
+
+        // the offset slot counts, whatever beginoffset says
+        let mut other = LINUX_WRITE;
+        other[32..36].copy_from_slice(&[0xff; 4]);
+        other[40..44].copy_from_slice(&[0; 4]);
+        let args = WriteArgs::decode(&mut XdrR::new(&other)).unwrap();
+        assert_eq!((args.offset, args.data.as_slice()), (8192, &b"hello"[..]));
+
+        assert!(WriteArgs::decode(&mut XdrR::new(&LINUX_WRITE[..44])).is_err());
+    }
+
+    #[test]
+    fn rdev_uses_linux_encoding() {
+        assert_eq!(nfs_rdev(libc::makedev(1, 3)), 0x103);
+        assert_eq!(nfs_rdev(libc::makedev(0x123, 0x45678)), 0x4561_2378);
+    }
+
+    #[test]
+    fn riscos_load_from_stamp_or_filetype() {
+        let stamped = [0x00, 0x1b, 0xf1, 0xff, 0x10, 0x20, 0x30, 0x40];
+        assert_eq!(riscos_load_value(&stamped), Some(0xfff1_1b00));
+        assert_eq!(riscos_load_value(b"ffb"), Some(0xffff_fb00));
+        assert_eq!(riscos_load_value(b"ffb\0"), Some(0xffff_fb00));
+        for bad in [&b""[..], b"ff", b"xyz", b"0ffb", &stamped[..7]] {
+            assert_eq!(riscos_load_value(bad), None, "{bad:?}");
+        }
+    }
+
+    #[test]
+    #[ignore = "needs user xattrs on the temp dir's filesystem"]
+    fn riscos_xattr_is_reported_in_rdev() {
+        const ATTR: &str = "user.riscos.load";
+        let dir = TempDir::new();
+        let (stamped, typed, plain) = (
+            dir.path().join("stamped"),
+            dir.path().join("typed"),
+            dir.path().join("plain"),
+        );
+        for f in [&stamped, &typed, &plain] {
+            fs::write(f, b"").unwrap();
+        }
+        let load_exec = [0x00, 0x1b, 0xf1, 0xff, 0x10, 0x20, 0x30, 0x40];
+        xattr::set(&stamped, ATTR, &load_exec).unwrap();
+        xattr::set(&typed, ATTR, b"ffb").unwrap();
+
+        let e = Export {
+            riscos_xattr: Some(ATTR.into()),
+            ..export(dir.path())
+        };
+        let s = server(vec![e.clone()]);
+        let rdev = |p: &Path| {
+            let reply = nfs(&s, 1, 0, &fh_args(&fh_from_path(&FhKey::default(), &e, p)));
+            let (st, mut r) = status(&reply);
+            assert_eq!(st, NFS_OK);
+            fattr(&mut r)[7]
+        };
+        assert_eq!(rdev(&stamped), 0xfff1_1b00);
+        assert_eq!(rdev(&typed), 0xffff_fb00);
+        assert_eq!(rdev(&plain), 0);
+    }
+
+    #[test]
+    fn char_device_reports_rdev_and_refuses_read() {
+        // the system's own null device (1, 3), so no mknod and no root
+        let dev = Path::new("/dev/null");
+        let e = export(Path::new("/dev"));
+        let s = server(vec![e.clone()]);
+        let fh = fh_from_path(&FhKey::default(), &e, dev);
+
+        let reply = nfs(&s, 1, 0, &fh_args(&fh));
+        let (st, mut r) = status(&reply);
+        assert_eq!(st, NFS_OK);
+        let a = fattr(&mut r);
+        assert_eq!(a[0], NFCHR);
+        assert_eq!(a[1] & 0o170000, 0o020000);
+        assert_eq!(a[7], 0x103);
+
+        let reply = nfs(&s, 6, 0, &read_args(&fh, 0, 16));
+        assert_eq!(status(&reply).0, NFSERR_NXIO);
+    }
+
+    #[test]
+    fn permission_checks_use_mapped_ids() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let dir = TempDir::new();
+        let f = dir.path().join("f");
+        fs::write(&f, b"data").unwrap();
+        fs::set_permissions(&f, fs::Permissions::from_mode(0o600)).unwrap();
+        // map a client uid onto whoever owns the file: us
+        let host = fs::metadata(&f).unwrap().uid();
+        let client = host.wrapping_add(1000);
+
+        let mut e = export(dir.path());
+        e.anon_uid = u32::MAX - 2;
+        e.anon_gid = u32::MAX - 2;
+        e.uid_map = crate::export::IdMap::parse(&[format!("{client}:{host}:1")]).unwrap();
+        let s = server(vec![e.clone()]);
+        let fh = fh_from_path(&FhKey::default(), &e, &f);
+
+        let reply = nfs(&s, 6, client, &read_args(&fh, 0, 4));
+        let (st, mut r) = status(&reply);
+        assert_eq!(st, NFS_OK);
+        assert_eq!(fattr(&mut r)[3], client, "owner shown in client terms");
+
+        // the host uid on the wire is not the host's uid
+        let reply = nfs(&s, 6, host, &read_args(&fh, 0, 4));
+        assert_eq!(status(&reply).0, NFSERR_ACCES);
+    }
+
+    /// CREATE is relayed upstream, so the file is made here by the test
+    /// process, whose ids the client's map to; the data and owner then
+    /// round-trip over WRITE and READ in client terms.
+    #[test]
+    fn mapped_owner_writes_and_reads_back() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let dir = TempDir::new();
+        let f = dir.path().join("f");
+        fs::write(&f, b"").unwrap();
+        fs::set_permissions(&f, fs::Permissions::from_mode(0o600)).unwrap();
+        let meta = fs::metadata(&f).unwrap();
+        let (uid, gid) = (meta.uid(), meta.gid());
+        // test calls send gid = uid, so one client id maps onto both
+        let client = uid.max(gid).wrapping_add(1000);
+
+        let mut e = export(dir.path());
+        e.uid_map = crate::export::IdMap::parse(&[format!("{client}:{uid}:1")]).unwrap();
+        e.gid_map = crate::export::IdMap::parse(&[format!("{client}:{gid}:1")]).unwrap();
+        let s = server(vec![e.clone()]);
+        let fh = fh_from_path(&FhKey::default(), &e, &f);
+
+        let reply = nfs(&s, 8, client, &write_args(&fh, 0, b"mapped"));
+        let (st, mut r) = status(&reply);
+        assert_eq!(st, NFS_OK);
+        let a = fattr(&mut r);
+        assert_eq!((a[3], a[4], a[5]), (client, client, 6));
+
+        let reply = nfs(&s, 6, client, &read_args(&fh, 0, 16));
+        let (st, mut r) = status(&reply);
+        assert_eq!(st, NFS_OK);
+        assert_eq!(fattr(&mut r)[3], client);
+        assert_eq!(r.get_opaque().unwrap(), b"mapped");
+
+        let meta = fs::metadata(&f).unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (uid, gid), "host ids unchanged");
+    }
+
+    fn readdir_args(fh: &[u8], cookie: u32, count: u32) -> Vec<u8> {
+        let mut w = XdrW::new();
+        w.put_fixed(fh);
+        w.put_u32(cookie);
+        w.put_u32(count);
+        w.into_vec()
+    }
+
+    /// Entries of a READDIR reply as (name, cookie), and its EOF flag.
+    fn entries(r: &mut XdrR) -> (Vec<(String, u32)>, bool) {
+        let mut v = Vec::new();
+        while r.get_u32().unwrap() == 1 {
+            r.get_u32().unwrap(); // fileid
+            let name = r.get_string().unwrap();
+            v.push((name, r.get_u32().unwrap()));
+        }
+        (v, r.get_u32().unwrap() == 1)
+    }
+
+    #[test]
+    fn readdir_count_below_one_entry_still_makes_progress() {
+        let dir = TempDir::new();
+        for n in ["a-rather-long-file-name-one", "a-rather-long-file-name-two"] {
+            fs::write(dir.path().join(n), b"").unwrap();
+        }
+        let e = export(dir.path());
+        let s = server(vec![e.clone()]);
+        let fh = fh_from_path(&FhKey::default(), &e, dir.path());
+
+        let mut cookie = 0;
+        let mut seen = Vec::new();
+        loop {
+            let reply = nfs(&s, 16, 0, &readdir_args(&fh, cookie, 16));
+            let (st, mut r) = status(&reply);
+            assert_eq!(st, NFS_OK);
+            let (list, eof) = entries(&mut r);
+            assert_eq!(list.len(), 1, "exactly one entry per tiny reply");
+            cookie = list[0].1;
+            seen.push(list[0].0.clone());
+            if eof {
+                break;
+            }
+            assert!(seen.len() < 3, "client would loop");
+        }
+        seen.sort();
+        assert_eq!(
+            seen,
+            ["a-rather-long-file-name-one", "a-rather-long-file-name-two"]
+        );
+    }
+
+    #[test]
+    fn readdir_cookie_zero_mid_listing_restarts() {
+        let dir = TempDir::new();
+        for i in 0..6 {
+            fs::write(dir.path().join(format!("file{i}")), b"").unwrap();
+        }
+        let e = export(dir.path());
+        let s = server(vec![e.clone()]);
+        let fh = fh_from_path(&FhKey::default(), &e, dir.path());
+        let list = |cookie, count| {
+            let reply = nfs(&s, 16, 0, &readdir_args(&fh, cookie, count));
+            let (st, mut r) = status(&reply);
+            assert_eq!(st, NFS_OK);
+            entries(&mut r)
+        };
+
+        let (full, eof) = list(0, 4096);
+        assert!(eof);
+        assert_eq!(full.len(), 6);
+        assert!(
+            full.iter().all(|(_, c)| *c != 0),
+            "0 is reserved for the start"
+        );
+
+        // a few entries in, the client starts over
+        let (first, eof) = list(0, 64);
+        assert_eq!((first.as_slice(), eof), (&full[..2], false));
+        let (next, _) = list(first[1].1, 64);
+        assert_eq!(next, &full[2..4]);
+        let (again, _) = list(0, 4096);
+        assert_eq!(again, full);
+    }
+
+    #[test]
+    fn readdir_skips_an_entry_it_cannot_stat() {
+        let dir = TempDir::new();
+        fs::write(dir.path().join("a"), b"").unwrap();
+        fs::write(dir.path().join("b"), b"").unwrap();
+        let e = export(dir.path());
+        let mut s = server(vec![e.clone()]);
+        s.env = Env::default().vanished_entry("gone");
+        let root = fh_from_path(&FhKey::default(), &e, dir.path());
+
+        let reply = nfs(&s, 16, 0, &readdir_args(&root, 0, 4096));
+        let (st, mut r) = status(&reply);
+        assert_eq!(st, NFS_OK);
+        let (list, eof) = entries(&mut r);
+        let mut names: Vec<_> = list.iter().map(|(n, _)| n.as_str()).collect();
+        names.sort();
+        assert_eq!(names, ["a", "b"]);
+        assert!(eof, "the skipped entry does not end the listing early");
+
+        // resuming after the last listed entry finds nothing more
+        let last = list.last().unwrap().1;
+        let reply = nfs(&s, 16, 0, &readdir_args(&root, last, 4096));
+        let (st, mut r) = status(&reply);
+        assert_eq!(st, NFS_OK);
+        assert_eq!(entries(&mut r), (Vec::new(), true));
+    }
+
+    #[test]
+    fn readdir_that_breaks_mid_listing_is_not_eof() {
+        let dir = TempDir::new();
+        for name in ["a", "b", "c", "d", "e"] {
+            fs::write(dir.path().join(name), b"").unwrap();
+        }
+        let e = export(dir.path());
+        let mut s = server(vec![e.clone()]);
+        s.env = Env::default().failing_listings(2, std::io::ErrorKind::Other);
+        let root = fh_from_path(&FhKey::default(), &e, dir.path());
+
+        let reply = nfs(&s, 16, 0, &readdir_args(&root, 0, 4096));
+        let (st, mut r) = status(&reply);
+        assert_eq!(st, NFS_OK);
+        let (first, eof) = entries(&mut r);
+        assert_eq!(first.len(), 2);
+        assert!(!eof, "a partial listing must not look complete");
+
+        // the client asks again from there and gets the rest
+        s.env = Env::default();
+        let last = first.last().unwrap().1;
+        let reply = nfs(&s, 16, 0, &readdir_args(&root, last, 4096));
+        let (rest, eof) = entries(&mut status(&reply).1);
+        assert!(eof);
+        let mut names: Vec<_> = first.iter().chain(&rest).map(|(n, _)| n.as_str()).collect();
+        names.sort();
+        assert_eq!(names, ["a", "b", "c", "d", "e"]);
+
+        // nothing listed before the break: an error, not an empty page
+        s.env = Env::default().failing_listings(0, std::io::ErrorKind::Other);
+        let reply = nfs(&s, 16, 0, &readdir_args(&root, 0, 4096));
+        let (st, mut r) = status(&reply);
+        assert_eq!(st, NFSERR_IO);
+        assert!(r.get_u32().is_err(), "no entry list after an error status");
+    }
+
+    #[test]
+    fn readdir_on_a_file_is_notdir() {
+        let dir = TempDir::new();
+        let f = dir.path().join("file");
+        fs::write(&f, b"data").unwrap();
+        let e = export(dir.path());
+        let s = server(vec![e.clone()]);
+        let fh = fh_from_path(&FhKey::default(), &e, &f);
+
+        let reply = nfs(&s, 16, 0, &readdir_args(&fh, 0, 4096));
+        let (st, r) = status(&reply);
+        assert_eq!(st, NFSERR_NOTDIR);
+        assert_eq!(r.pos, r.buf.len(), "no listing after the status");
+    }
+
+    #[test]
+    fn read_on_a_directory_is_isdir() {
+        let dir = TempDir::new();
+        let sub = dir.path().join("sub");
+        fs::create_dir(&sub).unwrap();
+        let e = export(dir.path());
+        let s = server(vec![e.clone()]);
+        let key = FhKey::default();
+
+        for p in [dir.path(), &sub] {
+            let reply = nfs(&s, 6, 0, &read_args(&fh_from_path(&key, &e, p), 0, 16));
+            let (st, r) = status(&reply);
+            assert_eq!(st, NFSERR_ISDIR, "{}", p.display());
+            assert_eq!(r.pos, r.buf.len(), "no attributes or data after the status");
+        }
+    }
+
+    #[test]
+    fn zero_byte_read_returns_attributes_only() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let dir = TempDir::new();
+        let f = dir.path().join("f");
+        fs::write(&f, b"data").unwrap();
+        fs::set_permissions(&f, fs::Permissions::from_mode(0o600)).unwrap();
+        let owner = fs::metadata(&f).unwrap().uid();
+        let e = export(dir.path());
+        let s = server(vec![e.clone()]);
+        let fh = fh_from_path(&FhKey::default(), &e, &f);
+
+        let reply = nfs(&s, 6, owner, &read_args(&fh, 0, 0));
+        let (st, mut r) = status(&reply);
+        assert_eq!(st, NFS_OK);
+        assert_eq!(fattr(&mut r)[5], 4);
+        assert_eq!(r.get_opaque().unwrap(), b"");
+        assert_eq!(r.pos, r.buf.len());
+
+        // the attributes are current, not cached from the last READ
+        fs::write(&f, b"longer data").unwrap();
+        let reply = nfs(&s, 6, owner, &read_args(&fh, 0, 0));
+        let (st, mut r) = status(&reply);
+        assert_eq!(st, NFS_OK);
+        assert_eq!(fattr(&mut r)[5], 11);
+
+        // count 0 does not skip the access check
+        fs::set_permissions(&f, fs::Permissions::from_mode(0o000)).unwrap();
+        let reply = nfs(&s, 6, owner.wrapping_add(1000), &read_args(&fh, 0, 0));
+        assert_eq!(status(&reply).0, NFSERR_ACCES);
+    }
+
+    /// Sequential READ latency with and without read-ahead, with the
+    /// file kept out of the page cache:
+    /// `cargo test --release bench_sequential_read -- --ignored --nocapture`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "benchmark"]
+    async fn bench_sequential_read_latency() {
+        use std::os::fd::AsRawFd;
+
+        const LEN: usize = 8 << 20;
+        let dir = TempDir::new();
+        let f = dir.path().join("big");
+        fs::write(&f, vec![7u8; LEN]).unwrap();
+
+        let file = fs::File::open(&f).unwrap();
+        file.sync_all().unwrap();
+        // SAFETY: plain advice on an open descriptor
+        let evict =
+            || unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+
+        for on in [false, true] {
+            let mut e = export(dir.path());
+            e.read_ahead = on;
+            let s = server(vec![e.clone()]);
+            let fh = fh_from_path(&FhKey::default(), &e, &f);
+
+            let n = LEN / NFS_MAXDATA;
+            let mut total = Duration::ZERO;
+            for i in 0..n {
+                let args = read_args(&fh, (i * NFS_MAXDATA) as u32, NFS_MAXDATA as u32);
+                // a working set bigger than the page cache
+                evict();
+                let t0 = Instant::now();
+                let reply = nfs(&s, 6, 0, &args);
+                total += t0.elapsed();
+                assert_eq!(status(&reply).0, NFS_OK);
+                // the client's round trip, during which a prefetch runs
+                tokio::time::sleep(Duration::from_micros(500)).await;
+            }
+            println!("read_ahead={on}: {n} READs, mean {:?}", total / n as u32);
+        }
+    }
+
+    #[test]
+    fn getattr_is_byte_identical_under_a_fixed_env() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new();
+        let f = dir.path().join("golden");
+        fs::write(&f, b"hello").unwrap();
+        fs::set_permissions(&f, fs::Permissions::from_mode(0o644)).unwrap();
+        let e = export(dir.path());
+        let fh = fh_from_path(&FhKey::default(), &e, &f);
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+
+        let getattr = || {
+            let mut s = server(vec![e.clone()]);
+            s.env = Env::fixed(7, now);
+            nfs(&s, 1, 0, &fh_args(&fh))
+        };
+
+        let first = getattr();
+        // the host clock and the file's own times move on
+        let t = fs::FileTimes::new()
+            .set_accessed(SystemTime::now())
+            .set_modified(SystemTime::now());
+        fs::File::options()
+            .write(true)
+            .open(&f)
+            .unwrap()
+            .set_times(t)
+            .unwrap();
+        assert_eq!(getattr(), first);
+
+        let (st, mut r) = status(&first);
+        assert_eq!(st, NFS_OK);
+        let a = fattr(&mut r);
+        assert_eq!([a[11], a[13], a[15]], [1_000_000_000; 3]);
+        assert_eq!((a[0], a[1], a[5]), (NFREG, 0o100644, 5));
+    }
+
+    #[test]
+    fn fixed_mtime_is_what_getattr_reports() {
+        let dir = TempDir::new();
+        let f = dir.path().join("f");
+        fs::write(&f, b"x").unwrap();
+        let e = Export {
+            fixed_mtime: Some(1_234_567_890),
+            ..export(dir.path())
+        };
+        let s = server(vec![e.clone()]);
+
+        for p in [&f, dir.path()] {
+            let reply = nfs(&s, 1, 0, &fh_args(&fh_from_path(&FhKey::default(), &e, p)));
+            let (st, mut r) = status(&reply);
+            assert_eq!(st, NFS_OK);
+            let a = fattr(&mut r);
+            assert_eq!((a[13], a[15]), (1_234_567_890, 1_234_567_890), "{p:?}");
+        }
+    }
+
+    fn write_args(fh: &[u8], offset: u32, data: &[u8]) -> Vec<u8> {
+        let mut w = XdrW::new();
+        w.put_fixed(fh);
+        w.put_u32(0); // beginoffset
+        w.put_u32(offset);
+        w.put_u32(0); // totalcount
+        w.put_opaque(data);
+        w.into_vec()
+    }
+
+    #[test]
+    fn write_whose_flush_fails_is_not_ok() {
+        use std::io::ErrorKind;
+
+        let dir = TempDir::new();
+        let f = dir.path().join("f");
+        fs::write(&f, b"").unwrap();
+        let e = export(dir.path());
+        let fh = fh_from_path(&FhKey::default(), &e, &f);
+
+        for (kind, want) in [
+            (ErrorKind::StorageFull, NFSERR_NOSPC),
+            (ErrorKind::Other, NFSERR_IO),
+        ] {
+            let mut s = server(vec![e.clone()]);
+            s.env = Env::default().failing_sync(kind);
+            let reply = nfs(&s, 8, 0, &write_args(&fh, 0, b"data"));
+            assert_eq!(status(&reply).0, want, "{kind:?}");
+        }
+        let reply = nfs(&server(vec![e]), 8, 0, &write_args(&fh, 0, b"data"));
+        assert_eq!(status(&reply).0, NFS_OK);
+    }
+
+    #[test]
+    fn lookup_honours_max_name_len() {
+        let dir = TempDir::new();
+        for name in ["abcd", "abcde"] {
+            fs::write(dir.path().join(name), b"").unwrap();
+        }
+        let e = Export {
+            max_name_len: Some(4),
+            ..export(dir.path())
+        };
+        let s = server(vec![e.clone()]);
+        let root = fh_from_path(&FhKey::default(), &e, dir.path());
+        let lookup = |name| status(&nfs(&s, 4, 0, &lookup_args(&root, name))).0;
+
+        assert_eq!(lookup("abcd"), NFS_OK);
+        assert_eq!(lookup("wxyz"), NFSERR_NOENT);
+        assert_eq!(lookup("abcde"), NFSERR_NAMETOOLONG);
+        assert_eq!(lookup("vwxyz"), NFSERR_NAMETOOLONG);
+
+        let reply = nfs(&s, 16, 0, &readdir_args(&root, 0, 4096));
+        let (_, mut r) = status(&reply);
+        let names: Vec<_> = entries(&mut r).0.into_iter().map(|(n, _)| n).collect();
+        assert_eq!(names, ["abcd"]);
+    }
+
+    #[test]
+    fn each_write_changes_the_reported_ctime() {
+        let dir = TempDir::new();
+        let f = dir.path().join("f");
+        fs::write(&f, b"").unwrap();
+        let e = Export {
+            change_ids: Some(crate::changeid::ChangeIds::new()),
+            ..export(dir.path())
+        };
+        let mut s = server(vec![e.clone()]);
+        // every write lands in the same second
+        s.env = Env::fixed(7, UNIX_EPOCH + Duration::from_secs(1_000_000_000));
+        let fh = fh_from_path(&FhKey::default(), &e, &f);
+        let ctime = || {
+            let reply = nfs(&s, 1, 0, &fh_args(&fh));
+            let (st, mut r) = status(&reply);
+            assert_eq!(st, NFS_OK);
+            let a = fattr(&mut r);
+            (a[15], a[16])
+        };
+
+        let mut seen = vec![ctime()];
+        for data in [b"a", b"b"] {
+            let reply = nfs(&s, 8, 0, &write_args(&fh, 0, data));
+            assert_eq!(status(&reply).0, NFS_OK);
+            seen.push(ctime());
+        }
+        assert_eq!(
+            seen,
+            [(1_000_000_000, 0), (1_000_000_000, 1), (1_000_000_000, 2)]
+        );
+        // reading changes nothing
+        nfs(&s, 6, 0, &read_args(&fh, 0, 1));
+        assert_eq!(ctime(), (1_000_000_000, 2));
+    }
+
+    #[test]
+    fn one_handle_gives_each_identity_its_own_answer() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let dir = TempDir::new();
+        let f = dir.path().join("private");
+        fs::write(&f, b"secret").unwrap();
+        fs::set_permissions(&f, fs::Permissions::from_mode(0o600)).unwrap();
+        let owner = fs::metadata(&f).unwrap().uid();
+        let other = owner.wrapping_add(1000);
+        let e = export(dir.path());
+        let s = server(vec![e.clone()]);
+        let fh = fh_from_path(&FhKey::default(), &e, &f);
+        let read = read_args(&fh, 0, 6);
+
+        for _ in 0..2 {
+            let reply = nfs(&s, 6, owner, &read);
+            assert_eq!(status(&reply).0, NFS_OK, "owner");
+            let reply = nfs(&s, 6, other, &read);
+            assert_eq!(status(&reply).0, NFSERR_ACCES, "other user");
+        }
+    }
+
+    #[test]
+    fn readdir_teaches_the_handle_cache() {
+        let dir = TempDir::new();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/deep"), b"").unwrap();
+        fs::write(dir.path().join("f"), b"data").unwrap();
+        let e = export(dir.path());
+        let s = server(vec![e.clone()]);
+        let cached = |p: &Path| {
+            let m = fs::symlink_metadata(p).unwrap();
+            s.handles.get(m.dev(), m.ino())
+        };
+        let (f, sub, deep) = (
+            dir.path().join("f"),
+            dir.path().join("sub"),
+            dir.path().join("sub/deep"),
+        );
+        assert_eq!(cached(&f), None);
+
+        let root = fh_from_path(&FhKey::default(), &e, dir.path());
+        let reply = nfs(&s, 16, 0, &readdir_args(&root, 0, 4096));
+        assert_eq!(status(&reply).0, NFS_OK);
+
+        // every listed entry is known; what was not listed is not
+        assert_eq!(cached(&f), Some(f.clone()));
+        assert_eq!(cached(&sub), Some(sub.clone()));
+        assert_eq!(cached(&deep), None);
+
+        // resolve() asks the cache before it walks: stat-ing every
+        // listed name walks nothing
+        let walks = || WALKS.with(|n| n.get());
+        let before = walks();
+        let (listed, _) = entries(&mut status(&reply).1);
+        assert_eq!(listed.len(), 2);
+        for (name, _) in listed {
+            let fh = fh_from_path(&FhKey::default(), &e, &dir.path().join(&name));
+            let reply = nfs(&s, 1, 0, &fh_args(&fh));
+            assert_eq!(status(&reply).0, NFS_OK, "{name}");
+        }
+        assert_eq!(walks(), before);
+
+        // an unlisted name still has to be found the slow way
+        let reply = nfs(
+            &s,
+            1,
+            0,
+            &fh_args(&fh_from_path(&FhKey::default(), &e, &deep)),
+        );
+        assert_eq!(status(&reply).0, NFS_OK);
+        assert_eq!(walks(), before + 1);
+    }
+
+    #[test]
+    fn no_readdir_export_still_answers_lookup() {
+        let dir = TempDir::new();
+        fs::write(dir.path().join("known"), b"x").unwrap();
+        let e = Export {
+            no_readdir: true,
+            ..export(dir.path())
+        };
+        let s = server(vec![e.clone()]);
+        let root = fh_from_path(&FhKey::default(), &e, dir.path());
+
+        let reply = nfs(&s, 16, 0, &readdir_args(&root, 0, 4096));
+        assert_eq!(status(&reply).0, NFSERR_ACCES);
+
+        let reply = nfs(&s, 4, 0, &lookup_args(&root, "known"));
+        let (st, mut r) = status(&reply);
+        assert_eq!(st, NFS_OK);
+        r.get_fixed(FH_SIZE).unwrap();
+        assert_eq!(fattr(&mut r)[0], NFREG);
+        let reply = nfs(&s, 4, 0, &lookup_args(&root, "unknown"));
+        assert_eq!(status(&reply).0, NFSERR_NOENT);
+    }
+
+    #[test]
+    fn slow_calls_are_logged_by_procedure_name() {
+        let dir = TempDir::new();
+        let f = dir.path().join("f");
+        fs::write(&f, b"hello").unwrap();
+        let e = export(dir.path());
+        let slow = SlowThreshold::default();
+        let mut s = server(vec![e.clone()]).slow_threshold(slow.clone());
+        s.env = Env::default().slow_reads(Duration::from_millis(50));
+        let read = read_args(&fh_from_path(&FhKey::default(), &e, &f), 0, 5);
+
+        slow.set(Duration::from_secs(60));
+        let (_, logged) = warnings(|| nfs(&s, 6, 0, &read));
+        assert_eq!(logged, "", "fast enough");
+
+        slow.set(Duration::from_millis(20));
+        let (_, logged) = warnings(|| nfs(&s, 6, 0, &read));
+        assert_eq!(logged.lines().count(), 1, "{logged}");
+        assert!(logged.contains("WARN"), "{logged}");
+        assert!(logged.contains("nfs2: slow request"), "{logged}");
+        assert!(
+            logged.contains(&format!("proc=\"{}\"", NFS_PROC_NAMES[6])),
+            "{logged}"
+        );
+        assert!(logged.contains(&format!("xid={}", 0x1234_5678)), "{logged}");
+        assert!(
+            logged.contains(&format!("path={}", f.display())),
+            "{logged}"
+        );
+        assert!(logged.contains(&format!("peer={CLIENT}")), "{logged}");
+        let ms: u64 = logged
+            .split_once("ms=")
+            .and_then(|(_, rest)| rest.split_whitespace().next())
+            .and_then(|ms| ms.parse().ok())
+            .expect(&logged);
+        assert!(ms >= 50, "{logged}");
+    }
+
+    fn lookup_args(dir: &[u8], name: &str) -> Vec<u8> {
+        let mut w = XdrW::new();
+        w.put_fixed(dir);
+        w.put_string(name);
+        w.into_vec()
+    }
+
+    /// An export one level below a scratch dir holding a secret file.
+    fn escape_fixture() -> (TempDir, Export) {
+        let dir = TempDir::new();
+        fs::write(dir.path().join("outside.txt"), b"secret").unwrap();
+        let root = dir.path().join("export");
+        fs::create_dir(&root).unwrap();
+        (dir, export(&root))
+    }
+
+    #[test]
+    fn lookup_dotdot_at_export_root_is_refused() {
+        let (_dir, e) = escape_fixture();
+        let s = server(vec![e.clone()]);
+        let root = fh_from_path(&FhKey::default(), &e, &e.path);
+
+        for name in ["..", ".", "", "../outside.txt", "a/b"] {
+            let reply = nfs(&s, 4, 0, &lookup_args(&root, name));
+            assert_eq!(status(&reply).0, NFSERR_ACCES, "{name:?}");
+        }
+        // the root's parent never made it into the handle cache
+        let reply = nfs(&s, 4, 0, &lookup_args(&root, "outside.txt"));
+        assert_eq!(status(&reply).0, NFSERR_NOENT);
+    }
+
+    #[test]
+    fn lookup_through_symlinked_dir_stays_inside() {
+        let (dir, e) = escape_fixture();
+        std::os::unix::fs::symlink(dir.path(), e.path.join("up")).unwrap();
+        let s = server(vec![e.clone()]);
+        let root = fh_from_path(&FhKey::default(), &e, &e.path);
+
+        let reply = nfs(&s, 4, 0, &lookup_args(&root, "up"));
+        let (st, mut r) = status(&reply);
+        assert_eq!(st, NFS_OK);
+        let link = r.get_fixed(FH_SIZE).unwrap();
+        assert_eq!(fattr(&mut r)[0], NFLNK);
+
+        let reply = nfs(&s, 4, 0, &lookup_args(&link, "outside.txt"));
+        assert_eq!(status(&reply).0, NFSERR_ACCES);
+    }
+
+    #[test]
+    fn cached_path_outside_export_is_not_served() {
+        let (dir, e) = escape_fixture();
+        let s = server(vec![e.clone()]);
+        let outside = dir.path().join("outside.txt");
+        let meta = fs::metadata(&outside).unwrap();
+
+        // what LOOKUP ".." used to leave behind
+        s.handles.insert_meta(&meta, &e.path.join("../outside.txt"));
+        let fh = fh_from_path(&FhKey::default(), &e, &outside);
+        let reply = nfs(&s, 6, 0, &read_args(&fh, 0, 6));
+        assert_eq!(status(&reply).0, NFSERR_STALE);
+    }
+
+    #[test]
+    fn tampered_signed_handle_is_stale() {
+        let dir = TempDir::new();
+        let f = dir.path().join("f");
+        fs::write(&f, b"x").unwrap();
+        let e = export(dir.path());
+        let key = FhKey::new(vec![42; 32]);
+        let mut s = server(vec![e.clone()]);
+        s.fh_key = key.clone();
+
+        let fh = fh_from_path(&key, &e, &f);
+        assert_eq!(status(&nfs(&s, 1, 0, &fh_args(&fh))).0, NFS_OK);
+
+        // every field the MAC covers, and the MAC itself
+        for i in [7, 15, 19, 23, 31] {
+            let mut bad = fh.clone();
+            bad[i] ^= 1;
+            assert_eq!(
+                status(&nfs(&s, 1, 0, &fh_args(&bad))).0,
+                NFSERR_STALE,
+                "byte {i}"
+            );
+        }
+        // unsigned, or signed by someone else
+        for other in [FhKey::default(), FhKey::new(vec![7; 32])] {
+            let forged = fh_from_path(&other, &e, &f);
+            assert_eq!(status(&nfs(&s, 1, 0, &fh_args(&forged))).0, NFSERR_STALE);
+        }
+    }
+
+    #[test]
+    fn handle_of_one_export_does_not_open_another_of_the_same_dir() {
+        let dir = TempDir::new();
+        let f = dir.path().join("f");
+        fs::write(&f, b"data").unwrap();
+        let a = Export {
+            clients: vec!["198.51.100.7".into()],
+            ..export(dir.path())
+        };
+        let b = Export {
+            read_only: true,
+            // same directory, different handle id
+            ..export(&dir.path().join("."))
+        };
+        let s = server(vec![a.clone(), b.clone()]);
+
+        // CLIENT may only use B; A's handle names the same inode but
+        // carries A's id and its rules
+        let from_a = fh_from_path(&FhKey::default(), &a, &f);
+        assert_eq!(status(&nfs(&s, 1, 0, &fh_args(&from_a))).0, NFSERR_STALE);
+        assert_eq!(
+            status(&nfs(&s, 6, 0, &read_args(&from_a, 0, 4))).0,
+            NFSERR_STALE
+        );
+        let from_b = fh_from_path(&FhKey::default(), &b, &b.path.join("f"));
+        assert_eq!(status(&nfs(&s, 6, 0, &read_args(&from_b, 0, 4))).0, NFS_OK);
+    }
+
+    /// SETATTR arguments; `None` leaves a field unset (all ones).
+    fn setattr_args(fh: &[u8], size: Option<u32>, atime: (u32, u32), mtime: (u32, u32)) -> Vec<u8> {
+        let mut w = XdrW::new();
+        w.put_fixed(fh);
+        for _ in 0..3 {
+            w.put_u32(u32::MAX); // mode, uid, gid
+        }
+        w.put_u32(size.unwrap_or(u32::MAX));
+        for (secs, usecs) in [atime, mtime] {
+            w.put_u32(secs);
+            w.put_u32(usecs);
+        }
+        w.into_vec()
+    }
+
+    const KEEP: (u32, u32) = (u32::MAX, u32::MAX);
+    const NOW: (u32, u32) = (0, 1_000_000);
+
+    fn fifo(dir: &TempDir) -> PathBuf {
+        let p = dir.path().join("fifo");
+        let c = CString::new(p.as_os_str().as_encoded_bytes()).unwrap();
+        // SAFETY: `c` is a valid NUL terminated path
+        assert_eq!(unsafe { libc::mkfifo(c.as_ptr(), 0o644) }, 0);
+        p
+    }
+
+    #[test]
+    fn setattr_times_on_a_fifo_does_not_open_it() {
+        let dir = TempDir::new();
+        let p = fifo(&dir);
+        let e = export(dir.path());
+        let s = server(vec![e.clone()]);
+        let fh = fh_from_path(&FhKey::default(), &e, &p);
+
+        // an open would block until a writer shows up, so run it aside
+        let (tx, rx) = std::sync::mpsc::channel();
+        let args = setattr_args(&fh, None, NOW, (1_000_000_000, 0));
+        std::thread::spawn(move || tx.send(nfs(&s, 2, 0, &args)).unwrap());
+        let reply = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("SETATTR hung on a FIFO");
+        assert_eq!(status(&reply).0, NFS_OK);
+        assert_eq!(fs::symlink_metadata(&p).unwrap().mtime(), 1_000_000_000);
+    }
+
+    #[test]
+    fn setattr_size_only_on_regular_files() {
+        let dir = TempDir::new();
+        let p = fifo(&dir);
+        let f = dir.path().join("f");
+        fs::write(&f, b"0123456789").unwrap();
+        let e = export(dir.path());
+        let s = server(vec![e.clone()]);
+        let key = FhKey::default();
+
+        let reply = nfs(
+            &s,
+            2,
+            0,
+            &setattr_args(&fh_from_path(&key, &e, &p), Some(0), KEEP, KEEP),
+        );
+        assert_eq!(status(&reply).0, NFSERR_NXIO);
+        let reply = nfs(
+            &s,
+            2,
+            0,
+            &setattr_args(&fh_from_path(&key, &e, dir.path()), Some(0), KEEP, KEEP),
+        );
+        assert_eq!(status(&reply).0, NFSERR_ISDIR);
+
+        let before = fs::metadata(&f).unwrap();
+        let reply = nfs(
+            &s,
+            2,
+            0,
+            &setattr_args(&fh_from_path(&key, &e, &f), Some(4), KEEP, KEEP),
+        );
+        let (st, mut r) = status(&reply);
+        assert_eq!(st, NFS_OK);
+        assert_eq!(fattr(&mut r)[5], 4);
+        assert_eq!(fs::read(&f).unwrap(), b"0123");
+        // times the client left unset stay as they were
+        assert_eq!(fs::metadata(&f).unwrap().atime(), before.atime());
+    }
+
+    #[test]
+    fn growing_past_max_file_size_is_fbig() {
+        let dir = TempDir::new();
+        let f = dir.path().join("f");
+        fs::write(&f, b"0123456789").unwrap();
+        let e = Export {
+            max_file_size: Some(10),
+            ..export(dir.path())
+        };
+        let s = server(vec![e.clone()]);
+        let fh = fh_from_path(&FhKey::default(), &e, &f);
+
+        let reply = nfs(&s, 8, 0, &write_args(&fh, 8, b"abc"));
+        assert_eq!(status(&reply).0, NFSERR_FBIG);
+        let reply = nfs(&s, 2, 0, &setattr_args(&fh, Some(11), KEEP, KEEP));
+        assert_eq!(status(&reply).0, NFSERR_FBIG);
+        assert_eq!(fs::read(&f).unwrap(), b"0123456789");
+
+        // up to the cap, in place, or smaller is fine
+        let reply = nfs(&s, 8, 0, &write_args(&fh, 8, b"ab"));
+        assert_eq!(status(&reply).0, NFS_OK);
+        let reply = nfs(&s, 2, 0, &setattr_args(&fh, Some(4), KEEP, KEEP));
+        assert_eq!(status(&reply).0, NFS_OK);
+        assert_eq!(fs::read(&f).unwrap(), b"0123");
+    }
+
+    #[test]
+    fn readlink_returns_the_target() {
+        let dir = TempDir::new();
+        fs::write(dir.path().join("file"), b"x").unwrap();
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink("file", &link).unwrap();
+        let e = export(dir.path());
+        let s = server(vec![e.clone()]);
+        let key = FhKey::default();
+
+        let reply = nfs(&s, 5, 0, &fh_args(&fh_from_path(&key, &e, &link)));
+        let (st, mut r) = status(&reply);
+        assert_eq!(st, NFS_OK);
+        assert_eq!(r.get_string().unwrap(), "file");
+
+        let reply = nfs(
+            &s,
+            5,
+            0,
+            &fh_args(&fh_from_path(&key, &e, &dir.path().join("file"))),
+        );
+        assert_eq!(status(&reply).0, NFSERR_NXIO);
+    }
+
+    #[test]
+    fn deref_symlinks_presents_in_export_targets() {
+        let (dir, mut e) = escape_fixture();
+        fs::write(e.path.join("file"), b"hello").unwrap();
+        std::os::unix::fs::symlink("file", e.path.join("inside")).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("outside.txt"), e.path.join("out")).unwrap();
+        e.deref_symlinks = true;
+        let s = server(vec![e.clone()]);
+        let root = fh_from_path(&FhKey::default(), &e, &e.path);
+
+        let reply = nfs(&s, 4, 0, &lookup_args(&root, "inside"));
+        let (st, mut r) = status(&reply);
+        assert_eq!(st, NFS_OK);
+        r.get_fixed(FH_SIZE).unwrap();
+        let a = fattr(&mut r);
+        assert_eq!((a[0], a[5]), (NFREG, 5));
+
+        // a target outside stays a link, and READ does not follow it
+        let reply = nfs(&s, 4, 0, &lookup_args(&root, "out"));
+        let (st, mut r) = status(&reply);
+        assert_eq!(st, NFS_OK);
+        let fh = r.get_fixed(FH_SIZE).unwrap();
+        assert_eq!(fattr(&mut r)[0], NFLNK);
+        assert_eq!(status(&nfs(&s, 6, 0, &read_args(&fh, 0, 6))).0, NFSERR_NXIO);
+    }
+
+    #[test]
+    fn empty_handle_falls_back_to_the_mount_root_only_when_enabled() {
+        let dir = TempDir::new();
+        for on in [true, false] {
+            let e = Export {
+                empty_fh_root_fallback: on,
+                ..export(dir.path())
+            };
+            let root = fh_from_path(&FhKey::default(), &e, dir.path());
+            let mut s = server(vec![e.clone()]);
+            s.mounts
+                .lock()
+                .unwrap()
+                .insert((CLIENT.ip(), e.name()), root);
+
+            // GETATTR with no handle at all
+            let reply = nfs(&s, 1, 0, &[]);
+            let (st, mut r) = status(&reply);
+            if on {
+                assert_eq!((st, fattr(&mut r)[0]), (NFS_OK, NFDIR));
+            } else {
+                assert_eq!(st, NFSERR_STALE);
+            }
+            s.mounts = MountTable::default();
+            assert_eq!(
+                status(&nfs(&s, 1, 0, &[])).0,
+                NFSERR_STALE,
+                "no mount to fall back to"
+            );
+        }
+    }
+
+    /// Read-only export named `dir/bundle` serving `dir/bundle.tar`.
+    fn archive_export(dir: &TempDir, members: &[(&str, u8, &[u8])]) -> Export {
+        let p = dir.path().join("bundle.tar");
+        fs::write(&p, tar(members)).unwrap();
+        Export {
+            read_only: true,
+            archive: Some(Arc::new(ArchiveFs::open(&p).unwrap())),
+            ..export(&dir.path().join("bundle"))
+        }
+    }
+
+    /// LOOKUP `name` in `dir`: status, handle and attributes.
+    fn archive_lookup(s: &Nfs2, dir: &[u8], name: &str) -> (u32, Vec<u8>, [u32; 17]) {
+        let reply = nfs(s, 4, 1000, &lookup_args(dir, name));
+        let (st, mut r) = status(&reply);
+        if st != NFS_OK {
+            return (st, Vec::new(), [0; 17]);
+        }
+        let fh = r.get_fixed(FH_SIZE).unwrap();
+        (st, fh, fattr(&mut r))
+    }
+
+    #[test]
+    fn archive_export_serves_files_out_of_a_tar() {
+        let dir = TempDir::new();
+        let e = archive_export(
+            &dir,
+            &[
+                ("docs/readme.txt", b'0', b"read me\n"),
+                ("docs/link", b'2', b"readme.txt"),
+                ("top", b'0', b"x"),
+            ],
+        );
+        let s = server(vec![e.clone()]);
+        let root = root_fh(&FhKey::default(), &e);
+
+        let reply = nfs(&s, 1, 1000, &fh_args(&root));
+        let (st, mut r) = status(&reply);
+        assert_eq!((st, fattr(&mut r)[0]), (NFS_OK, NFDIR));
+
+        let reply = nfs(&s, 16, 1000, &readdir_args(&root, 0, 4096));
+        let (st, mut r) = status(&reply);
+        assert_eq!(st, NFS_OK);
+        let (list, eof) = entries(&mut r);
+        assert_eq!(list, [("docs".to_string(), 1), ("top".to_string(), 2)]);
+        assert!(eof);
+
+        let (st, docs, a) = archive_lookup(&s, &root, "docs");
+        assert_eq!((st, a[0]), (NFS_OK, NFDIR));
+        let (st, readme, a) = archive_lookup(&s, &docs, "readme.txt");
+        assert_eq!(
+            (st, a[0], a[1], a[3], a[5]),
+            (NFS_OK, NFREG, 0o100644, 1000, 8)
+        );
+        // the same member gets the same handle every time
+        assert_eq!(archive_lookup(&s, &docs, "readme.txt").1, readme);
+
+        let reply = nfs(&s, 6, 1000, &read_args(&readme, 5, 100));
+        let (st, mut r) = status(&reply);
+        assert_eq!(st, NFS_OK);
+        fattr(&mut r);
+        assert_eq!(r.get_opaque().unwrap(), b"me\n");
+
+        let (_, link, a) = archive_lookup(&s, &docs, "link");
+        assert_eq!(a[0], NFLNK);
+        let reply = nfs(&s, 5, 1000, &fh_args(&link));
+        let (st, mut r) = status(&reply);
+        assert_eq!(st, NFS_OK);
+        assert_eq!(r.get_opaque().unwrap(), b"readme.txt");
+
+        assert_eq!(archive_lookup(&s, &root, "..").0, NFSERR_ACCES);
+        assert_eq!(archive_lookup(&s, &docs, "missing").0, NFSERR_NOENT);
+        assert_eq!(archive_lookup(&s, &readme, "x").0, NFSERR_NOTDIR);
+    }
+
+    #[test]
+    fn archive_export_refuses_changes() {
+        let dir = TempDir::new();
+        let e = archive_export(&dir, &[("f", b'0', b"data")]);
+        let s = server(vec![e.clone()]);
+        let f = archive_lookup(&s, &root_fh(&FhKey::default(), &e), "f").1;
+
+        assert_eq!(
+            status(&nfs(&s, 8, 0, &write_args(&f, 0, b"new"))).0,
+            NFSERR_ROFS
+        );
+        assert_eq!(
+            status(&nfs(&s, 2, 0, &setattr_args(&f, Some(0), KEEP, KEEP))).0,
+            NFSERR_ROFS
+        );
+        assert_eq!(
+            e.archive.unwrap().read(&Env::default(), 2, 0, 10).unwrap(),
+            b"data"
+        );
+    }
+
+    #[test]
+    fn archive_handles_go_stale_when_the_archive_is_replaced() {
+        let dir = TempDir::new();
+        let old = archive_export(&dir, &[("f", b'0', b"old")]);
+        let f = archive_lookup(
+            &server(vec![old.clone()]),
+            &root_fh(&FhKey::default(), &old),
+            "f",
+        )
+        .1;
+
+        // what a reload after replacing the file would build
+        std::thread::sleep(Duration::from_millis(10));
+        let new = archive_export(&dir, &[("f", b'0', b"new contents")]);
+        let s = server(vec![new]);
+        assert_eq!(
+            status(&nfs(&s, 6, 1000, &read_args(&f, 0, 100))).0,
+            NFSERR_STALE
+        );
+    }
+
+    /// MKDIR of `name` in `dir`, leaving every attribute alone.
+    fn mkdir_args(dir: &[u8], name: &str) -> Vec<u8> {
+        let mut w = XdrW::new();
+        w.put_fixed(dir);
+        w.put_string(name);
+        for _ in 0..8 {
+            w.put_u32(u32::MAX);
+        }
+        w.into_vec()
+    }
+
+    #[test]
+    fn rename_between_two_exports_is_not_relayed() {
+        let dir = TempDir::new();
+        let sub = |name: &str| {
+            let p = dir.path().join(name);
+            fs::create_dir(&p).unwrap();
+            export(&p)
+        };
+        let (a, mut b) = (sub("a"), sub("b"));
+        b.all_squash = true;
+        let s = server(vec![a.clone(), b.clone()])
+            .upstream(Some(Upstream::new("127.0.0.1:9".parse().unwrap())));
+        let key = FhKey::default();
+        let rename = |from: &Export, to: &Export| {
+            let mut w = XdrW::new();
+            w.put_fixed(&root_fh(&key, from));
+            w.put_string("x");
+            w.put_fixed(&root_fh(&key, to));
+            w.put_string("y");
+            s.handle_call(
+                &call(NFS_PROG, NFS_VERS, 11, Some(0), &w.into_vec()),
+                CLIENT,
+            )
+        };
+
+        // one credential cannot speak for both exports' squash settings
+        for (from, to) in [(&a, &b), (&b, &a)] {
+            match rename(from, to) {
+                Reply::Ready(Some(reply)) => assert_eq!(status(&reply).0, NFSERR_XDEV),
+                _ => panic!("cross-export RENAME was relayed"),
+            }
+        }
+        assert!(matches!(rename(&b, &b), Reply::Pending(_)));
+    }
+
+    /// Stand-in for the upstream: mountd and nfsd on one UDP socket,
+    /// over the same files as we serve but with handles of its own.
+    /// MNT of `name` and LOOKUP hand them out; a handle it did not
+    /// issue, or issued before `restart`, is NFSERR_STALE. It carries
+    /// out CREATE, MKDIR, REMOVE and RMDIR, answers anything else with
+    /// NFS_OK, and records each call.
+    #[derive(Clone)]
+    struct MockUpstream {
+        addr: SocketAddr,
+        /// generation, and the file each handle of it names
+        issued: Arc<std::sync::Mutex<(u32, Vec<PathBuf>)>>,
+        calls: Arc<std::sync::Mutex<Vec<MockCall>>>,
+    }
+
+    #[derive(Clone, Debug)]
+    struct MockCall {
+        prog: u32,
+        procid: u32,
+        /// (uid, gid), for AUTH_UNIX
+        cred: Option<(u32, u32)>,
+        from: SocketAddr,
+        args: Vec<u8>,
+    }
+
+    impl MockUpstream {
+        async fn start(root: &Path, name: &str, delay: Duration) -> Self {
+            use crate::mountd::MOUNT_PROG;
+
+            let sock = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mock = Self {
+                addr: sock.local_addr().unwrap(),
+                issued: Arc::default(),
+                calls: Arc::default(),
+            };
+            let (this, root, name) = (mock.clone(), root.to_path_buf(), name.to_string());
+            tokio::spawn(async move {
+                let mut buf = [0u8; 9000];
+                loop {
+                    let (n, from) = sock.recv_from(&mut buf).await.unwrap();
+                    let (c, ofs) = decode_call(&buf[..n]).unwrap();
+                    this.calls.lock().unwrap().push(MockCall {
+                        prog: c.prog,
+                        procid: c.procid,
+                        cred: match &c.auth {
+                            RpcAuth::Unix(u) => Some((u.uid, u.gid)),
+                            RpcAuth::Null => None,
+                        },
+                        from,
+                        args: buf[ofs..n].to_vec(),
+                    });
+                    let mut r = XdrR::new(&buf[ofs..n]);
+                    let mut w = rpc_accept_header(c.xid, SUCCESS);
+                    if c.prog == MOUNT_PROG {
+                        if r.get_string().unwrap() == name {
+                            w.put_u32(0);
+                            w.put_fixed(&this.issue(&root));
+                        } else {
+                            w.put_u32(NFSERR_ACCES);
+                        }
+                    } else {
+                        let fh = r.get_fixed(FH_SIZE).unwrap();
+                        match this.path(&fh) {
+                            None => w.put_u32(NFSERR_STALE),
+                            Some(dir) if matches!(c.procid, 4 | 9 | 10 | 14 | 15) => {
+                                let p = dir.join(r.get_string().unwrap());
+                                let done = match c.procid {
+                                    4 => fs::symlink_metadata(&p).map(drop),
+                                    9 => fs::File::create(&p).map(drop),
+                                    10 => fs::remove_file(&p),
+                                    14 => fs::create_dir(&p),
+                                    _ => fs::remove_dir(&p),
+                                };
+                                match done {
+                                    Ok(()) => {
+                                        w.put_u32(NFS_OK);
+                                        if matches!(c.procid, 4 | 9 | 14) {
+                                            w.put_fixed(&this.issue(&p));
+                                            for _ in 0..17 {
+                                                w.put_u32(0); // not what we report
+                                            }
+                                        }
+                                    }
+                                    Err(e) => w.put_u32(nfs_status(&e)),
+                                }
+                            }
+                            Some(_) => w.put_u32(NFS_OK),
                         }
                     }
+                    tokio::time::sleep(delay).await;
+                    sock.send_to(&w.into_vec(), from).await.unwrap();
                 }
-
-                info!("nfs2 TCP disconnected peer={}", peer_s);
             });
+            mock
         }
+
+        fn upstream(&self) -> Upstream {
+            Upstream::new(self.addr).mountd(Some(self.addr))
+        }
+
+        fn issue(&self, p: &Path) -> Vec<u8> {
+            let mut issued = self.issued.lock().unwrap();
+            let mut fh = b"mock".to_vec();
+            fh.extend(issued.0.to_be_bytes());
+            fh.extend((issued.1.len() as u32).to_be_bytes());
+            fh.resize(FH_SIZE, 0);
+            issued.1.push(p.to_path_buf());
+            fh
+        }
+
+        fn path(&self, fh: &[u8]) -> Option<PathBuf> {
+            let issued = self.issued.lock().unwrap();
+            let word = |i: usize| u32::from_be_bytes(fh[i..i + 4].try_into().unwrap());
+            if fh[..4] != *b"mock" || word(4) != issued.0 {
+                return None;
+            }
+            issued.1.get(word(8) as usize).cloned()
+        }
+
+        /// Forget every handle issued so far, as a server that does not
+        /// keep them across a restart.
+        fn restart(&self) {
+            let mut issued = self.issued.lock().unwrap();
+            issued.0 += 1;
+            issued.1.clear();
+        }
+
+        fn calls(&self) -> Vec<MockCall> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    async fn relayed(s: &Nfs2, procid: u32, uid: u32, args: &[u8]) -> Vec<u8> {
+        match s.handle_call(&call(NFS_PROG, NFS_VERS, procid, Some(uid), args), CLIENT) {
+            Reply::Pending(reply) => reply.await,
+            _ => panic!("call was not relayed"),
+        }
+    }
+
+    #[test]
+    fn slow_relayed_calls_are_logged() {
+        let dir = TempDir::new();
+        let e = export(dir.path());
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let mock = rt.block_on(MockUpstream::start(
+            dir.path(),
+            &e.name(),
+            Duration::from_millis(50),
+        ));
+        let slow = SlowThreshold::default();
+        slow.set(Duration::from_millis(20));
+        let s = server(vec![e.clone()])
+            .slow_threshold(slow)
+            .upstream(Some(mock.upstream()));
+        let mkdir = mkdir_args(&root_fh(&FhKey::default(), &e), "d");
+
+        let (reply, logged) = warnings(|| rt.block_on(relayed(&s, 14, 0, &mkdir)));
+        assert_eq!(status(&reply).0, NFS_OK);
+        assert_eq!(logged.lines().count(), 1, "{logged}");
+        assert!(logged.contains("nfs2: slow request"), "{logged}");
+        assert!(
+            logged.contains(&format!("proc=\"{}\"", NFS_PROC_NAMES[14])),
+            "{logged}"
+        );
+        assert!(logged.contains(&format!("peer={CLIENT}")), "{logged}");
+        assert!(logged.contains("ms="), "{logged}");
+    }
+
+    #[tokio::test]
+    async fn unimplemented_calls_are_relayed_after_the_export_checks() {
+        let dir = TempDir::new();
+        let sub = |name: &str| {
+            let p = dir.path().join(name);
+            fs::create_dir(&p).unwrap();
+            export(&p)
+        };
+        let mut open = sub("open");
+        open.root_squash = true;
+        let mut ro = sub("ro");
+        ro.read_only = true;
+        let mut closed = sub("closed");
+        closed.clients = vec!["198.51.100.0/24".into()];
+
+        let mock = MockUpstream::start(&open.path, &open.name(), Duration::ZERO).await;
+        let s =
+            server(vec![open.clone(), ro.clone(), closed.clone()]).upstream(Some(mock.upstream()));
+        let key = FhKey::default();
+        let mkdir = |e: &Export| mkdir_args(&root_fh(&key, e), "d");
+
+        // refused here, without a word to the upstream
+        for (e, want) in [(&ro, NFSERR_ROFS), (&closed, NFSERR_STALE)] {
+            assert_eq!(status(&nfs(&s, 14, 0, &mkdir(e))).0, want, "{}", e.name());
+        }
+        assert!(mock.calls().is_empty());
+
+        let reply = relayed(&s, 14, 0, &mkdir(&open)).await;
+        let made = open.path.join("d");
+        assert!(made.is_dir());
+        // the handle and attributes are ours, whatever the upstream said
+        let (st, mut r) = status(&reply);
+        assert_eq!(st, NFS_OK);
+        let fh = r.get_fixed(FH_SIZE).unwrap();
+        assert_eq!(fh, fh_from_path(&key, &open, &made));
+        let a = fattr(&mut r);
+        assert_eq!(a[0], NFDIR);
+        let getattr = nfs(&s, 1, 0, &fh_args(&fh));
+        assert_eq!(fattr(&mut status(&getattr).1), a);
+
+        // it was asked with its own handle, found by MNT, from a
+        // reserved port when we may bind one; root arrives squashed
+        let calls = mock.calls();
+        let procs: Vec<_> = calls.iter().map(|c| (c.prog, c.procid)).collect();
+        assert_eq!(procs, [(crate::mountd::MOUNT_PROG, 1), (NFS_PROG, 14)]);
+        let theirs = mock.path(&calls[1].args[..FH_SIZE]);
+        assert_eq!(theirs.as_deref(), Some(open.path.as_path()));
+        assert!(calls.iter().all(|c| c.cred == Some((65534, 65534))));
+        // SAFETY: geteuid cannot fail
+        if unsafe { libc::geteuid() } == 0 {
+            assert!(calls.iter().all(|c| c.from.port() < 1024), "{calls:?}");
+        }
+
+        // RMDIR of what MKDIR made: our handle for the new directory is
+        // not needed, the known root is enough
+        let mut rmdir = XdrW::new();
+        rmdir.put_fixed(&root_fh(&key, &open));
+        rmdir.put_string("d");
+        let reply = relayed(&s, 15, 0, &rmdir.into_vec()).await;
+        assert_eq!(status(&reply).0, NFS_OK);
+        assert!(!made.exists());
+        assert_eq!(mock.calls().len(), 3, "no second MNT");
+    }
+
+    #[tokio::test]
+    async fn relay_looks_up_below_the_root_and_recovers_from_stale() {
+        let dir = TempDir::new();
+        let e = export(dir.path());
+        fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        let mock = MockUpstream::start(dir.path(), &e.name(), Duration::ZERO).await;
+        let s = server(vec![e.clone()]).upstream(Some(mock.upstream()));
+        let key = FhKey::default();
+        let b = fh_from_path(&key, &e, &dir.path().join("a/b"));
+        let create = |name: &str| mkdir_args(&b, name);
+
+        let reply = relayed(&s, 9, 0, &create("f")).await;
+        assert_eq!(status(&reply).0, NFS_OK);
+        let procs: Vec<_> = mock.calls().iter().map(|c| c.procid).collect();
+        assert_eq!(procs, [1, 4, 4, 9], "MNT, LOOKUP a, LOOKUP b, CREATE");
+
+        // the upstream comes back with new handles: the first call sees
+        // NFSERR_STALE, the next one finds them again
+        mock.restart();
+        let reply = relayed(&s, 9, 0, &create("g")).await;
+        assert_eq!(status(&reply).0, NFSERR_STALE);
+        assert!(!dir.path().join("a/b/g").exists());
+        let reply = relayed(&s, 9, 0, &create("g")).await;
+        assert_eq!(status(&reply).0, NFS_OK);
+        assert!(dir.path().join("a/b/g").exists());
+    }
+
+    /// Files the upstream creates are made as the host identity the
+    /// export maps the caller to, as ours would be.
+    #[tokio::test]
+    async fn relayed_create_carries_the_mapped_ids() {
+        let dir = TempDir::new();
+        let mut e = export(dir.path());
+        e.uid_map = crate::export::IdMap::parse(&["5000:4000:10".into()]).unwrap();
+        e.gid_map = crate::export::IdMap::parse(&["5000:4000:10".into()]).unwrap();
+        let mock = MockUpstream::start(dir.path(), &e.name(), Duration::ZERO).await;
+        let s = server(vec![e.clone()]).upstream(Some(mock.upstream()));
+        let root = root_fh(&FhKey::default(), &e);
+
+        // test calls send gid = uid
+        let reply = relayed(&s, 9, 5003, &mkdir_args(&root, "f")).await;
+        assert_eq!(status(&reply).0, NFS_OK);
+        let create = mock.calls().into_iter().find(|c| c.procid == 9).unwrap();
+        assert_eq!(create.cred, Some((4003, 4003)));
+
+        // an id outside the map is the export's anonymous user
+        let reply = relayed(&s, 9, 7000, &mkdir_args(&root, "g")).await;
+        assert_eq!(status(&reply).0, NFS_OK);
+        let create = mock.calls().into_iter().rfind(|c| c.procid == 9).unwrap();
+        assert_eq!(create.cred, Some((e.anon_uid, e.anon_gid)));
     }
 }
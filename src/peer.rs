@@ -0,0 +1,197 @@
+// src/peer.rs
+
+use crate::rpc::{RpcAuth, RpcAuthUnix};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+/// Who sent a call, worked out once per request from the source address
+/// and the credential. Export access, the mount table, read-ahead
+/// streams and logs all key off this, so they agree on who a client is.
+#[derive(Clone, Debug)]
+pub struct PeerInfo {
+    /// source address; Unix socket clients show up as 127.0.0.1:0
+    pub addr: SocketAddr,
+    /// machine name from the AUTH_UNIX credential. The client picks
+    /// it, nothing resolves it: good for logs, not for access checks.
+    pub machine_name: Option<String>,
+    /// AUTH_UNIX credential as sent, before squashing and id mapping
+    pub cred: Option<RpcAuthUnix>,
+}
+
+impl PeerInfo {
+    pub fn new(addr: SocketAddr, auth: &RpcAuth) -> Self {
+        let cred = match auth {
+            RpcAuth::Unix(u) => Some(u.clone()),
+            RpcAuth::Null => None,
+        };
+        Self {
+            addr,
+            machine_name: cred
+                .as_ref()
+                .map(|c| c.machine.clone())
+                .filter(|m| !m.is_empty()),
+            cred,
+        }
+    }
+
+    /// The client host, without the port: UDP clients may change source
+    /// ports between calls, and IPv4-mapped addresses count as IPv4.
+    pub fn ip(&self) -> IpAddr {
+        self.addr.ip().to_canonical()
+    }
+}
+
+impl fmt::Display for PeerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.machine_name {
+            Some(h) => write!(f, "{} ({h})", self.addr),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::Env;
+    use crate::export::Exports;
+    use crate::fsqueue::FsQueue;
+    use crate::mountd::{MOUNT_PROG, MountTable, Mountd};
+    use crate::nfs2::{DisabledProcs, FhKey, NFS_PROG, NFS_VERS, Nfs2, fh_from_path};
+    use crate::shutdown::Shutdown;
+    use crate::testutil::{TempDir, accepted, call, export};
+    use crate::xdr::XdrW;
+    use std::fs;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+
+    fn unix(machine: &str) -> RpcAuth {
+        RpcAuth::Unix(RpcAuthUnix {
+            machine: machine.into(),
+            uid: 1000,
+            gid: 1000,
+            aux_gids: vec![],
+        })
+    }
+
+    #[test]
+    fn machine_name_is_what_the_client_sent() {
+        let addr = "[::ffff:192.0.2.1]:800".parse().unwrap();
+
+        let peer = PeerInfo::new(addr, &unix("pi"));
+        assert_eq!(peer.machine_name.as_deref(), Some("pi"));
+        assert_eq!(peer.ip(), "192.0.2.1".parse::<IpAddr>().unwrap());
+        assert_eq!(peer.to_string(), "[::ffff:192.0.2.1]:800 (pi)");
+
+        assert_eq!(PeerInfo::new(addr, &unix("")).machine_name, None);
+        let anon = PeerInfo::new(addr, &RpcAuth::Null);
+        assert!(anon.machine_name.is_none() && anon.cred.is_none());
+        assert_eq!(anon.to_string(), "[::ffff:192.0.2.1]:800");
+    }
+
+    /// First u32 of the results: MNT's or NFS's status.
+    fn status(reply: &[u8]) -> u32 {
+        let (stat, mut r) = accepted(reply);
+        assert_eq!(stat, crate::rpc::SUCCESS);
+        r.get_u32().unwrap()
+    }
+
+    async fn tcp_call(client: &mut TcpStream, request: &[u8]) -> Vec<u8> {
+        let mut record = (0x8000_0000u32 | request.len() as u32)
+            .to_be_bytes()
+            .to_vec();
+        record.extend_from_slice(request);
+        client.write_all(&record).await.unwrap();
+        let marker = client.read_u32().await.unwrap();
+        let mut reply = vec![0u8; (marker & 0x7fff_ffff) as usize];
+        client.read_exact(&mut reply).await.unwrap();
+        reply
+    }
+
+    /// mountd and nfsd work out who is calling from what each transport
+    /// reports (the datagram's source over UDP, accept()'s address per
+    /// TCP connection) and the credential: the mount table is keyed by
+    /// it, `clients` is checked against it, and files are opened as it.
+    #[tokio::test]
+    async fn udp_and_tcp_calls_carry_the_client_identity() {
+        let dir = TempDir::new();
+        let f = dir.path().join("f");
+        fs::write(&f, b"secret").unwrap();
+        fs::set_permissions(&f, fs::Permissions::from_mode(0o600)).unwrap();
+        let owner = fs::metadata(&f).unwrap().uid();
+        let mut e = export(dir.path());
+        e.clients = vec!["127.0.0.1".into()];
+        // nobody else, whoever runs the test
+        e.anon_uid = u32::MAX - 2;
+        e.anon_gid = u32::MAX - 2;
+        let path = e.name();
+        let exports = Exports::new(vec![e.clone()]);
+        let mounts = MountTable::default();
+        let key = FhKey::default();
+        let queue = FsQueue::default();
+        let stop = Shutdown::default();
+
+        // MNT over UDP: recorded under the caller's host, refused to a
+        // host `clients` does not list
+        let mountd = Mountd::new(exports.clone(), mounts.clone(), key.clone(), MOUNT_PROG);
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mountd_addr = sock.local_addr().unwrap();
+        tokio::spawn(mountd.run_udp(sock, stop.clone(), queue.clone()));
+        let mut mnt = XdrW::new();
+        mnt.put_string(&path);
+        let mnt = call(MOUNT_PROG, 1, 1, Some(owner), &mnt.into_vec());
+        let mut buf = [0u8; 512];
+        for (from, want) in [("127.0.0.2:0", 13), ("127.0.0.1:0", 0)] {
+            let client = UdpSocket::bind(from).await.unwrap();
+            client.connect(mountd_addr).await.unwrap();
+            client.send(&mnt).await.unwrap();
+            let n = client.recv(&mut buf).await.unwrap();
+            assert_eq!(status(&buf[..n]), want, "{from}");
+        }
+        let mounted: Vec<_> = mounts.lock().unwrap().keys().cloned().collect();
+        assert_eq!(mounted, [("127.0.0.1".parse().unwrap(), path)]);
+
+        // READ over TCP: as the owner, someone else and AUTH_NULL (anon)
+        let nfsd = Nfs2::new(
+            exports,
+            mounts,
+            key.clone(),
+            DisabledProcs::default(),
+            Env::default(),
+            NFS_PROG,
+            queue.clone(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let nfsd_addr = listener.local_addr().unwrap();
+        tokio::spawn(nfsd.run_tcp(listener, stop.clone(), queue, 1));
+        let mut read = XdrW::new();
+        read.put_fixed(&fh_from_path(&key, &e, &f));
+        for v in [0, 6, 0] {
+            read.put_u32(v);
+        }
+        let read = read.into_vec();
+        let mut client = TcpStream::connect(nfsd_addr).await.unwrap();
+        for (uid, want) in [
+            (Some(owner), 0),
+            (Some(owner.wrapping_add(1)), 13),
+            (None, 13),
+        ] {
+            let reply = tcp_call(&mut client, &call(NFS_PROG, NFS_VERS, 6, uid, &read)).await;
+            assert_eq!(status(&reply), want, "{uid:?}");
+        }
+
+        // the owner again, from a host the export does not list
+        let outsider = TcpSocket::new_v4().unwrap();
+        outsider.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+        let mut outsider = outsider.connect(nfsd_addr).await.unwrap();
+        let reply = tcp_call(
+            &mut outsider,
+            &call(NFS_PROG, NFS_VERS, 6, Some(owner), &read),
+        )
+        .await;
+        assert_eq!(status(&reply), 70); // NFSERR_STALE
+
+        stop.trigger();
+    }
+}
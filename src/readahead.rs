@@ -0,0 +1,259 @@
+// src/readahead.rs
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, atomic::Ordering},
+};
+use tracing::debug;
+
+use crate::env::Env;
+use crate::fsqueue::FsQueue;
+use crate::metrics::METRICS;
+
+// Bounds: at most MAX_CHUNKS prefetched chunks (each <= NFS_MAXDATA)
+// and MAX_STREAMS tracked (file, client) read streams.
+const MAX_CHUNKS: usize = 64;
+const MAX_STREAMS: usize = 1024;
+
+/// (dev, ino)
+type FileKey = (u64, u64);
+
+struct Chunk {
+    file: FileKey,
+    // identifies the file version the data was read from
+    mtime: i64,
+    size: u64,
+    offset: u64,
+    data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Inner {
+    // next expected offset per (file, client)
+    streams: HashMap<(FileKey, String), u64>,
+    chunks: VecDeque<Chunk>,
+    pending: HashSet<(FileKey, u64)>,
+    // bumped by forget(); prefetches started before a bump are dropped
+    epoch: u64,
+}
+
+/// Sequential READ detection with a small prefetch cache.
+/// When a client reads a file at monotonically increasing offsets,
+/// the following range is read in the background so the next READ
+/// is served from memory. Random access never triggers a prefetch.
+/// Prefetches go through the same queue as requests, so they count
+/// against its bound on concurrent filesystem work.
+#[derive(Clone)]
+pub struct ReadAhead {
+    inner: Arc<Mutex<Inner>>,
+    queue: FsQueue,
+    env: Env,
+}
+
+impl ReadAhead {
+    pub fn new(queue: FsQueue, env: Env) -> Self {
+        Self {
+            inner: Arc::default(),
+            queue,
+            env,
+        }
+    }
+
+    /// Serve a READ from a prefetched chunk of the same file version.
+    pub fn get(&self, meta: &fs::Metadata, offset: u64, count: usize) -> Option<Vec<u8>> {
+        let file = (meta.dev(), meta.ino());
+        let inner = self.inner.lock().unwrap();
+
+        let c = inner.chunks.iter().find(|c| {
+            c.file == file
+                && c.mtime == meta.mtime()
+                && c.size == meta.len()
+                && c.offset <= offset
+                && offset < c.offset + c.data.len() as u64
+        })?;
+
+        let start = (offset - c.offset) as usize;
+        let end = start + count;
+        let chunk_end = c.offset + c.data.len() as u64;
+
+        // A chunk that stops short of both the request and EOF would
+        // look like a short read to the client: treat it as a miss.
+        if end > c.data.len() && chunk_end < meta.len() {
+            return None;
+        }
+
+        debug!(offset, count, "readahead: hit");
+        Some(c.data[start..end.min(c.data.len())].to_vec())
+    }
+
+    /// Drop everything cached for a file whose contents changed.
+    pub fn forget(&self, meta: &fs::Metadata) {
+        let file = (meta.dev(), meta.ino());
+        let mut inner = self.inner.lock().unwrap();
+        let before = inner.chunks.len();
+        inner.chunks.retain(|c| c.file != file);
+        METRICS
+            .readahead_chunks
+            .fetch_sub((before - inner.chunks.len()) as u64, Ordering::Relaxed);
+        inner.epoch += 1;
+    }
+
+    /// Record a completed READ. If it continues where this client's
+    /// previous READ of the file stopped, prefetch the next range.
+    pub fn observe(&self, path: &Path, meta: &fs::Metadata, client: &str, offset: u64, len: usize) {
+        let file = (meta.dev(), meta.ino());
+        let next = offset + len as u64;
+
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.streams.len() >= MAX_STREAMS {
+            inner.streams.clear();
+        }
+        let prev = inner.streams.insert((file, client.to_string()), next);
+
+        if prev != Some(offset) || len == 0 || next >= meta.len() {
+            return;
+        }
+        if inner.pending.contains(&(file, next))
+            || inner
+                .chunks
+                .iter()
+                .any(|c| c.file == file && c.offset == next)
+        {
+            return;
+        }
+        inner.pending.insert((file, next));
+        let epoch = inner.epoch;
+        drop(inner);
+
+        let this = self.clone();
+        let path: PathBuf = path.to_path_buf();
+        let (mtime, size) = (meta.mtime(), meta.len());
+
+        let fetch = move || {
+            let mut data = vec![0u8; len];
+            let res = fs::File::open(&path)
+                .and_then(|f| crate::nfs2::read_full(&this.env, &f, &mut data, next));
+
+            let mut inner = this.inner.lock().unwrap();
+            inner.pending.remove(&(file, next));
+
+            match res {
+                Ok(_) if inner.epoch != epoch => {
+                    debug!(path = %path.display(), "readahead: discarding prefetch raced by a write")
+                }
+                Ok(n) => {
+                    data.truncate(n);
+                    debug!(path = %path.display(), offset = next, n, "readahead: prefetched");
+                    if inner.chunks.len() >= MAX_CHUNKS {
+                        inner.chunks.pop_front();
+                    } else {
+                        METRICS.readahead_chunks.fetch_add(1, Ordering::Relaxed);
+                    }
+                    inner.chunks.push_back(Chunk {
+                        file,
+                        mtime,
+                        size,
+                        offset: next,
+                        data,
+                    });
+                }
+                Err(e) => debug!(path = %path.display(), ?e, "readahead: prefetch failed"),
+            }
+        };
+        let queue = self.queue.clone();
+        tokio::spawn(async move { queue.run(fetch).await });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+    use std::time::{Duration, Instant};
+
+    const CHUNK: usize = 4096;
+
+    fn file(dir: &TempDir, len: usize) -> (PathBuf, fs::Metadata) {
+        let p = dir.path().join("data");
+        let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        fs::write(&p, data).unwrap();
+        let meta = fs::metadata(&p).unwrap();
+        (p, meta)
+    }
+
+    /// Wait for a background prefetch to land.
+    async fn cached(ra: &ReadAhead, meta: &fs::Metadata, offset: u64) -> Option<Vec<u8>> {
+        let t0 = Instant::now();
+        while t0.elapsed() < Duration::from_secs(2) {
+            if let Some(d) = ra.get(meta, offset, CHUNK) {
+                return Some(d);
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn sequential_reads_prefetch_the_next_range() {
+        let dir = TempDir::new();
+        let (p, meta) = file(&dir, 4 * CHUNK);
+        let ra = ReadAhead::new(FsQueue::default(), Env::default());
+
+        ra.observe(&p, &meta, "a", 0, CHUNK);
+        ra.observe(&p, &meta, "a", CHUNK as u64, CHUNK);
+        let d = cached(&ra, &meta, 2 * CHUNK as u64)
+            .await
+            .expect("prefetched");
+        assert_eq!(d[0], (2 * CHUNK) as u8);
+        assert_eq!(d.len(), CHUNK);
+
+        // changed contents drop the chunk
+        ra.forget(&meta);
+        assert!(ra.get(&meta, 2 * CHUNK as u64, CHUNK).is_none());
+    }
+
+    #[tokio::test]
+    async fn random_reads_do_not_prefetch() {
+        let dir = TempDir::new();
+        let (p, meta) = file(&dir, 8 * CHUNK);
+        let ra = ReadAhead::new(FsQueue::default(), Env::default());
+
+        ra.observe(&p, &meta, "a", 5 * CHUNK as u64, CHUNK);
+        ra.observe(&p, &meta, "a", CHUNK as u64, CHUNK);
+        // another client's stream does not continue this one
+        ra.observe(&p, &meta, "b", 2 * CHUNK as u64, CHUNK);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        for i in 0..8 {
+            assert!(ra.get(&meta, (i * CHUNK) as u64, CHUNK).is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn prefetch_waits_for_a_queue_slot() {
+        let dir = TempDir::new();
+        let (p, meta) = file(&dir, 4 * CHUNK);
+        let queue = FsQueue::new(1);
+        let ra = ReadAhead::new(queue.clone(), Env::default());
+
+        // a request holding the only slot
+        let (release, held) = std::sync::mpsc::channel::<()>();
+        let busy = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.run(move || held.recv()).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        ra.observe(&p, &meta, "a", 0, CHUNK);
+        ra.observe(&p, &meta, "a", CHUNK as u64, CHUNK);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(ra.get(&meta, 2 * CHUNK as u64, CHUNK).is_none());
+
+        release.send(()).unwrap();
+        busy.await.unwrap().unwrap().unwrap();
+        assert!(cached(&ra, &meta, 2 * CHUNK as u64).await.is_some());
+    }
+}
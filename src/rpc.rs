@@ -1,8 +1,13 @@
 // src/rpc.rs
 
-use crate::xdr::{XdrR, XdrW};
-use anyhow::Result;
+use crate::env::Env;
+use crate::xdr::{XdrError, XdrR, XdrW};
+use anyhow::{Result, anyhow, bail};
 //use serde::de;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 use tokio::net::UdpSocket;
 use tracing::debug;
 //use tracing::{info, warn};
@@ -11,25 +16,57 @@ pub const RPC_VERSION: u32 = 2;
 pub const RPCBIND_PROGRAM: u32 = 100000;
 pub const RPCBIND_VERSION: u32 = 2;
 pub const RPCBPROC_SET: u32 = 1;
+pub const RPCBPROC_GETPORT: u32 = 3;
+
+/// The local rpcbind (portmapper).
+pub const RPCBIND_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 111);
+
+// How long a GETPORT waits for rpcbind before giving up.
+const RPCBIND_REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// accept_stat
+pub const SUCCESS: u32 = 0;
+pub const PROG_UNAVAIL: u32 = 1;
+pub const PROC_UNAVAIL: u32 = 3;
+pub const GARBAGE_ARGS: u32 = 4;
+pub const SYSTEM_ERR: u32 = 5;
+
+// auth flavors
+pub const AUTH_NULL: u32 = 0;
+pub const AUTH_UNIX: u32 = 1;
+
+// RFC 5531 limits
+const MAX_AUTH_BYTES: usize = 400;
+const MAX_MACHINE_NAME: usize = 255;
+const MAX_AUX_GIDS: usize = 16;
 
 pub const IPPROTO_TCP: u32 = 6;
 pub const IPPROTO_UDP: u32 = 17;
 
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Allocate an id for a new TCP connection.
+/// Ids are unique within the process and fixed for the life of the
+/// connection, so every request of one client session can be grouped
+/// in the logs. UDP has no connection: (peer, xid) is the key there.
+pub fn next_conn_id() -> u64 {
+    NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed)
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum MsgType {
     Call = 0,
     Reply = 1,
 }
 
-#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct RpcAuthUnix {
+    pub machine: String,
     pub uid: u32,
     pub gid: u32,
     pub aux_gids: Vec<u32>,
 }
 
-#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub enum RpcAuth {
     Null,
@@ -45,60 +82,158 @@ pub struct RpcCall {
     pub auth: RpcAuth,
 }
 
+/// Why a packet could not be decoded as a call.
+#[derive(Debug)]
+pub enum CallError {
+    /// Not a call we can answer (no xid, a reply, wrong RPC version).
+    Ignore,
+    /// A call whose header is malformed: answer GARBAGE_ARGS so the
+    /// client stops retransmitting into the void.
+    Garbage { xid: u32 },
+}
+
+impl CallError {
+    /// Reply to send for this error, if any.
+    pub fn reply(&self) -> Option<Vec<u8>> {
+        match self {
+            CallError::Ignore => None,
+            CallError::Garbage { xid } => Some(rpc_accept_reply(*xid, GARBAGE_ARGS, &[])),
+        }
+    }
+}
+
 /// Decode an ONC RPC CALL message.
 /// Returns the parsed call and the offset where the procedure arguments start.
-pub fn decode_call(pkt: &[u8]) -> Option<(RpcCall, usize)> {
+pub fn decode_call(pkt: &[u8]) -> Result<(RpcCall, usize), CallError> {
     let mut r = XdrR::new(pkt);
 
-    let xid = r.get_u32().ok()?;
-    let mtype = r.get_u32().ok()?;
+    let xid = r.get_u32().map_err(|_| CallError::Ignore)?;
+    let mtype = r.get_u32().map_err(|_| CallError::Ignore)?;
     debug!("RPC message xid={} mtype={}", xid, mtype);
     if mtype != MsgType::Call as u32 {
         debug!("nfs2: ignoring non-call message");
-        return None;
+        return Err(CallError::Ignore);
     }
 
-    let rpcvers = r.get_u32().ok()?;
+    let garbage = |e| {
+        debug!(xid, ?e, "RPC CALL header malformed");
+        CallError::Garbage { xid }
+    };
+
+    let rpcvers = r.get_u32().map_err(garbage)?;
     if rpcvers != RPC_VERSION {
         debug!("nfs2: unsupported RPC version {}", rpcvers);
-        return None;
+        return Err(CallError::Ignore);
     }
 
-    let prog = r.get_u32().ok()?;
-    let vers = r.get_u32().ok()?;
-    let procid = r.get_u32().ok()?;
+    let prog = r.get_u32().map_err(garbage)?;
+    let vers = r.get_u32().map_err(garbage)?;
+    let procid = r.get_u32().map_err(garbage)?;
 
     // cred: (flavor, length, bytes[length], pad)
-    let _cred_flavor = r.get_u32().ok()?;
-    let cred_len = r.get_u32().ok()? as usize;
-    r.skip_bytes(cred_len).ok()?;
+    let cred_flavor = r.get_u32().map_err(garbage)?;
+    let cred = r.get_opaque_max(MAX_AUTH_BYTES).map_err(garbage)?;
+    let auth = match cred_flavor {
+        AUTH_UNIX => RpcAuth::Unix(decode_auth_unix(&cred).ok_or(CallError::Garbage { xid })?),
+        AUTH_NULL if !cred.is_empty() => {
+            debug!(xid, len = cred.len(), "AUTH_NULL credential with a body");
+            return Err(CallError::Garbage { xid });
+        }
+        _ => RpcAuth::Null,
+    };
 
     // verf: (flavor, length, bytes[length], pad)
-    let _verf_flavor = r.get_u32().ok()?;
-    let verf_len = r.get_u32().ok()? as usize;
-    r.skip_bytes(verf_len).ok()?;
+    // Minimal stacks sometimes drop the pad of a verifier that ends the
+    // packet; nothing follows it, so there is nothing to misalign.
+    let verf_flavor = r.get_u32().map_err(garbage)?;
+    let verf_len = r.get_u32().map_err(garbage)? as usize;
+    if verf_len > MAX_AUTH_BYTES {
+        return Err(garbage(XdrError::StrTooLong));
+    }
+    if verf_flavor == AUTH_NULL && verf_len != 0 {
+        debug!(xid, verf_len, "AUTH_NULL verifier with a body");
+        return Err(CallError::Garbage { xid });
+    }
+    r.skip_bytes_lenient(verf_len).map_err(garbage)?;
 
     debug!(
         "RPC CALL received xid={} prog={} vers={} procid={}",
         xid, prog, vers, procid
     );
 
-    Some((
+    Ok((
         RpcCall {
             xid,
             prog,
             vers,
             procid,
-            auth: RpcAuth::Null,
+            auth,
         },
         r.pos,
     ))
 }
 
-/// Build a successful RPC ACCEPTED reply.
-/// `body` must contain the procedure-specific XDR payload.
-pub fn rpc_accept_reply(xid: u32, accept_stat: u32, body: &[u8]) -> Vec<u8> {
+/// Decode the body of an AUTH_UNIX credential. The declared length
+/// must match the structure exactly: trailing bytes mean the sender
+/// and we disagree about where the credential ends.
+fn decode_auth_unix(body: &[u8]) -> Option<RpcAuthUnix> {
+    let mut r = XdrR::new(body);
+
+    let _stamp = r.get_u32().ok()?;
+    let machine = r.get_opaque_max(MAX_MACHINE_NAME).ok()?;
+    let uid = r.get_u32().ok()?;
+    let gid = r.get_u32().ok()?;
+
+    let n = r.get_u32().ok()? as usize;
+    if n > MAX_AUX_GIDS {
+        debug!(n, "AUTH_UNIX: too many aux gids");
+        return None;
+    }
+    let mut aux_gids = Vec::with_capacity(n);
+    for _ in 0..n {
+        aux_gids.push(r.get_u32().ok()?);
+    }
+    if r.pos != body.len() {
+        debug!(
+            len = body.len(),
+            used = r.pos,
+            "AUTH_UNIX: length disagrees with contents"
+        );
+        return None;
+    }
+
+    Some(RpcAuthUnix {
+        machine: String::from_utf8_lossy(&machine).into_owned(),
+        uid,
+        gid,
+        aux_gids,
+    })
+}
+
+/// Encode an AUTH_UNIX credential body, the inverse of
+/// `decode_auth_unix`.
+pub fn encode_auth_unix(u: &RpcAuthUnix) -> Vec<u8> {
     let mut w = XdrW::new();
+    w.put_u32(0); // stamp
+    w.put_string(&u.machine);
+    w.put_u32(u.uid);
+    w.put_u32(u.gid);
+    w.put_u32(u.aux_gids.len() as u32);
+    for &g in &u.aux_gids {
+        w.put_u32(g);
+    }
+    w.into_vec()
+}
+
+// Room for the reply header plus the usual results (status, handle,
+// fattr), so most replies never regrow; READ and READDIR grow once.
+const REPLY_CAPACITY: usize = 256;
+
+/// Start an RPC ACCEPTED reply. The procedure result is written into
+/// the returned buffer after the header, so the reply is built in one
+/// allocation; finish it with `XdrW::into_vec`.
+pub fn rpc_accept_header(xid: u32, accept_stat: u32) -> XdrW {
+    let mut w = XdrW::with_capacity(REPLY_CAPACITY);
 
     w.put_u32(xid);
     w.put_u32(MsgType::Reply as u32);
@@ -108,12 +243,15 @@ pub fn rpc_accept_reply(xid: u32, accept_stat: u32, body: &[u8]) -> Vec<u8> {
     w.put_u32(0);
     w.put_u32(0);
 
-    // accept status (0 = SUCCESS)
     w.put_u32(accept_stat);
+    w
+}
 
-    let mut v = w.buf.to_vec();
-    v.extend_from_slice(body);
-    v
+/// Build an RPC ACCEPTED reply around an already encoded `body`.
+pub fn rpc_accept_reply(xid: u32, accept_stat: u32, body: &[u8]) -> Vec<u8> {
+    let mut w = rpc_accept_header(xid, accept_stat);
+    w.buf.extend_from_slice(body);
+    w.into_vec()
 }
 
 /// Build an RPC CALL message.
@@ -137,24 +275,20 @@ pub fn build_rpc_call(xid: u32, prog: u32, vers: u32, procid: u32, body: &[u8])
     w.put_u32(0);
     w.put_u32(0);
 
-    let mut v = w.buf.to_vec();
-    v.extend_from_slice(body);
-    v
-}
-
-/// Register a program/version over UDP with rpcbind.
-pub async fn rpcbind_register_udp(program: u32, version: u32, port: u16) -> Result<()> {
-    rpcbind_register(program, version, IPPROTO_UDP, port).await
+    w.buf.extend_from_slice(body);
+    w.into_vec()
 }
 
-/// Register a program/version over TCP with rpcbind.
-pub async fn rpcbind_register_tcp(program: u32, version: u32, port: u16) -> Result<()> {
-    rpcbind_register(program, version, IPPROTO_TCP, port).await
-}
-
-async fn rpcbind_register(program: u32, version: u32, protocol: u32, port: u16) -> Result<()> {
+/// Map program/version/protocol to `port` in rpcbind.
+pub async fn rpcbind_register(
+    env: &Env,
+    rpcbind: SocketAddr,
+    program: u32,
+    version: u32,
+    protocol: u32,
+    port: u16,
+) -> Result<()> {
     let sock = UdpSocket::bind("0.0.0.0:0").await?;
-    let rpcbind_addr = "127.0.0.1:111";
 
     let mut body = XdrW::new();
     body.put_u32(program);
@@ -162,7 +296,7 @@ async fn rpcbind_register(program: u32, version: u32, protocol: u32, port: u16)
     body.put_u32(protocol);
     body.put_u32(port as u32);
 
-    let xid = rand::random::<u32>();
+    let xid = env.xid();
 
     let call = build_rpc_call(
         xid,
@@ -174,13 +308,70 @@ async fn rpcbind_register(program: u32, version: u32, protocol: u32, port: u16)
 
     debug!(program, version, protocol, port, "registering with rpcbind");
 
-    sock.send_to(&call, rpcbind_addr).await?;
+    sock.send_to(&call, rpcbind).await?;
     Ok(())
 }
 
-pub async fn rpcbind_unregister(program: u32, version: u32, proto: &str) -> Result<()> {
+/// Port rpcbind holds for program/version/protocol, `None` if it has
+/// no mapping. Errs when rpcbind does not answer.
+pub async fn rpcbind_getport(
+    env: &Env,
+    rpcbind: SocketAddr,
+    program: u32,
+    version: u32,
+    protocol: u32,
+) -> Result<Option<u16>> {
+    let sock = UdpSocket::bind("0.0.0.0:0").await?;
+
+    let mut body = XdrW::new();
+    body.put_u32(program);
+    body.put_u32(version);
+    body.put_u32(protocol);
+    body.put_u32(0);
+
+    let xid = env.xid();
+    let call = build_rpc_call(
+        xid,
+        RPCBIND_PROGRAM,
+        RPCBIND_VERSION,
+        RPCBPROC_GETPORT,
+        &body.buf,
+    );
+    sock.send_to(&call, rpcbind).await?;
+
+    let mut buf = [0u8; 128];
+    loop {
+        let n = tokio::time::timeout(RPCBIND_REPLY_TIMEOUT, sock.recv(&mut buf))
+            .await
+            .map_err(|_| anyhow!("rpcbind did not answer GETPORT"))??;
+
+        let mut r = XdrR::new(&buf[..n]);
+        if r.get_u32()? != xid || r.get_u32()? != MsgType::Reply as u32 {
+            continue; // stray datagram
+        }
+        if r.get_u32()? != 0 {
+            bail!("rpcbind denied GETPORT");
+        }
+        r.get_u32()?; // verifier flavor
+        r.get_opaque()?;
+        let stat = r.get_u32()?;
+        if stat != SUCCESS {
+            bail!("rpcbind GETPORT failed (accept_stat {stat})");
+        }
+
+        let port = r.get_u32()?;
+        return Ok((port != 0).then_some(port as u16));
+    }
+}
+
+pub async fn rpcbind_unregister(
+    env: &Env,
+    rpcbind: SocketAddr,
+    program: u32,
+    version: u32,
+    proto: &str,
+) -> Result<()> {
     let sock = UdpSocket::bind("0.0.0.0:0").await?;
-    let rpcbind_addr = "127.0.0.1:111";
 
     let mut body = XdrW::new();
     body.put_u32(program);
@@ -189,7 +380,7 @@ pub async fn rpcbind_unregister(program: u32, version: u32, proto: &str) -> Resu
     body.put_string("");
     body.put_string("");
 
-    let xid = rand::random::<u32>();
+    let xid = env.xid();
 
     let call = build_rpc_call(
         xid, 100000, // rpcbind
@@ -197,7 +388,7 @@ pub async fn rpcbind_unregister(program: u32, version: u32, proto: &str) -> Resu
         &body.buf,
     );
 
-    let _ = sock.send_to(&call, rpcbind_addr).await?;
+    let _ = sock.send_to(&call, rpcbind).await?;
     Ok(())
 }
 
@@ -212,5 +403,198 @@ pub fn rpc_prog_mismatch_reply(xid: u32, low: u32, high: u32) -> Vec<u8> {
     w.put_u32(low);
     w.put_u32(high);
 
-    w.buf.to_vec()
+    w.into_vec()
+}
+
+/// What a handler made of one call. Most replies are built on the
+/// spot; a call relayed elsewhere only has a reply once the other
+/// server answers, and the transport awaits that outside the fs queue
+/// so waiting on the network never holds a slot.
+pub enum Reply {
+    Ready(Option<Vec<u8>>),
+    Pending(Pin<Box<dyn Future<Output = Vec<u8>> + Send>>),
+}
+
+impl Reply {
+    pub async fn finish(self) -> Option<Vec<u8>> {
+        match self {
+            Reply::Ready(reply) => reply,
+            Reply::Pending(reply) => Some(reply.await),
+        }
+    }
+}
+
+impl From<Option<Vec<u8>>> for Reply {
+    fn from(reply: Option<Vec<u8>>) -> Self {
+        Reply::Ready(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{allocations, call};
+    use std::time::Instant;
+
+    /// Call header up to the credential, which the test supplies.
+    fn with_cred(flavor: u32, body: &[u8]) -> Vec<u8> {
+        let mut w = XdrW::new();
+        for v in [7, MsgType::Call as u32, RPC_VERSION, 100003, 2, 0, flavor] {
+            w.put_u32(v);
+        }
+        w.put_opaque(body);
+        w.put_u32(AUTH_NULL);
+        w.put_u32(0);
+        w.into_vec()
+    }
+
+    fn unix_cred(extra: &[u8]) -> Vec<u8> {
+        let mut w = XdrW::new();
+        w.put_u32(0);
+        w.put_string("host");
+        w.put_u32(1000);
+        w.put_u32(1000);
+        w.put_u32(1);
+        w.put_u32(20);
+        w.buf.extend_from_slice(extra);
+        w.into_vec()
+    }
+
+    fn garbage(pkt: &[u8]) -> bool {
+        matches!(decode_call(pkt), Err(CallError::Garbage { xid: 7 }))
+    }
+
+    #[test]
+    fn well_formed_credentials_decode() {
+        let (c, ofs) = decode_call(&with_cred(AUTH_UNIX, &unix_cred(&[]))).unwrap();
+        let RpcAuth::Unix(u) = c.auth else {
+            panic!("expected AUTH_UNIX");
+        };
+        assert_eq!((u.uid, u.gid, u.aux_gids), (1000, 1000, vec![20]));
+        assert_eq!(ofs, with_cred(AUTH_UNIX, &unix_cred(&[])).len());
+
+        let pkt = call(100003, 2, 0, None, &[]);
+        assert!(matches!(decode_call(&pkt).unwrap().0.auth, RpcAuth::Null));
+    }
+
+    #[test]
+    fn auth_null_cred_with_a_body_is_garbage() {
+        assert!(garbage(&with_cred(AUTH_NULL, &[0; 4])));
+    }
+
+    #[test]
+    fn auth_unix_length_must_match_its_body() {
+        // declared longer than the structure
+        assert!(garbage(&with_cred(AUTH_UNIX, &unix_cred(&[0; 4]))));
+        // declared shorter: the gid list runs off the end
+        let cred = unix_cred(&[]);
+        assert!(garbage(&with_cred(AUTH_UNIX, &cred[..cred.len() - 4])));
+    }
+
+    #[test]
+    fn auth_null_verifier_with_a_body_is_garbage() {
+        let mut pkt = with_cred(AUTH_NULL, &[]);
+        let n = pkt.len();
+        pkt[n - 4..].copy_from_slice(&4u32.to_be_bytes());
+        pkt.extend_from_slice(&[0; 4]);
+        assert!(garbage(&pkt));
+    }
+
+    #[test]
+    fn verifier_may_end_without_its_pad() {
+        // AUTH_NULL verifier ending the packet: decodes, no arguments
+        let pkt = with_cred(AUTH_NULL, &[]);
+        let (c, ofs) = decode_call(&pkt).unwrap();
+        assert_eq!((c.xid, ofs), (7, pkt.len()));
+
+        // a 3-byte verifier of another flavor, last pad byte missing
+        let mut pkt = with_cred(AUTH_NULL, &[]);
+        let n = pkt.len();
+        pkt[n - 8..].copy_from_slice(&[0, 0, 0, 6, 0, 0, 0, 3]);
+        pkt.extend_from_slice(b"abc");
+        assert_eq!(decode_call(&pkt).unwrap().1, pkt.len());
+
+        // cut inside the verifier: answered with GARBAGE_ARGS, not dropped
+        let pkt = &with_cred(AUTH_NULL, &[])[..n - 2];
+        let Err(e) = decode_call(pkt) else {
+            panic!("truncated verifier accepted");
+        };
+        assert!(matches!(e, CallError::Garbage { xid: 7 }));
+        let reply = e.reply().expect("a reply");
+        assert_eq!(reply[20..24], GARBAGE_ARGS.to_be_bytes());
+    }
+
+    /// A GETATTR-sized reply the way it was built before replies shared
+    /// one buffer: result and header each in their own growing buffer,
+    /// the header copied out and the result appended.
+    fn two_buffer_reply(xid: u32) -> Vec<u8> {
+        let mut body = XdrW::new();
+        for v in 0..18 {
+            body.put_u32(v);
+        }
+        let mut header = XdrW::new();
+        for v in [xid, MsgType::Reply as u32, 0, 0, 0, SUCCESS] {
+            header.put_u32(v);
+        }
+        let mut v = header.buf.to_vec();
+        v.extend_from_slice(&body.buf);
+        v
+    }
+
+    /// A GETATTR-sized result written the way handlers write it.
+    fn getattr_reply(xid: u32) -> Vec<u8> {
+        let mut w = rpc_accept_header(xid, SUCCESS);
+        for v in 0..18 {
+            w.put_u32(v);
+        }
+        w.into_vec()
+    }
+
+    #[test]
+    fn reply_bytes_are_unchanged() {
+        let body: Vec<u8> = (0..18u32).flat_map(u32::to_be_bytes).collect();
+        let want = two_buffer_reply(9);
+        assert_eq!(getattr_reply(9), want);
+        assert_eq!(rpc_accept_reply(9, SUCCESS, &body), want);
+        assert_eq!(
+            rpc_accept_reply(9, GARBAGE_ARGS, &[]),
+            [
+                0, 0, 0, 9, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4
+            ]
+        );
+    }
+
+    #[test]
+    fn small_reply_is_one_allocation() {
+        let before = allocations();
+        let reply = getattr_reply(1);
+        assert_eq!(allocations() - before, 1);
+        assert_eq!(reply.len(), 24 + 18 * 4);
+    }
+
+    /// Allocations and time per reply, old way against new:
+    /// `cargo test --release bench_reply_assembly -- --ignored --nocapture`
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_reply_assembly() {
+        const N: u32 = 1_000_000;
+
+        let run = |name: &str, f: &dyn Fn(u32) -> Vec<u8>| {
+            let (a0, t0) = (allocations(), Instant::now());
+            let mut total = 0;
+            for xid in 0..N {
+                total += std::hint::black_box(f(xid)).len();
+            }
+            let (allocs, elapsed) = (allocations() - a0, t0.elapsed());
+            println!(
+                "{name}: {:.2} allocations/reply, {:.0} ns/reply ({total} bytes)",
+                allocs as f64 / N as f64,
+                elapsed.as_nanos() as f64 / N as f64
+            );
+            allocs
+        };
+        let old = run("header + copy", &two_buffer_reply);
+        let new = run("one buffer   ", &getattr_reply);
+        assert!(new < old);
+    }
 }
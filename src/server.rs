@@ -0,0 +1,801 @@
+// src/server.rs
+
+use crate::{
+    combined::Combined,
+    env::Env,
+    export::Exports,
+    fsqueue::FsQueue,
+    mountd::{self, MOUNT_PROG, MountTable},
+    nfs2::{self, DisabledProcs, FhKey, NFS_PROG},
+    rpc,
+    shutdown::Shutdown,
+    tcp,
+    upstream::Upstream,
+};
+use anyhow::{Result, bail};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, UdpSocket, UnixListener};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// How long shutdown waits for in-flight requests.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often each instance checks that rpcbind still has its ports.
+const RPCBIND_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Well known mountd port used by the default instance.
+pub const MOUNTD_PORT: u16 = 20048;
+
+/// Calls slower than this are logged as warnings; zero disables it.
+/// Shared with the config reloader, so a change applies to the next call.
+#[derive(Clone, Default)]
+pub struct SlowThreshold(Arc<AtomicU64>);
+
+impl SlowThreshold {
+    pub fn set(&self, d: Duration) {
+        self.0.store(d.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn exceeded_by(&self, d: Duration) -> bool {
+        let t = self.0.load(Ordering::Relaxed);
+        t != 0 && d.as_micros() as u64 > t
+    }
+}
+
+/// One nfsd + mountd pair serving its own subset of the exports.
+#[derive(Clone)]
+pub struct Instance {
+    pub name: String,
+    pub exports: Exports,
+    /// 0 picks an ephemeral port
+    pub nfs_port: u16,
+    pub mountd_port: u16,
+    /// program numbers registered with rpcbind
+    pub nfs_prog: u32,
+    pub mount_prog: u32,
+    /// also serve both programs on this Unix socket
+    pub unix_socket: Option<PathBuf>,
+}
+
+impl Instance {
+    /// Single instance serving every export on the classic ports.
+    pub fn default_for(exports: Exports) -> Self {
+        Self {
+            name: "default".into(),
+            exports,
+            nfs_port: 0,
+            mountd_port: MOUNTD_PORT,
+            nfs_prog: NFS_PROG,
+            mount_prog: MOUNT_PROG,
+            unix_socket: None,
+        }
+    }
+
+    async fn unregister(&self, env: &Env, rpcbind: SocketAddr) -> Result<()> {
+        // mountd: versions 1,2,3 on both transports
+        for v in [1u32, 2u32, 3u32] {
+            rpc::rpcbind_unregister(env, rpcbind, self.mount_prog, v, "udp").await?;
+            rpc::rpcbind_unregister(env, rpcbind, self.mount_prog, v, "tcp").await?;
+        }
+
+        // nfs v2
+        rpc::rpcbind_unregister(env, rpcbind, self.nfs_prog, 2, "udp").await?;
+        rpc::rpcbind_unregister(env, rpcbind, self.nfs_prog, 2, "tcp").await?;
+
+        Ok(())
+    }
+
+    /// What we register with rpcbind, given the (udp, tcp) ports of each
+    /// service. NFS comes first; the watcher probes it.
+    fn mappings(&self, nfs: (u16, u16), mountd: (u16, u16)) -> Vec<Mapping> {
+        let mut v = vec![
+            Mapping::new(self.nfs_prog, 2, rpc::IPPROTO_UDP, nfs.0),
+            Mapping::new(self.nfs_prog, 2, rpc::IPPROTO_TCP, nfs.1),
+        ];
+        // mountd versions commonly queried by clients
+        for vers in [1u32, 2u32, 3u32] {
+            v.push(Mapping::new(
+                self.mount_prog,
+                vers,
+                rpc::IPPROTO_UDP,
+                mountd.0,
+            ));
+            v.push(Mapping::new(
+                self.mount_prog,
+                vers,
+                rpc::IPPROTO_TCP,
+                mountd.1,
+            ));
+        }
+        v
+    }
+
+    /// The instance's mountd and nfsd, sharing a mount table of their
+    /// own and the server-wide settings.
+    pub fn services(&self, server: &Server) -> (mountd::Mountd, nfs2::Nfs2) {
+        let mount_table: MountTable = Arc::new(Mutex::new(HashMap::new()));
+        let mountd = mountd::Mountd::new(
+            self.exports.clone(),
+            mount_table.clone(),
+            server.fh_key.clone(),
+            self.mount_prog,
+        )
+        .slow_threshold(server.slow_threshold.clone());
+        let nfsd = nfs2::Nfs2::new(
+            self.exports.clone(),
+            mount_table,
+            server.fh_key.clone(),
+            server.disabled_procs.clone(),
+            server.env.clone(),
+            self.nfs_prog,
+            server.fs_queue.clone(),
+        )
+        .upstream(server.upstream.clone())
+        .slow_threshold(server.slow_threshold.clone());
+        (mountd, nfsd)
+    }
+
+    /// Bind sockets, register with rpcbind and spawn the service tasks.
+    async fn start(&self, server: &Server) -> Result<Vec<JoinHandle<()>>> {
+        let name = self.name.as_str();
+        let (env, stop) = (&server.env, &server.stop);
+        let max_inflight = server.tcp_max_inflight;
+        let queue = &server.fs_queue;
+        let (mountd, nfsd) = self.services(server);
+
+        let mut tasks = Vec::new();
+        if let Some(path) = &self.unix_socket {
+            let listener = bind_unix(path)?;
+            let svc = Combined::new(mountd.clone(), nfsd.clone());
+            tasks.push(tokio::spawn(svc.run_unix(
+                listener,
+                path.clone(),
+                stop.clone(),
+                server.fs_queue.clone(),
+                max_inflight,
+            )));
+        }
+
+        //
+        // ---- Unregister stale entries from rpcbind ----
+        //
+        self.unregister(env, server.rpcbind).await?;
+
+        if server.single_port {
+            tasks.extend(
+                self.start_combined(server, Combined::new(mountd, nfsd))
+                    .await?,
+            );
+            return Ok(tasks);
+        }
+
+        //
+        // ---- Bind UDP sockets ----
+        //
+
+        let mountd_udp = UdpSocket::bind(("0.0.0.0", self.mountd_port)).await?;
+        let mountd_udp_port = mountd_udp.local_addr()?.port();
+
+        let nfs_udp = UdpSocket::bind(("0.0.0.0", self.nfs_port)).await?;
+        let nfs_udp_port = nfs_udp.local_addr()?.port();
+
+        //
+        // ---- Bind TCP sockets ----
+        //
+
+        let mountd_tcp = TcpListener::bind(("0.0.0.0", self.mountd_port)).await?;
+        let mountd_tcp_port = mountd_tcp.local_addr()?.port();
+
+        let nfs_tcp = TcpListener::bind(("0.0.0.0", self.nfs_port)).await?;
+        let nfs_tcp_port = nfs_tcp.local_addr()?.port();
+
+        //
+        // ---- Register with rpcbind ----
+        //
+
+        let mappings = self.mappings(
+            (nfs_udp_port, nfs_tcp_port),
+            (mountd_udp_port, mountd_tcp_port),
+        );
+        register(env, server.rpcbind, &mappings).await?;
+        tasks.push(tokio::spawn(watch_rpcbind(
+            env.clone(),
+            server.rpcbind,
+            self.name.clone(),
+            mappings,
+            RPCBIND_CHECK_INTERVAL,
+            stop.clone(),
+        )));
+
+        //
+        // ---- Start servers ----
+        //
+
+        tasks.extend([
+            tokio::spawn(
+                mountd
+                    .clone()
+                    .run_udp(mountd_udp, stop.clone(), queue.clone()),
+            ),
+            tokio::spawn(mountd.run_tcp(mountd_tcp, stop.clone(), queue.clone(), max_inflight)),
+            tokio::spawn(nfsd.clone().run_udp(nfs_udp, stop.clone(), queue.clone())),
+            tokio::spawn(nfsd.run_tcp(nfs_tcp, stop.clone(), queue.clone(), max_inflight)),
+        ]);
+
+        info!(
+            name,
+            nfs_port = nfs_udp_port,
+            mountd_port = mountd_udp_port,
+            exports = self.exports.list().len(),
+            "instance started"
+        );
+        Ok(tasks)
+    }
+
+    /// `single_port`: both programs on the mountd port, registered
+    /// there with rpcbind and routed by program number.
+    async fn start_combined(&self, server: &Server, svc: Combined) -> Result<Vec<JoinHandle<()>>> {
+        let (env, stop, queue) = (&server.env, &server.stop, &server.fs_queue);
+
+        let udp = UdpSocket::bind(("0.0.0.0", self.mountd_port)).await?;
+        let udp_port = udp.local_addr()?.port();
+        let tcp = TcpListener::bind(("0.0.0.0", self.mountd_port)).await?;
+        let tcp_port = tcp.local_addr()?.port();
+
+        let mappings = self.mappings((udp_port, tcp_port), (udp_port, tcp_port));
+        register(env, server.rpcbind, &mappings).await?;
+
+        let tasks = vec![
+            tokio::spawn(svc.clone().run_udp(udp, stop.clone(), queue.clone())),
+            tokio::spawn(svc.run_tcp(tcp, stop.clone(), queue.clone(), server.tcp_max_inflight)),
+            tokio::spawn(watch_rpcbind(
+                env.clone(),
+                server.rpcbind,
+                self.name.clone(),
+                mappings,
+                RPCBIND_CHECK_INTERVAL,
+                stop.clone(),
+            )),
+        ];
+
+        info!(
+            name = self.name.as_str(),
+            port = udp_port,
+            exports = self.exports.list().len(),
+            "instance started (single port)"
+        );
+        Ok(tasks)
+    }
+}
+
+/// One program/version/protocol to port mapping held by rpcbind.
+#[derive(Clone, Copy)]
+struct Mapping {
+    prog: u32,
+    vers: u32,
+    proto: u32,
+    port: u16,
+}
+
+impl Mapping {
+    fn new(prog: u32, vers: u32, proto: u32, port: u16) -> Self {
+        Self {
+            prog,
+            vers,
+            proto,
+            port,
+        }
+    }
+}
+
+async fn register(env: &Env, rpcbind: SocketAddr, mappings: &[Mapping]) -> Result<()> {
+    for m in mappings {
+        rpc::rpcbind_register(env, rpcbind, m.prog, m.vers, m.proto, m.port).await?;
+    }
+    Ok(())
+}
+
+/// rpcbind forgets every mapping when it restarts, after which clients
+/// can no longer find us. Ask it every `every` for our NFS port and put
+/// all of the instance's mappings back if it is gone. A mapping to some
+/// other port belongs to another program (or a second copy of us);
+/// that is reported once and left alone.
+async fn watch_rpcbind(
+    env: Env,
+    rpcbind: SocketAddr,
+    name: String,
+    mappings: Vec<Mapping>,
+    every: Duration,
+    stop: Shutdown,
+) {
+    let Some(probe) = mappings.first().copied() else {
+        return;
+    };
+    let mut taken = false;
+
+    loop {
+        tokio::select! {
+            _ = stop.stopped() => break,
+            _ = tokio::time::sleep(every) => {}
+        }
+
+        let found =
+            match rpc::rpcbind_getport(&env, rpcbind, probe.prog, probe.vers, probe.proto).await {
+                Ok(found) => found,
+                Err(e) => {
+                    // rpcbind down: nothing to register with until it is back
+                    debug!(name, ?e, "rpcbind check failed");
+                    continue;
+                }
+            };
+
+        match found {
+            Some(port) if port == probe.port => taken = false,
+            Some(port) => {
+                if !taken {
+                    warn!(
+                        name,
+                        prog = probe.prog,
+                        port,
+                        ours = probe.port,
+                        "rpcbind maps our program to another port; leaving it alone"
+                    );
+                    taken = true;
+                }
+            }
+            None => {
+                taken = false;
+                info!(
+                    name,
+                    expected = probe.port,
+                    "rpcbind lost our registration, restoring"
+                );
+                match register(&env, rpcbind, &mappings).await {
+                    Ok(()) => info!(name, "rpcbind registration restored"),
+                    Err(e) => warn!(name, ?e, "rpcbind re-registration failed"),
+                }
+            }
+        }
+    }
+}
+
+/// Bind a Unix socket, replacing one left behind by an earlier run.
+fn bind_unix(path: &Path) -> Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(m) if m.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => bail!("unix_socket {}: exists and is not a socket", path.display()),
+        Err(_) => {}
+    }
+    Ok(UnixListener::bind(path)?)
+}
+
+/// All nfsd/mountd instances of this process.
+pub struct Server {
+    instances: Vec<Instance>,
+    env: Env,
+    /// rpcbind to register with; tests point this at a stand-in
+    rpcbind: SocketAddr,
+    stop: Shutdown,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+    metrics_addr: Option<SocketAddr>,
+    fh_key: FhKey,
+    tcp_max_inflight: usize,
+    fs_queue: FsQueue,
+    single_port: bool,
+    upstream: Option<Upstream>,
+    disabled_procs: DisabledProcs,
+    slow_threshold: SlowThreshold,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self {
+            instances: Vec::new(),
+            env: Env::default(),
+            rpcbind: rpc::RPCBIND_ADDR,
+            stop: Shutdown::default(),
+            tasks: Mutex::default(),
+            metrics_addr: None,
+            fh_key: FhKey::default(),
+            tcp_max_inflight: tcp::DEFAULT_MAX_INFLIGHT,
+            fs_queue: FsQueue::default(),
+            single_port: false,
+            upstream: None,
+            disabled_procs: DisabledProcs::default(),
+            slow_threshold: SlowThreshold::default(),
+        }
+    }
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn instance(mut self, inst: Instance) -> Self {
+        self.instances.push(inst);
+        self
+    }
+
+    /// Sign file handles with this key.
+    pub fn fh_key(mut self, key: FhKey) -> Self {
+        self.fh_key = key;
+        self
+    }
+
+    /// Calls a TCP connection may have outstanding before we stop reading.
+    pub fn tcp_max_inflight(mut self, n: usize) -> Self {
+        self.tcp_max_inflight = n;
+        self
+    }
+
+    /// Calls, across all instances, that may touch the filesystem at once.
+    pub fn fs_concurrency(mut self, n: usize) -> Self {
+        self.fs_queue = FsQueue::new(n);
+        self
+    }
+
+    /// Serve NFS on each instance's mountd port instead of its own.
+    pub fn single_port(mut self, on: bool) -> Self {
+        self.single_port = on;
+        self
+    }
+
+    /// Forward NFS procedures we do not implement to this server.
+    pub fn upstream(mut self, upstream: Option<Upstream>) -> Self {
+        self.upstream = upstream;
+        self
+    }
+
+    /// Switch NFS procedures off (bit n = procedure n). Takes effect for
+    /// the next call, also while running.
+    pub fn set_disabled_procs(&self, mask: u32) {
+        self.disabled_procs.set(mask);
+    }
+
+    /// Warn about calls slower than `d` (zero: never). Takes effect for
+    /// the next call, also while running.
+    pub fn set_slow_threshold(&self, d: Duration) {
+        self.slow_threshold.set(d);
+    }
+
+    /// Serve Prometheus metrics over HTTP (needs the `metrics-http` feature).
+    pub fn metrics_addr(mut self, addr: Option<SocketAddr>) -> Self {
+        self.metrics_addr = addr;
+        self
+    }
+
+    /// rpcbind keeps one port per program, so every instance needs its
+    /// own program numbers.
+    pub fn validate(&self) -> Result<()> {
+        let mut progs = HashSet::new();
+        for i in &self.instances {
+            for prog in [i.nfs_prog, i.mount_prog] {
+                if !progs.insert(prog) {
+                    bail!(
+                        "server '{}': program {} is already used by another instance",
+                        i.name,
+                        prog
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Start every instance.
+    pub async fn start(&self) -> Result<()> {
+        for i in &self.instances {
+            let tasks = i.start(self).await?;
+            self.tasks.lock().unwrap().extend(tasks);
+        }
+
+        if let Some(addr) = self.metrics_addr {
+            #[cfg(feature = "metrics-http")]
+            {
+                let listener = TcpListener::bind(addr).await?;
+                let task = tokio::spawn(crate::metrics_http::run(listener, self.stop.clone()));
+                self.tasks.lock().unwrap().push(task);
+            }
+            #[cfg(not(feature = "metrics-http"))]
+            warn!(%addr, "metrics_addr is set but this build lacks the metrics-http feature");
+        }
+        Ok(())
+    }
+
+    /// Remove every instance's rpcbind registrations.
+    pub async fn unregister(&self) -> Result<()> {
+        for i in &self.instances {
+            i.unregister(&self.env, self.rpcbind).await?;
+        }
+        Ok(())
+    }
+
+    /// Orderly stop: listeners first, then in-flight work, then the
+    /// rpcbind registrations, then the tasks themselves. Callers should
+    /// bound the whole thing with a timeout.
+    pub async fn shutdown(&self) {
+        info!("shutdown: stopping listeners");
+        self.stop.trigger();
+
+        info!(
+            inflight = self.stop.inflight(),
+            "shutdown: draining in-flight requests"
+        );
+        if !self.stop.drain(DRAIN_TIMEOUT).await {
+            warn!(
+                inflight = self.stop.inflight(),
+                "shutdown: drain timed out, abandoning requests"
+            );
+        }
+
+        info!("shutdown: unregistering RPC services");
+        if let Err(e) = self.unregister().await {
+            warn!(?e, "rpcbind unregister failed");
+        }
+
+        // No handle database to persist yet: handles are derived from
+        // dev/ino and survive restarts on their own.
+
+        info!("shutdown: joining service tasks");
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        for t in tasks {
+            if let Err(e) = t.await {
+                warn!(?e, "service task failed");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn slow_threshold_changes_reach_every_holder() {
+        let server = Server::new();
+        let held = server.slow_threshold.clone();
+        assert!(!held.exceeded_by(Duration::from_secs(60)), "off by default");
+
+        server.set_slow_threshold(Duration::from_millis(100));
+        assert!(held.exceeded_by(Duration::from_millis(101)));
+        assert!(!held.exceeded_by(Duration::from_millis(100)));
+
+        server.set_slow_threshold(Duration::ZERO);
+        assert!(!held.exceeded_by(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn instances_keep_their_exports_and_mounts_apart() {
+        use crate::rpc::{PROG_UNAVAIL, SUCCESS};
+        use crate::testutil::{CLIENT, TempDir, accepted, call, export};
+        use crate::xdr::XdrW;
+
+        let (dir_a, dir_b) = (TempDir::new(), TempDir::new());
+        let instance = |name: &str, dir: &TempDir, prog: u32| Instance {
+            name: name.into(),
+            nfs_prog: prog,
+            mount_prog: prog + 1,
+            ..Instance::default_for(Exports::new(vec![export(dir.path())]))
+        };
+        let server = Server::new()
+            .instance(instance("a", &dir_a, 0x2000_0000))
+            .instance(instance("b", &dir_b, 0x2000_0010));
+        server.validate().unwrap();
+        let [(mountd_a, nfsd_a), (mountd_b, nfsd_b)] =
+            [0, 1].map(|i| server.instances[i].services(&server));
+        let (a, b) = (&server.instances[0], &server.instances[1]);
+
+        let mut args = XdrW::new();
+        args.put_string(dir_a.path().to_str().unwrap());
+        let mnt = |m: &mountd::Mountd, prog| {
+            m.handle_call(&call(prog, 1, 1, Some(0), &args.buf), CLIENT)
+                .unwrap()
+        };
+        let reply = mnt(&mountd_a, a.mount_prog);
+        let (_, mut r) = accepted(&reply);
+        assert_eq!(r.get_u32().unwrap(), 0);
+        let fh = r.get_fixed(32).unwrap();
+        // B does not export A's directory
+        let reply = mnt(&mountd_b, b.mount_prog);
+        assert_eq!(accepted(&reply).1.get_u32().unwrap(), 13); // NFSERR_ACCES
+        // nor answer for A's program number
+        let reply = mnt(&mountd_b, a.mount_prog);
+        assert_eq!(accepted(&reply).0, PROG_UNAVAIL);
+
+        let getattr = |n: &nfs2::Nfs2, prog, fh: &[u8]| match n
+            .handle_call(&call(prog, 2, 1, Some(0), fh), CLIENT)
+        {
+            rpc::Reply::Ready(Some(reply)) => {
+                let (stat, mut r) = accepted(&reply);
+                (stat, r.get_u32().unwrap_or(u32::MAX))
+            }
+            _ => panic!("no reply on the spot"),
+        };
+        assert_eq!(getattr(&nfsd_a, a.nfs_prog, &fh), (SUCCESS, 0));
+        assert_eq!(getattr(&nfsd_b, b.nfs_prog, &fh), (SUCCESS, 70)); // NFSERR_STALE
+        assert_eq!(getattr(&nfsd_b, a.nfs_prog, &fh).0, PROG_UNAVAIL);
+
+        // no handle: the caller's mount root, which only A's table holds
+        assert_eq!(getattr(&nfsd_a, a.nfs_prog, &[]), (SUCCESS, 0));
+        assert_eq!(getattr(&nfsd_b, b.nfs_prog, &[]), (SUCCESS, 70));
+    }
+
+    type Table = Arc<Mutex<HashMap<(u32, u32, u32), u16>>>;
+
+    /// Procedure numbers the rpcbind stand-in was called with, in order.
+    type Calls = Arc<Mutex<Vec<u32>>>;
+
+    /// rpcbind stand-in answering SET and GETPORT from `table`; returns
+    /// its address and the calls it answered.
+    async fn mock_rpcbind(table: Table, stop: Shutdown) -> (SocketAddr, Calls) {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = sock.local_addr().unwrap();
+        let calls = Calls::default();
+        let log = calls.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            loop {
+                let (n, peer) = tokio::select! {
+                    _ = stop.stopped() => break,
+                    res = sock.recv_from(&mut buf) => res.unwrap(),
+                };
+                let (call, ofs) = rpc::decode_call(&buf[..n]).unwrap();
+                let mut r = crate::xdr::XdrR::new(&buf[ofs..n]);
+                let key = (
+                    r.get_u32().unwrap(),
+                    r.get_u32().unwrap(),
+                    r.get_u32().unwrap(),
+                );
+                let port = r.get_u32().unwrap() as u16;
+
+                let mut w = rpc::rpc_accept_header(call.xid, rpc::SUCCESS);
+                match call.procid {
+                    rpc::RPCBPROC_SET => {
+                        // like rpcbind, SET does not replace a mapping
+                        let mut t = table.lock().unwrap();
+                        let fresh = !t.contains_key(&key);
+                        t.entry(key).or_insert(port);
+                        w.put_u32(fresh as u32);
+                    }
+                    rpc::RPCBPROC_GETPORT => {
+                        w.put_u32(table.lock().unwrap().get(&key).copied().unwrap_or(0) as u32);
+                    }
+                    _ => continue,
+                }
+                // logged before answering, so the watcher's next call
+                // never shows up ahead of this one
+                log.lock().unwrap().push(call.procid);
+                sock.send_to(&w.into_vec(), peer).await.unwrap();
+            }
+        });
+        (addr, calls)
+    }
+
+    fn count(calls: &Calls, procid: u32) -> usize {
+        calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&&p| p == procid)
+            .count()
+    }
+
+    fn ours() -> Vec<Mapping> {
+        Instance::default_for(Exports::new(Vec::new())).mappings((2049, 2049), (20048, 20048))
+    }
+
+    /// Run the watcher until it has checked rpcbind `checks` times.
+    async fn watch(rpcbind: SocketAddr, calls: &Calls, checks: usize) {
+        let stop = Shutdown::new();
+        let task = tokio::spawn(watch_rpcbind(
+            Env::default(),
+            rpcbind,
+            "test".into(),
+            ours(),
+            Duration::from_millis(20),
+            stop.clone(),
+        ));
+        let t0 = Instant::now();
+        while count(calls, rpc::RPCBPROC_GETPORT) < checks {
+            assert!(
+                t0.elapsed() < Duration::from_secs(5),
+                "watcher checked {} times",
+                count(calls, rpc::RPCBPROC_GETPORT)
+            );
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        stop.trigger();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn dropped_registration_is_restored() {
+        let table = Table::default();
+        let stop = Shutdown::new();
+        let (addr, calls) = mock_rpcbind(table.clone(), stop.clone()).await;
+
+        // rpcbind restarted: it knows nothing about us. The first check
+        // finds that out, the second finds the mappings back.
+        watch(addr, &calls, 2).await;
+        stop.trigger();
+
+        let t = table.lock().unwrap();
+        for m in ours() {
+            assert_eq!(t.get(&(m.prog, m.vers, m.proto)), Some(&m.port));
+        }
+        // restored once, then found in place
+        assert_eq!(count(&calls, rpc::RPCBPROC_SET), ours().len());
+    }
+
+    #[tokio::test]
+    async fn mapping_held_by_someone_else_is_left_alone() {
+        let table = Table::default();
+        let nfs = (NFS_PROG, 2, rpc::IPPROTO_UDP);
+        table.lock().unwrap().insert(nfs, 3049);
+        let stop = Shutdown::new();
+        let (addr, calls) = mock_rpcbind(table.clone(), stop.clone()).await;
+
+        watch(addr, &calls, 3).await;
+        stop.trigger();
+
+        assert_eq!(count(&calls, rpc::RPCBPROC_SET), 0);
+        assert_eq!(table.lock().unwrap().get(&nfs), Some(&3049));
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_before_unregistering() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        // rpcbind stand-in noting when the first UNSET arrives
+        let rpcbind = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut server = Server::new().instance(Instance::default_for(Exports::new(Vec::new())));
+        server.rpcbind = rpcbind.local_addr().unwrap();
+        let seen = log.clone();
+        let unsets = tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            rpcbind.recv_from(&mut buf).await.unwrap();
+            seen.lock().unwrap().push("unregister");
+        });
+
+        let (_, nfsd) = server.instances[0].services(&server);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let nfs_addr = listener.local_addr().unwrap();
+        let stop = server.stop.clone();
+        let listen = tokio::spawn(nfsd.run_tcp(listener, stop.clone(), FsQueue::default(), 4));
+
+        // a call that is still running when shutdown starts
+        let guard = stop.track();
+        let seen = log.clone();
+        let call = tokio::spawn(async move {
+            stop.stopped().await;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let closed = tokio::net::TcpStream::connect(nfs_addr).await.is_err();
+            seen.lock().unwrap().push(if closed {
+                "call done, listener closed"
+            } else {
+                "call done, listener open"
+            });
+            drop(guard);
+        });
+        server.tasks.lock().unwrap().extend([listen, call]);
+
+        server.shutdown().await;
+        unsets.await.unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["call done, listener closed", "unregister"]
+        );
+        assert_eq!(server.stop.inflight(), 0);
+        assert!(server.tasks.lock().unwrap().is_empty(), "tasks joined");
+    }
+}
@@ -0,0 +1,110 @@
+// src/shutdown.rs
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::{Instant, sleep};
+
+/// Stop signal shared by every service loop, plus a count of requests
+/// being handled so shutdown can wait for them.
+#[derive(Clone)]
+pub struct Shutdown {
+    stop: Arc<watch::Sender<bool>>,
+    inflight: Arc<AtomicUsize>,
+}
+
+/// Marks one request as in flight until dropped.
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self {
+            stop: Arc::new(watch::Sender::new(false)),
+            inflight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Tell every loop to stop taking new work.
+    pub fn trigger(&self) {
+        self.stop.send_replace(true);
+    }
+
+    /// Resolves once `trigger` has been called.
+    pub async fn stopped(&self) {
+        let mut rx = self.stop.subscribe();
+        let _ = rx.wait_for(|stop| *stop).await;
+    }
+
+    pub fn track(&self) -> InFlight {
+        self.inflight.fetch_add(1, Ordering::AcqRel);
+        InFlight(self.inflight.clone())
+    }
+
+    pub fn inflight(&self) -> usize {
+        self.inflight.load(Ordering::Acquire)
+    }
+
+    /// Wait until no request is in flight. Returns false on timeout.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.inflight() > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        true
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn drain_waits_for_every_guard() {
+        let stop = Shutdown::new();
+        let (a, b) = (stop.track(), stop.track());
+        assert_eq!(stop.inflight(), 2);
+
+        drop(a);
+        assert!(!stop.drain(Duration::from_millis(30)).await, "one left");
+
+        let release = tokio::spawn(async move {
+            sleep(Duration::from_millis(30)).await;
+            drop(b);
+        });
+        assert!(stop.drain(Duration::from_secs(5)).await);
+        assert_eq!(stop.inflight(), 0);
+        release.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stopped_resolves_for_every_clone() {
+        let stop = Shutdown::new();
+        let waiter = tokio::spawn({
+            let stop = stop.clone();
+            async move { stop.stopped().await }
+        });
+        sleep(Duration::from_millis(10)).await;
+        assert!(!waiter.is_finished());
+
+        stop.trigger();
+        waiter.await.unwrap();
+        // and for anyone who starts waiting later
+        stop.clone().stopped().await;
+    }
+}
@@ -0,0 +1,166 @@
+// src/tcp.rs
+
+use crate::fsqueue::FsQueue;
+use crate::rpc::Reply;
+use crate::shutdown::Shutdown;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::{JoinError, JoinHandle};
+use tracing::{Instrument, warn};
+
+/// Requests a connection may have read but not yet answered.
+pub const DEFAULT_MAX_INFLIGHT: usize = 16;
+
+// Largest record marked fragment we accept. NFSv2 calls top out around
+// 8 KiB of WRITE data plus headers; anything far bigger is hostile.
+const MAX_RECORD: usize = 64 * 1024;
+
+type Job = JoinHandle<Result<Option<Vec<u8>>, JoinError>>;
+
+/// Serve one record-marked RPC connection (TCP or Unix socket). Calls are handled
+/// concurrently but answered in order; once `max_inflight` replies are
+/// pending the next record is not read until the client drains some,
+/// so a client that never reads cannot make us buffer without bound.
+/// Handlers run through `queue`, which bounds them across connections;
+/// a pending reply is awaited after the handler has left it.
+pub async fn serve<S, H, R>(
+    stream: S,
+    stop: Shutdown,
+    queue: FsQueue,
+    max_inflight: usize,
+    handle: H,
+) where
+    S: AsyncRead + AsyncWrite + Send,
+    H: Fn(&[u8]) -> R + Clone + Send + 'static,
+    R: Into<Reply> + Send + 'static,
+{
+    let (mut rd, mut wr) = tokio::io::split(stream);
+    let (tx, mut rx) = mpsc::channel::<Job>(max_inflight.max(1));
+
+    let reader = async move {
+        loop {
+            let mut hdr = [0u8; 4];
+            tokio::select! {
+                _ = stop.stopped() => break,
+                res = rd.read_exact(&mut hdr) => if res.is_err() {
+                    break;
+                },
+            }
+
+            let marker = u32::from_be_bytes(hdr);
+            let len = (marker & 0x7fff_ffff) as usize;
+            if len > MAX_RECORD {
+                warn!(len, "TCP record too large, closing connection");
+                break;
+            }
+
+            let mut buf = vec![0u8; len];
+            if rd.read_exact(&mut buf).await.is_err() {
+                break;
+            }
+
+            let req = stop.track();
+            let handle = handle.clone();
+            let queue = queue.clone();
+            let job = tokio::spawn(
+                async move {
+                    let _req = req;
+                    match queue.run(move || handle(&buf).into()).await {
+                        Ok(reply) => Ok(reply.finish().await),
+                        Err(e) => Err(e),
+                    }
+                }
+                .in_current_span(),
+            );
+
+            // waits while max_inflight replies are queued
+            if tx.send(job).await.is_err() {
+                break;
+            }
+        }
+    };
+
+    let writer = async move {
+        while let Some(job) = rx.recv().await {
+            let reply = match job.await {
+                Ok(Ok(Some(reply))) => reply,
+                Ok(Ok(None)) => continue,
+                Ok(Err(e)) | Err(e) => {
+                    warn!(?e, "RPC handler failed");
+                    break;
+                }
+            };
+
+            let mut out = Vec::with_capacity(4 + reply.len());
+            out.extend_from_slice(&(0x8000_0000u32 | reply.len() as u32).to_be_bytes());
+            out.extend_from_slice(&reply);
+
+            if wr.write_all(&out).await.is_err() {
+                break;
+            }
+        }
+    };
+
+    // The writer outlives the reader to flush queued replies; a dead
+    // writer ends the connection even if the reader is idle.
+    tokio::pin!(writer);
+    tokio::select! {
+        _ = reader => (&mut writer).await,
+        _ = &mut writer => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn stalled_reader_bounds_pending_replies() {
+        const MAX_INFLIGHT: usize = 4;
+        const CALLS: usize = 1000;
+
+        // the client never reads, so the server's writes stall at once
+        let (mut client, server) = tokio::io::duplex(256);
+        let handled = Arc::new(AtomicUsize::new(0));
+        let stop = Shutdown::default();
+
+        let counter = handled.clone();
+        let srv = tokio::spawn(serve(
+            server,
+            stop.clone(),
+            FsQueue::default(),
+            MAX_INFLIGHT,
+            move |_: &[u8]| {
+                counter.fetch_add(1, Ordering::Relaxed);
+                Some(vec![0u8; 8192])
+            },
+        ));
+
+        let sender = tokio::spawn(async move {
+            let mut record = 0x8000_0004u32.to_be_bytes().to_vec();
+            record.extend_from_slice(&[0; 4]);
+            for _ in 0..CALLS {
+                if client.write_all(&record).await.is_err() {
+                    break;
+                }
+            }
+            client
+        });
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let n = handled.load(Ordering::Relaxed);
+        // a full channel, one job with the writer, one with the reader
+        assert!(
+            (MAX_INFLIGHT..=MAX_INFLIGHT + 2).contains(&n),
+            "{n} calls handled"
+        );
+        assert!(!sender.is_finished(), "the server kept reading");
+
+        stop.trigger();
+        sender.abort();
+        let _ = srv.await;
+    }
+}
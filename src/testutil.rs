@@ -0,0 +1,224 @@
+// src/testutil.rs
+
+//! Fixtures for the unit tests: scratch directories, exports and
+//! hand-built RPC calls.
+
+use crate::export::{Export, IdMap};
+use crate::rpc::{AUTH_NULL, AUTH_UNIX, MsgType, RPC_VERSION};
+use crate::xdr::{XdrR, XdrW};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Source address of test calls.
+pub const CLIENT: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 800));
+
+static NEXT_DIR: AtomicU32 = AtomicU32::new(0);
+
+/// Directory under the system temp dir, removed on drop.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let n = NEXT_DIR.fetch_add(1, Ordering::Relaxed);
+        let p = std::env::temp_dir().join(format!("nfs2-test-{}-{n}", std::process::id()));
+        let _ = fs::remove_dir_all(&p);
+        fs::create_dir_all(&p).unwrap();
+        // canonical, so it compares equal to what the server resolves
+        Self(fs::canonicalize(p).unwrap())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Writable export of `path` open to everybody, with nothing squashed.
+pub fn export(path: &Path) -> Export {
+    Export {
+        path: path.to_path_buf(),
+        read_only: false,
+        anon_uid: 65534,
+        anon_gid: 65534,
+        squash_uid: 65534,
+        squash_gid: 65534,
+        root_squash: false,
+        all_squash: false,
+        clients: Vec::new(),
+        uid_map: IdMap::default(),
+        gid_map: IdMap::default(),
+        read_ahead: false,
+        fixed_mtime: None,
+        alias: None,
+        max_name_len: None,
+        no_readdir: false,
+        riscos_xattr: None,
+        guest: false,
+        deref_symlinks: false,
+        max_file_size: None,
+        empty_fh_root_fallback: true,
+        change_ids: None,
+        archive: None,
+    }
+}
+
+/// RPC call with an AUTH_UNIX credential for `uid` (gid = uid), or
+/// AUTH_NULL without one.
+pub fn call(prog: u32, vers: u32, procid: u32, uid: Option<u32>, args: &[u8]) -> Vec<u8> {
+    let mut w = XdrW::new();
+    w.put_u32(0x1234_5678); // xid
+    w.put_u32(MsgType::Call as u32);
+    w.put_u32(RPC_VERSION);
+    w.put_u32(prog);
+    w.put_u32(vers);
+    w.put_u32(procid);
+
+    match uid {
+        Some(uid) => {
+            let mut cred = XdrW::new();
+            cred.put_u32(0); // stamp
+            cred.put_string("testhost");
+            cred.put_u32(uid);
+            cred.put_u32(uid);
+            cred.put_u32(0); // no aux gids
+            w.put_u32(AUTH_UNIX);
+            w.put_opaque(&cred.buf);
+        }
+        None => {
+            w.put_u32(AUTH_NULL);
+            w.put_u32(0);
+        }
+    }
+    w.put_u32(AUTH_NULL); // verifier
+    w.put_u32(0);
+
+    w.buf.extend_from_slice(args);
+    w.into_vec()
+}
+
+/// Check that `reply` is an accepted reply and return its accept_stat
+/// and a reader positioned at the procedure result.
+pub fn accepted(reply: &[u8]) -> (u32, XdrR<'_>) {
+    let mut r = XdrR::new(reply);
+    r.get_u32().unwrap(); // xid
+    assert_eq!(r.get_u32().unwrap(), MsgType::Reply as u32);
+    assert_eq!(r.get_u32().unwrap(), 0, "MSG_ACCEPTED");
+    r.get_u32().unwrap(); // verifier
+    r.get_opaque().unwrap();
+    let stat = r.get_u32().unwrap();
+    (stat, r)
+}
+
+/// Run `f` and return what it logged at WARN and above, as plain text.
+pub fn warnings<R>(f: impl FnOnce() -> R) -> (R, String) {
+    #[derive(Clone, Default)]
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let sink = Sink::default();
+    let writer = sink.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::WARN)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let out = tracing::subscriber::with_default(subscriber, f);
+    let text = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+    (out, text)
+}
+
+/// ustar archive of `(name, typeflag, contents)` members. Contents of
+/// links ('1', '2') are their target.
+pub fn tar(members: &[(&str, u8, &[u8])]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for &(name, typeflag, data) in members {
+        let is_link = matches!(typeflag, b'1' | b'2');
+        let size = if is_link { 0 } else { data.len() };
+        let mode: &[u8] = if typeflag == b'5' {
+            b"0000755\0"
+        } else {
+            b"0000644\0"
+        };
+
+        let mut h = [0u8; 512];
+        h[..name.len()].copy_from_slice(name.as_bytes());
+        h[100..108].copy_from_slice(mode);
+        h[108..116].copy_from_slice(b"0001750\0"); // uid 1000
+        h[116..124].copy_from_slice(b"0001750\0");
+        h[124..136].copy_from_slice(format!("{size:011o}\0").as_bytes());
+        h[136..148].copy_from_slice(b"14000000000\0");
+        h[148..156].fill(b' ');
+        h[156] = typeflag;
+        if is_link {
+            h[157..157 + data.len()].copy_from_slice(data);
+        }
+        h[257..263].copy_from_slice(b"ustar\0");
+        h[263..265].copy_from_slice(b"00");
+        let sum: u32 = h.iter().map(|&b| b as u32).sum();
+        h[148..156].copy_from_slice(format!("{sum:06o}\0 ").as_bytes());
+
+        out.extend_from_slice(&h);
+        if !is_link {
+            out.extend_from_slice(data);
+            out.resize(out.len().next_multiple_of(512), 0);
+        }
+    }
+    out.resize(out.len() + 1024, 0);
+    out
+}
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+/// System allocator that counts allocations per thread, so tests can
+/// measure what a piece of code allocates while others run.
+struct Counting;
+
+// SAFETY: forwards to `System`; the counter itself never allocates.
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        // SAFETY: same contract as ours
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // SAFETY: same contract as ours
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        // SAFETY: same contract as ours
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static COUNTING: Counting = Counting;
+
+/// Allocations and reallocations made by the current thread so far.
+pub fn allocations() -> u64 {
+    ALLOCATIONS.with(Cell::get)
+}
@@ -0,0 +1,136 @@
+// src/udp.rs
+
+use crate::fsqueue::FsQueue;
+use crate::rpc::Reply;
+use crate::shutdown::Shutdown;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::Semaphore;
+use tracing::{Instrument, info_span, warn};
+
+// Largest datagram we accept.
+const MAX_DATAGRAM: usize = 65536;
+
+/// Serve RPC datagrams on `sock`. Every call is handled in its own task
+/// through `queue`, so a slow call does not hold up the ones behind it.
+/// Once as many calls are pending as the queue runs at once, the socket
+/// is not read until one finishes; the kernel's receive buffer takes
+/// the rest, and clients retransmit what it drops. A pending reply is
+/// awaited after the handler has given its queue slot back.
+pub async fn serve<H, R>(sock: UdpSocket, stop: Shutdown, queue: FsQueue, handle: H)
+where
+    H: Fn(&[u8], SocketAddr) -> R + Clone + Send + 'static,
+    R: Into<Reply> + Send + 'static,
+{
+    let sock = Arc::new(sock);
+    let pending = Arc::new(Semaphore::new(queue.limit()));
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+
+    loop {
+        let permit = tokio::select! {
+            _ = stop.stopped() => break,
+            p = pending.clone().acquire_owned() => p.expect("never closed"),
+        };
+        let (n, peer) = tokio::select! {
+            _ = stop.stopped() => break,
+            res = sock.recv_from(&mut buf) => match res {
+                Ok(v) => v,
+                Err(_) => continue,
+            },
+        };
+        let req = stop.track();
+
+        let (sock, queue, handle) = (sock.clone(), queue.clone(), handle.clone());
+        let call = buf[..n].to_vec();
+        tokio::spawn(
+            async move {
+                let _req = req;
+                let _permit = permit;
+                let reply = match queue.run(move || handle(&call, peer).into()).await {
+                    Ok(reply) => reply.finish().await,
+                    Err(e) => {
+                        warn!(?e, "RPC handler failed");
+                        None
+                    }
+                };
+                if let Some(reply) = reply
+                    && let Err(e) = sock.send_to(&reply, peer).await
+                {
+                    warn!(?e, "UDP send failed");
+                }
+            }
+            .instrument(info_span!("udp", %peer)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    async fn bound(
+        queue: FsQueue,
+        handle: impl Fn(&[u8], SocketAddr) -> Option<Vec<u8>> + Clone + Send + 'static,
+    ) -> (UdpSocket, Shutdown) {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(sock.local_addr().unwrap()).await.unwrap();
+        let stop = Shutdown::default();
+        tokio::spawn(serve(sock, stop.clone(), queue, handle));
+        (client, stop)
+    }
+
+    #[tokio::test]
+    async fn slow_call_does_not_hold_up_the_next() {
+        let (client, stop) = bound(FsQueue::new(4), |buf: &[u8], _| {
+            if buf[0] == 1 {
+                std::thread::sleep(Duration::from_millis(500));
+            }
+            Some(buf.to_vec())
+        })
+        .await;
+
+        client.send(&[1]).await.unwrap();
+        client.send(&[2]).await.unwrap();
+        let mut buf = [0u8; 8];
+        let n = tokio::time::timeout(Duration::from_millis(300), client.recv(&mut buf))
+            .await
+            .expect("second call waited for the first")
+            .unwrap();
+        assert_eq!(&buf[..n], [2]);
+        stop.trigger();
+    }
+
+    #[tokio::test]
+    async fn calls_run_concurrently_up_to_the_queue_limit() {
+        const LIMIT: usize = 2;
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let (r, p) = (running.clone(), peak.clone());
+        let (client, stop) = bound(FsQueue::new(LIMIT), move |buf: &[u8], _| {
+            let now = r.fetch_add(1, Ordering::SeqCst) + 1;
+            p.fetch_max(now, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(50));
+            r.fetch_sub(1, Ordering::SeqCst);
+            Some(buf.to_vec())
+        })
+        .await;
+
+        for i in 0..10u8 {
+            client.send(&[i]).await.unwrap();
+        }
+        let mut buf = [0u8; 8];
+        for _ in 0..10 {
+            tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf))
+                .await
+                .expect("reply")
+                .unwrap();
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), LIMIT);
+        stop.trigger();
+    }
+}
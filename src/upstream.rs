@@ -0,0 +1,315 @@
+// src/upstream.rs
+
+use crate::env::Env;
+use crate::export::Export;
+use crate::mountd::{MOUNT_PROG, MOUNT_VERS_MIN};
+use crate::nfs2::{FH_SIZE, NFS_PROG, NFS_VERS};
+use crate::rpc::{
+    AUTH_UNIX, IPPROTO_UDP, MsgType, RPC_VERSION, RPCBIND_ADDR, RpcAuthUnix, SUCCESS,
+    encode_auth_unix, rpcbind_getport,
+};
+use crate::xdr::{XdrR, XdrW};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+// A UDP client has long retransmitted by then; give up and say so.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Largest UDP datagram.
+const MAX_REPLY: usize = 65535;
+
+// Source ports tried for our calls, as bindresvport(3) does: reserved,
+// but above the well-known services.
+const RESERVED_PORTS: std::ops::Range<u16> = 600..1024;
+
+// Translated handles kept; past this the cache starts over.
+const MAX_HANDLES: usize = 4096;
+
+/// Upstream handles by (export id, host path).
+type Handles = HashMap<(u32, PathBuf), Vec<u8>>;
+
+const MOUNTPROC_MNT: u32 = 1;
+const NFSPROC_LOOKUP: u32 = 4;
+
+/// Why one of our files has no upstream handle.
+#[derive(Debug)]
+pub enum HandleError {
+    /// MNT or LOOKUP failed with this status (an errno, for both).
+    Status(u32),
+    /// No usable answer: timeout, socket error or malformed reply.
+    Io(io::Error),
+}
+
+impl From<io::Error> for HandleError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Existing NFSv2 server that answers the procedures we do not
+/// implement (`upstream`), so clients can be moved over before the
+/// last of them is done. Its handles are its own: before a call
+/// leaves, ours are swapped for the ones it gave us for the same path
+/// through MNT and LOOKUP, remembered per export. It sees our address
+/// as the caller's, from a reserved port when we may bind one.
+#[derive(Clone, Debug)]
+pub struct Upstream {
+    nfs: SocketAddr,
+    /// its mountd; unset, rpcbind on its host is asked
+    mountd: Option<SocketAddr>,
+    handles: Arc<Mutex<Handles>>,
+}
+
+impl Upstream {
+    pub fn new(nfs: SocketAddr) -> Self {
+        Self {
+            nfs,
+            mountd: None,
+            handles: Arc::default(),
+        }
+    }
+
+    /// Send MNT here instead of where the upstream's rpcbind says.
+    pub fn mountd(mut self, addr: Option<SocketAddr>) -> Self {
+        self.mountd = addr;
+        self
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.nfs
+    }
+
+    /// The upstream's handle for `path`, a file of `export`, looked up
+    /// as `cred`: MNT of the name clients mount the export by, then
+    /// LOOKUP of each component below the deepest path already known.
+    pub async fn handle(
+        &self,
+        env: &Env,
+        export: &Export,
+        path: &Path,
+        cred: &RpcAuthUnix,
+    ) -> Result<Vec<u8>, HandleError> {
+        let rel = path
+            .strip_prefix(&export.path)
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let mut paths = vec![export.path.clone()];
+        for name in rel {
+            paths.push(paths[paths.len() - 1].join(name));
+        }
+
+        let known = {
+            let handles = self.handles.lock().unwrap();
+            paths
+                .iter()
+                .enumerate()
+                .rev()
+                .find_map(|(i, p)| Some((i, handles.get(&(export.id(), p.clone()))?.clone())))
+        };
+        let (mut i, mut fh) = match known {
+            Some(v) => v,
+            None => {
+                let fh = self.mnt(env, &export.name(), cred).await?;
+                self.learn(export, &export.path, &fh);
+                (0, fh)
+            }
+        };
+        while i + 1 < paths.len() {
+            i += 1;
+            let name = paths[i].file_name().unwrap_or_default().to_string_lossy();
+            fh = self.lookup(env, &fh, &name, cred).await?;
+            self.learn(export, &paths[i], &fh);
+        }
+        Ok(fh)
+    }
+
+    /// Remember the upstream's handle for `path`, e.g. from a CREATE.
+    pub fn learn(&self, export: &Export, path: &Path, fh: &[u8]) {
+        let mut handles = self.handles.lock().unwrap();
+        if handles.len() >= MAX_HANDLES {
+            handles.clear();
+        }
+        handles.insert((export.id(), path.to_path_buf()), fh.to_vec());
+    }
+
+    /// Drop what we know of `path` and everything below it, after it
+    /// was removed or renamed.
+    pub fn forget(&self, export: &Export, path: &Path) {
+        let id = export.id();
+        self.handles
+            .lock()
+            .unwrap()
+            .retain(|(e, p), _| *e != id || !p.starts_with(path));
+    }
+
+    /// Drop every handle of `export`, after the upstream called one stale.
+    pub fn forget_export(&self, export: &Export) {
+        let id = export.id();
+        self.handles.lock().unwrap().retain(|(e, _), _| *e != id);
+    }
+
+    async fn mnt(&self, env: &Env, name: &str, cred: &RpcAuthUnix) -> Result<Vec<u8>, HandleError> {
+        let mountd = match self.mountd {
+            Some(addr) => addr,
+            None => {
+                let rpcbind = SocketAddr::new(self.nfs.ip(), RPCBIND_ADDR.port());
+                let port = rpcbind_getport(env, rpcbind, MOUNT_PROG, MOUNT_VERS_MIN, IPPROTO_UDP)
+                    .await
+                    .map_err(io::Error::other)?
+                    .ok_or_else(|| io::Error::other("upstream has no mountd registered"))?;
+                SocketAddr::new(self.nfs.ip(), port)
+            }
+        };
+        let mut args = XdrW::new();
+        args.put_string(name);
+        let res = call(
+            env,
+            mountd,
+            MOUNT_PROG,
+            MOUNT_VERS_MIN,
+            MOUNTPROC_MNT,
+            cred,
+            &args.buf,
+        )
+        .await?;
+        handle_result(&res)
+    }
+
+    async fn lookup(
+        &self,
+        env: &Env,
+        dir: &[u8],
+        name: &str,
+        cred: &RpcAuthUnix,
+    ) -> Result<Vec<u8>, HandleError> {
+        let mut args = XdrW::new();
+        args.put_fixed(dir);
+        args.put_string(name);
+        let res = call(
+            env,
+            self.nfs,
+            NFS_PROG,
+            NFS_VERS,
+            NFSPROC_LOOKUP,
+            cred,
+            &args.buf,
+        )
+        .await?;
+        // diropres: the attributes after the handle are not needed
+        handle_result(&res)
+    }
+
+    /// Relay a complete RPC call and wait for the matching reply, which
+    /// carries the client's xid unchanged.
+    pub async fn forward(&self, mut call: Vec<u8>) -> io::Result<Vec<u8>> {
+        if call.len() < 16 {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        // The upstream knows NFS by its standard number, whatever
+        // program this instance was registered as.
+        call[12..16].copy_from_slice(&NFS_PROG.to_be_bytes());
+        exchange(self.nfs, &call).await
+    }
+}
+
+/// The results of an accepted, successful RPC reply.
+pub fn results(reply: &[u8]) -> Option<&[u8]> {
+    let mut r = XdrR::new(reply);
+    r.get_u32().ok()?; // xid
+    if r.get_u32().ok()? != MsgType::Reply as u32 || r.get_u32().ok()? != 0 {
+        return None;
+    }
+    r.get_u32().ok()?; // verifier flavor
+    r.get_opaque().ok()?;
+    (r.get_u32().ok()? == SUCCESS).then(|| &reply[r.pos..])
+}
+
+/// Status, then a handle when it is 0: fhstatus (MNT v1) and the start
+/// of diropres (LOOKUP) alike.
+fn handle_result(res: &[u8]) -> Result<Vec<u8>, HandleError> {
+    let malformed = |_| io::Error::from(io::ErrorKind::InvalidData);
+    let mut r = XdrR::new(res);
+    match r.get_u32().map_err(malformed)? {
+        0 => Ok(r.get_fixed(FH_SIZE).map_err(malformed)?),
+        status => Err(HandleError::Status(status)),
+    }
+}
+
+/// Make a call of our own as `cred` and return its results.
+async fn call(
+    env: &Env,
+    to: SocketAddr,
+    prog: u32,
+    vers: u32,
+    procid: u32,
+    cred: &RpcAuthUnix,
+    args: &[u8],
+) -> io::Result<Vec<u8>> {
+    let mut w = XdrW::new();
+    w.put_u32(env.xid());
+    w.put_u32(MsgType::Call as u32);
+    w.put_u32(RPC_VERSION);
+    w.put_u32(prog);
+    w.put_u32(vers);
+    w.put_u32(procid);
+    w.put_u32(AUTH_UNIX);
+    w.put_opaque(&encode_auth_unix(cred));
+    // verifier: AUTH_NULL
+    w.put_u32(0);
+    w.put_u32(0);
+    w.buf.extend_from_slice(args);
+
+    let reply = exchange(to, &w.buf).await?;
+    match results(&reply) {
+        Some(res) => Ok(res.to_vec()),
+        None => Err(io::Error::other(format!(
+            "upstream did not accept call {prog}/{procid}"
+        ))),
+    }
+}
+
+/// Send one call and wait for the reply with its xid.
+async fn exchange(to: SocketAddr, call: &[u8]) -> io::Result<Vec<u8>> {
+    let sock = bind_reserved(to).await?;
+    sock.connect(to).await?;
+    sock.send(call).await?;
+
+    let mut buf = vec![0u8; MAX_REPLY];
+    let reply = async {
+        loop {
+            let n = sock.recv(&mut buf).await?;
+            // anything else is a late reply to someone's earlier call
+            if n >= 4 && buf[..4] == call[..4] {
+                return Ok::<_, io::Error>(n);
+            }
+        }
+    };
+    let n = tokio::time::timeout(REPLY_TIMEOUT, reply)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    buf.truncate(n);
+    Ok(buf)
+}
+
+/// A socket on a reserved port, which servers exporting `secure` (the
+/// knfsd default) insist on. Without the privilege for one any port
+/// does, and the upstream must export `insecure`.
+async fn bind_reserved(to: SocketAddr) -> io::Result<UdpSocket> {
+    let any = match to {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    for port in RESERVED_PORTS.rev() {
+        match UdpSocket::bind((any, port)).await {
+            Ok(sock) => return Ok(sock),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => break,
+            Err(e) => return Err(e),
+        }
+    }
+    UdpSocket::bind((any, 0)).await
+}
@@ -22,6 +22,12 @@ impl XdrW {
         }
     }
 
+    pub fn with_capacity(n: usize) -> Self {
+        Self {
+            buf: BytesMut::with_capacity(n),
+        }
+    }
+
     pub fn put_u32(&mut self, v: u32) {
         self.buf.put_u32(v);
     }
@@ -40,6 +46,18 @@ impl XdrW {
     pub fn put_string(&mut self, s: &str) {
         self.put_opaque(s.as_bytes());
     }
+    /// The encoded bytes, without copying them.
+    pub fn into_vec(self) -> Vec<u8> {
+        self.buf.into()
+    }
+    /// Fixed-length opaque (`opaque x[n]`): no length word.
+    pub fn put_fixed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+        let pad = (4 - (data.len() % 4)) % 4;
+        if pad > 0 {
+            self.buf.extend_from_slice(&[0; 3][..pad]);
+        }
+    }
 }
 
 pub struct XdrR<'a> {
@@ -53,10 +71,12 @@ impl<'a> XdrR<'a> {
 }
 
 impl<'a> XdrR<'a> {
-    pub fn skip_bytes(&mut self, len: usize) -> Result<(), XdrError> {
+    /// Skip `len` bytes and their pad. A missing pad is accepted at the
+    /// very end of the buffer.
+    pub fn skip_bytes_lenient(&mut self, len: usize) -> Result<(), XdrError> {
         let pad = (4 - (len % 4)) % 4;
-        self.need(len + pad)?;
-        self.pos += len + pad;
+        self.need(len)?;
+        self.pos += (len + pad).min(self.buf.len() - self.pos);
         Ok(())
     }
 }
@@ -110,7 +130,23 @@ impl<'a> XdrR<'a> {
         Ok(self.get_u32()? as i32)
     }
     pub fn get_opaque(&mut self) -> Result<Vec<u8>, XdrError> {
+        self.get_opaque_max(usize::MAX)
+    }
+    pub fn get_string(&mut self) -> Result<String, XdrError> {
+        let v = self.get_opaque()?;
+        Ok(String::from_utf8_lossy(&v).into())
+    }
+
+    /// Read an opaque whose declared length must not exceed `max`.
+    /// The length is checked before anything is allocated for the payload:
+    /// one claiming more than the buffer holds is an underrun, one that is
+    /// there but over `max` is too long.
+    pub fn get_opaque_max(&mut self, max: usize) -> Result<Vec<u8>, XdrError> {
         let len = self.get_u32()? as usize;
+        self.need(len)?;
+        if len > max {
+            return Err(XdrError::StrTooLong);
+        }
         let pad = (4 - (len % 4)) % 4;
         self.need(len + pad)?;
 
@@ -119,8 +155,19 @@ impl<'a> XdrR<'a> {
 
         Ok(data)
     }
-    pub fn get_string(&mut self) -> Result<String, XdrError> {
-        let v = self.get_opaque()?;
+    pub fn get_string_max(&mut self, max: usize) -> Result<String, XdrError> {
+        let v = self.get_opaque_max(max)?;
         Ok(String::from_utf8_lossy(&v).into())
     }
+
+    /// Fixed-length opaque (`opaque x[n]`): exactly `n` bytes, no length word.
+    pub fn get_fixed(&mut self, n: usize) -> Result<Vec<u8>, XdrError> {
+        let pad = (4 - (n % 4)) % 4;
+        self.need(n + pad)?;
+
+        let data = self.buf[self.pos..self.pos + n].to_vec();
+        self.pos += n + pad;
+
+        Ok(data)
+    }
 }